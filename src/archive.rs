@@ -0,0 +1,181 @@
+//! Unpacking archives found in the tree for --extract, and writing the flat archive
+//! for --dest-archive. The system's `tar`, `zip` and `unzip` do the work, so no
+//! archive formats are compiled in.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Archive formats --extract can unpack and --dest-archive can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    /// `.zip`
+    Zip,
+    /// `.tar`
+    Tar,
+    /// `.tar.gz` or `.tgz`
+    #[value(name = "tar.gz", alias = "tgz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 3] =
+        [ArchiveFormat::Zip, ArchiveFormat::Tar, ArchiveFormat::TarGz];
+
+    /// The format of `path` by its extension, if it is one of `formats`
+    pub fn detect(path: &Path, formats: &[ArchiveFormat]) -> Option<ArchiveFormat> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        let format = if name.ends_with(".zip") {
+            ArchiveFormat::Zip
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            ArchiveFormat::TarGz
+        } else if name.ends_with(".tar") {
+            ArchiveFormat::Tar
+        } else {
+            return None;
+        };
+        formats.contains(&format).then_some(format)
+    }
+}
+
+/// Unpack `archive` into the existing directory `dest`
+pub fn extract(archive: &Path, format: ArchiveFormat, dest: &Path) -> io::Result<()> {
+    let command = match format {
+        // Windows' bundled tar (bsdtar) reads zip files; elsewhere unzip is the norm
+        ArchiveFormat::Zip if !cfg!(windows) => {
+            let mut command = Command::new("unzip");
+            command.arg("-q").arg(archive).arg("-d").arg(dest);
+            command
+        }
+        _ => {
+            let mut command = Command::new("tar");
+            command.arg("-xf").arg(archive).arg("-C").arg(dest);
+            command
+        }
+    };
+
+    run(command, None)
+}
+
+/// Write the files `names` in `dir` into a new archive at `archive`, which must be
+/// an absolute path
+pub fn create(
+    archive: &Path,
+    format: ArchiveFormat,
+    dir: &Path,
+    names: &[OsString],
+) -> io::Result<()> {
+    // Names go in on stdin, so there's no limit on how many there are
+    let (mut command, separator) = match format {
+        ArchiveFormat::Zip => {
+            let mut command = Command::new("zip");
+            command.arg("-q").arg(archive).arg("-@");
+            (command, b'\n')
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let mut command = Command::new("tar");
+            let flags = if format == ArchiveFormat::TarGz {
+                "-czf"
+            } else {
+                "-cf"
+            };
+            command
+                .arg("--null")
+                .arg(flags)
+                .arg(archive)
+                .arg("-T")
+                .arg("-");
+            (command, b'\0')
+        }
+    };
+    command.current_dir(dir);
+
+    let mut list = Vec::new();
+    for name in names {
+        list.extend_from_slice(name.as_encoded_bytes());
+        list.push(separator);
+    }
+    run(command, Some(&list))
+}
+
+/// Run an archiver, passing it `input` on stdin. A non-zero exit status is an error
+/// carrying what it printed to stderr.
+fn run(mut command: Command, input: Option<&[u8]>) -> io::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let spawn_error =
+        |e: io::Error| io::Error::new(e.kind(), format!("could not run {}: {}", program, e));
+    command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let mut child = command.spawn().map_err(spawn_error)?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            program,
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Every regular file below `dir`, sorted. Symbolic links from the archive are left out.
+pub fn files_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let all = ArchiveFormat::ALL;
+        assert_eq!(
+            ArchiveFormat::detect(Path::new("a/photos.ZIP"), &all),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(Path::new("backup.tar.gz"), &all),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(Path::new("backup.tgz"), &all),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(Path::new("backup.tar"), &all),
+            Some(ArchiveFormat::Tar)
+        );
+        assert_eq!(ArchiveFormat::detect(Path::new("notes.txt"), &all), None);
+        assert_eq!(
+            ArchiveFormat::detect(Path::new("photos.zip"), &[ArchiveFormat::TarGz]),
+            None
+        );
+    }
+}
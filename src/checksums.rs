@@ -0,0 +1,123 @@
+//! SHA-256 hashing and `sha256sum`-compatible checksum files
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub type Hash = [u8; 32];
+
+/// SHA-256 of a file's contents
+pub fn sha256_file(path: &Path) -> io::Result<Hash> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Checksums of the files placed in the root, keyed by path relative to the root.
+/// A file that is later replaced under the same name keeps only its latest hash.
+#[derive(Default)]
+pub struct Checksums {
+    entries: BTreeMap<PathBuf, Hash>,
+}
+
+impl Checksums {
+    pub fn insert(&mut self, relative_path: PathBuf, hash: Hash) {
+        self.entries.insert(relative_path, hash);
+    }
+
+    /// Write the checksums in the format `sha256sum -c` reads, sorted by path
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        for (relative_path, hash) in &self.entries {
+            out.write_all(&checksum_line(hash, relative_path))?;
+        }
+        out.flush()
+    }
+}
+
+/// One `sha256sum` line. Names containing a backslash or newline are escaped and the
+/// line is prefixed with a backslash, as GNU coreutils does.
+fn checksum_line(hash: &Hash, path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    let name = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let name = path.to_string_lossy().replace('\\', "/").into_bytes();
+
+    let escape = name.iter().any(|&b| b == b'\\' || b == b'\n');
+    let mut line = Vec::with_capacity(name.len() + 68);
+    if escape {
+        line.push(b'\\');
+    }
+    for byte in hash {
+        line.extend_from_slice(format!("{:02x}", byte).as_bytes());
+    }
+    line.extend_from_slice(b"  ");
+    for &b in &name {
+        match b {
+            b'\\' if escape => line.extend_from_slice(b"\\\\"),
+            b'\n' => line.extend_from_slice(b"\\n"),
+            _ => line.push(b),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // sha256("abc")
+    const ABC_HEX: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_sha256_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("abc.txt");
+        fs::write(&path, "abc").unwrap();
+
+        let hex: String = sha256_file(&path)
+            .unwrap()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, ABC_HEX);
+    }
+
+    #[test]
+    fn test_write_checksums() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("abc.txt");
+        fs::write(&file, "abc").unwrap();
+        let hash = sha256_file(&file).unwrap();
+
+        let mut checksums = Checksums::default();
+        checksums.insert(PathBuf::from("b.txt"), [0; 32]);
+        checksums.insert(PathBuf::from("a.txt"), hash);
+        // Replaced files keep only their latest hash
+        checksums.insert(PathBuf::from("b.txt"), hash);
+
+        let out = temp_dir.path().join("sha256sums.txt");
+        checksums.write(&out).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            format!("{ABC_HEX}  a.txt\n{ABC_HEX}  b.txt\n")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_checksum_line_escapes_names() {
+        let line = checksum_line(&[0; 32], Path::new("new\nline"));
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.starts_with("\\0000"));
+        assert!(line.ends_with("  new\\nline\n"));
+    }
+}
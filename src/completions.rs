@@ -0,0 +1,192 @@
+//! Shell completion scripts for `rflatten completions`. Besides option names, they
+//! complete --include and --exclude values from the top-level directories of the
+//! directory given on the command line.
+
+use clap::CommandFactory;
+use std::collections::HashSet;
+
+/// Shell to write a completion script for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// An option as completions offer it
+struct Flag {
+    short: Option<char>,
+    long: Option<String>,
+    help: String,
+}
+
+/// Subcommand names and the options of the command and its subcommands
+fn flags() -> (Vec<String>, Vec<Flag>) {
+    let command = crate::Cli::command();
+    let subcommands = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    let mut flags = Vec::new();
+    let mut seen = HashSet::new();
+    let args = std::iter::once(&command)
+        .chain(command.get_subcommands())
+        .flat_map(|command| command.get_arguments());
+    for arg in args {
+        if arg.is_hide_set() || arg.is_positional() {
+            continue;
+        }
+        // Subcommands share options like --yes and the filters
+        let long = arg.get_long().map(str::to_string);
+        if !seen.insert((arg.get_short(), long.clone())) {
+            continue;
+        }
+        let help = arg
+            .get_help()
+            .map(|help| help.to_string())
+            .unwrap_or_default();
+        flags.push(Flag {
+            short: arg.get_short(),
+            long,
+            // Only the first line fits next to the option
+            help: help.lines().next().unwrap_or_default().to_string(),
+        });
+    }
+    (subcommands, flags)
+}
+
+pub fn script(shell: Shell) -> String {
+    let (subcommands, flags) = flags();
+    match shell {
+        Shell::Bash => bash(&subcommands, &flags),
+        // zsh runs the bash script through its bash completion layer
+        Shell::Zsh => format!(
+            "autoload -U +X bashcompinit && bashcompinit\n{}",
+            bash(&subcommands, &flags)
+        ),
+        Shell::Fish => fish(&subcommands, &flags),
+    }
+}
+
+fn bash(subcommands: &[String], flags: &[Flag]) -> String {
+    let mut words = subcommands.to_vec();
+    for flag in flags {
+        words.extend(flag.short.map(|short| format!("-{}", short)));
+        words.extend(flag.long.as_ref().map(|long| format!("--{}", long)));
+    }
+    BASH_TEMPLATE.replace("@WORDS@", &words.join(" "))
+}
+
+const BASH_TEMPLATE: &str = r#"# rflatten completions for bash
+_rflatten_top_dirs() {
+    # The directory being flattened is whichever word on the line names one
+    local dir=. word i
+    for ((i = 1; i < ${#COMP_WORDS[@]}; i++)); do
+        word=${COMP_WORDS[i]}
+        word=${word/#\~/$HOME}
+        if ((i != COMP_CWORD)) && [[ $word != -* && -d $word ]]; then
+            dir=$word
+        fi
+    done
+
+    # Values are comma-separated; complete the one after the last comma
+    local cur=$1 prefix=""
+    if [[ $cur == *,* ]]; then
+        prefix=${cur%,*},
+    fi
+    local IFS=$'\n' d names=()
+    for d in "$dir"/*/; do
+        [[ -d $d ]] || continue
+        d=${d%/}
+        names+=("${d##*/}")
+    done
+    COMPREPLY=($(compgen -P "$prefix" -W "${names[*]}" -- "${cur##*,}"))
+}
+
+_rflatten() {
+    local cur=${COMP_WORDS[COMP_CWORD]} prev=${COMP_WORDS[COMP_CWORD - 1]}
+    # --include=VALUE is split into three words at the =
+    if [[ $prev == = ]] && ((COMP_CWORD > 1)); then
+        prev=${COMP_WORDS[COMP_CWORD - 2]}
+    elif [[ $cur == = ]]; then
+        cur=""
+    fi
+    case $prev in
+        -i | --include | -e | --exclude)
+            _rflatten_top_dirs "$cur"
+            return
+            ;;
+    esac
+
+    if [[ $cur == -* ]]; then
+        COMPREPLY=($(compgen -W "@WORDS@" -- "$cur"))
+    else
+        COMPREPLY=($(compgen -d -- "$cur"))
+    fi
+}
+
+complete -o filenames -F _rflatten rflatten
+"#;
+
+fn fish(subcommands: &[String], flags: &[Flag]) -> String {
+    let mut script = String::from(FISH_FUNCTIONS);
+    for subcommand in subcommands {
+        script.push_str(&format!(
+            "complete -c rflatten -n __fish_use_subcommand -f -a {}\n",
+            subcommand
+        ));
+    }
+    for flag in flags {
+        let mut line = String::from("complete -c rflatten");
+        if let Some(short) = flag.short {
+            line.push_str(&format!(" -s {}", short));
+        }
+        if let Some(long) = &flag.long {
+            line.push_str(&format!(" -l {}", long));
+        }
+        if matches!(flag.long.as_deref(), Some("include" | "exclude")) {
+            line.push_str(" -x -a '(__rflatten_top_dirs)'");
+        }
+        if !flag.help.is_empty() {
+            line.push_str(&format!(" -d '{}'", flag.help.replace('\'', "\\'")));
+        }
+        script.push_str(&line);
+        script.push('\n');
+    }
+    script
+}
+
+const FISH_FUNCTIONS: &str = r#"# rflatten completions for fish
+function __rflatten_top_dirs
+    # The directory being flattened is whichever word on the line names one
+    set -l dir .
+    for word in (commandline -o)[2..-1]
+        if not string match -q -- '-*' $word; and test -d $word
+            set dir $word
+        end
+    end
+    # Values are comma-separated; complete the one after the last comma
+    set -l prefix (string match -r '.*,' -- (commandline -ct))
+    for d in $dir/*/
+        echo $prefix(basename $d)
+    end
+end
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripts_list_options() {
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("--one-file-system"));
+        assert!(bash.contains(" merge "));
+        assert!(!bash.contains("@WORDS@"));
+
+        let fish = script(Shell::Fish);
+        assert!(
+            fish.contains("complete -c rflatten -s i -l include -x -a '(__rflatten_top_dirs)'")
+        );
+    }
+}
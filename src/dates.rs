@@ -0,0 +1,257 @@
+//! Date and duration parsing for time-based filters, and timestamp formatting.
+//! Dates are interpreted as UTC.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+/// (Howard Hinnant's `days_from_civil` algorithm)
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date for a number of days since 1970-01-01
+/// (Howard Hinnant's `civil_from_days` algorithm)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Year, month and day of a time in UTC. Times before 1970 are clamped to the epoch.
+pub fn utc_date(time: SystemTime) -> (i64, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    civil_from_days((secs / SECONDS_PER_DAY) as i64)
+}
+
+/// Format a time as `YYYYMMDD-HHMMSS` in UTC, for use in file names.
+/// Times before 1970 are clamped to the epoch.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (year, month, day) = utc_date(time);
+    let secs_of_day = secs % SECONDS_PER_DAY;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Format a time as `YYYY-MM-DD HH:MM:SS` in UTC, for people to read
+pub fn format_datetime(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (year, month, day) = utc_date(time);
+    let secs_of_day = secs % SECONDS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str, what: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid {} '{}'", what, s))
+}
+
+/// Parse `YYYY-MM-DD`, optionally followed by `THH:MM[:SS]` or ` HH:MM[:SS]`
+pub fn parse_date(s: &str) -> Result<SystemTime, String> {
+    let (date, time) = match s.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let parts: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(format!("invalid date '{}', expected YYYY-MM-DD", s));
+    };
+    let year: i64 = parse_number(year, "year")?;
+    let month: u32 = parse_number(month, "month")?;
+    let day: u32 = parse_number(day, "day")?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(format!("invalid date '{}'", s));
+    }
+
+    let mut seconds = days_from_civil(year, month, day) * SECONDS_PER_DAY as i64;
+    if let Some(time) = time {
+        let parts: Vec<&str> = time.split(':').collect();
+        let (hour, minute, second) = match parts[..] {
+            [h, m] => (h, m, "0"),
+            [h, m, s] => (h, m, s),
+            _ => return Err(format!("invalid time '{}', expected HH:MM[:SS]", time)),
+        };
+        let hour: i64 = parse_number(hour, "hour")?;
+        let minute: i64 = parse_number(minute, "minute")?;
+        let second: i64 = parse_number(second, "second")?;
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(format!("invalid time '{}'", time));
+        }
+        seconds += hour * 3600 + minute * 60 + second;
+    }
+
+    if seconds >= 0 {
+        Ok(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+    } else {
+        Ok(UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()))
+    }
+}
+
+/// Parse a duration such as `90s`, `15m`, `12h`, `30d` or `2w`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in duration '{}' (use s, m, h, d or w)", s))?;
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = parse_number(amount, "duration")?;
+
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => SECONDS_PER_DAY,
+        "w" => 7 * SECONDS_PER_DAY,
+        _ => {
+            return Err(format!(
+                "invalid unit '{}' in duration '{}' (use s, m, h, d or w)",
+                unit, s
+            ));
+        }
+    };
+
+    Ok(Duration::from_secs(amount * unit_seconds))
+}
+
+/// Parse a duration, treating a bare number as seconds
+pub fn parse_seconds(s: &str) -> Result<Duration, String> {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(Duration::from_secs(parse_number(s, "number of seconds")?));
+    }
+    parse_duration(s)
+}
+
+/// Parse either an absolute date or a duration counted back from now
+/// (`7d` means "7 days ago")
+pub fn parse_time_spec(s: &str) -> Result<SystemTime, String> {
+    if s.contains('-') {
+        return parse_date(s);
+    }
+
+    let duration = parse_duration(s)?;
+    SystemTime::now()
+        .checked_sub(duration)
+        .ok_or_else(|| format!("duration '{}' is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in [-1000, 0, 59, 10_000, 19_782] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let time = parse_date("2024-03-05T07:08:09").unwrap();
+        assert_eq!(format_timestamp(time), "20240305-070809");
+        assert_eq!(format_timestamp(UNIX_EPOCH), "19700101-000000");
+        assert_eq!(format_datetime(time), "2024-03-05 07:08:09");
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("1970-01-02").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(86_400)
+        );
+        assert_eq!(
+            parse_date("1970-01-01T01:02:03").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(3723)
+        );
+        assert_eq!(
+            parse_date("1970-01-01 00:10").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(600)
+        );
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-01").is_err());
+        assert!(parse_date("2024-01-01T25:00").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(
+            parse_duration("30d").unwrap(),
+            Duration::from_secs(2_592_000)
+        );
+        assert_eq!(parse_duration("1w").unwrap(), Duration::from_secs(604_800));
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("10y").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_seconds("5m").unwrap(), Duration::from_secs(300));
+        assert!(parse_seconds("").is_err());
+        assert!(parse_seconds("-5").is_err());
+    }
+
+    #[test]
+    fn test_parse_time_spec() {
+        let week_ago = parse_time_spec("7d").unwrap();
+        let elapsed = SystemTime::now().duration_since(week_ago).unwrap();
+        assert!(elapsed >= Duration::from_secs(7 * 86_400));
+        assert!(elapsed < Duration::from_secs(7 * 86_400 + 60));
+
+        assert_eq!(parse_time_spec("1970-01-01").unwrap(), UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,140 @@
+//! Content-based duplicate detection for --dedupe
+
+use crate::checksums::{Hash, sha256_file};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+struct Entry {
+    path: PathBuf,
+    /// Only computed once another file of the same size shows up
+    hash: Option<Hash>,
+}
+
+/// Index of file contents seen so far. Files are grouped by size and only hashed
+/// when they share a size with another file.
+#[derive(Default)]
+pub struct ContentIndex {
+    by_size: HashMap<u64, Vec<Entry>>,
+}
+
+impl ContentIndex {
+    /// Index every file directly in `dir`
+    pub fn with_files_in(dir: &Path) -> io::Result<Self> {
+        let mut index = ContentIndex::default();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                index.add(entry.path(), metadata.len(), None);
+            }
+        }
+        Ok(index)
+    }
+
+    fn add(&mut self, path: PathBuf, size: u64, hash: Option<Hash>) {
+        self.by_size
+            .entry(size)
+            .or_default()
+            .push(Entry { path, hash });
+    }
+
+    /// Find an indexed file with the same contents as `path`. If there is none,
+    /// `path` is added to the index.
+    pub fn find_or_add(&mut self, path: &Path, size: u64) -> io::Result<Option<PathBuf>> {
+        let Some(entries) = self.by_size.get_mut(&size).filter(|e| !e.is_empty()) else {
+            self.add(path.to_path_buf(), size, None);
+            return Ok(None);
+        };
+
+        let hash = sha256_file(path)?;
+        for entry in entries.iter_mut() {
+            if entry.hash.is_none() {
+                // A file that vanished or can't be read can't be a match
+                entry.hash = sha256_file(&entry.path).ok();
+            }
+            if entry.hash == Some(hash) {
+                return Ok(Some(entry.path.clone()));
+            }
+        }
+
+        entries.push(Entry {
+            path: path.to_path_buf(),
+            hash: Some(hash),
+        });
+        Ok(None)
+    }
+
+    /// Follow an indexed file that was moved
+    pub fn relocate(&mut self, size: u64, from: &Path, to: &Path) {
+        if let Some(entry) = self
+            .by_size
+            .get_mut(&size)
+            .and_then(|entries| entries.iter_mut().find(|e| e.path == from))
+        {
+            entry.path = to.to_path_buf();
+        }
+    }
+}
+
+/// Whether two files have the same contents
+pub fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(sha256_file(a)? == sha256_file(b)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_or_add() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        for (name, contents) in [("a", "same"), ("b", "same"), ("c", "diff"), ("d", "long")] {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let mut index = ContentIndex::default();
+        assert_eq!(index.find_or_add(&dir.join("a"), 4).unwrap(), None);
+        assert_eq!(
+            index.find_or_add(&dir.join("b"), 4).unwrap(),
+            Some(dir.join("a"))
+        );
+        assert_eq!(index.find_or_add(&dir.join("c"), 4).unwrap(), None);
+        assert_eq!(index.find_or_add(&dir.join("d"), 4).unwrap(), None);
+    }
+
+    #[test]
+    fn test_with_files_in_and_relocate() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        fs::write(dir.join("root.txt"), "contents").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/copy.txt"), "contents").unwrap();
+        fs::write(dir.join("sub/other.txt"), "other").unwrap();
+
+        let mut index = ContentIndex::with_files_in(dir).unwrap();
+        assert_eq!(
+            index.find_or_add(&dir.join("sub/copy.txt"), 8).unwrap(),
+            Some(dir.join("root.txt"))
+        );
+
+        assert_eq!(
+            index.find_or_add(&dir.join("sub/other.txt"), 5).unwrap(),
+            None
+        );
+        fs::rename(dir.join("sub/other.txt"), dir.join("other.txt")).unwrap();
+        index.relocate(5, &dir.join("sub/other.txt"), &dir.join("other.txt"));
+
+        fs::write(dir.join("sub/other2.txt"), "other").unwrap();
+        assert_eq!(
+            index.find_or_add(&dir.join("sub/other2.txt"), 5).unwrap(),
+            Some(dir.join("other.txt"))
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! The root's listing before and after a run, diff-style, for --diff
+
+use std::collections::BTreeSet;
+
+/// Unchanged entries kept around each change, like `diff -u`
+const CONTEXT: usize = 3;
+
+/// Lines for the entries in `before` and `after`, merged in name order: `-` for
+/// entries that go away, `+` for those the run adds, and a space for those it
+/// leaves. Long runs of unchanged entries are shortened to a count.
+pub fn render(label: &str, before: &BTreeSet<String>, after: &BTreeSet<String>) -> String {
+    let lines: Vec<(char, &String)> = before
+        .union(after)
+        .map(|name| match (before.contains(name), after.contains(name)) {
+            (true, false) => ('-', name),
+            (false, true) => ('+', name),
+            _ => (' ', name),
+        })
+        .collect();
+    let changed: Vec<usize> = (0..lines.len()).filter(|&i| lines[i].0 != ' ').collect();
+    let near_change = |i: usize| changed.iter().any(|&c| c.abs_diff(i) <= CONTEXT);
+
+    let mut out = format!("--- {} (before)\n+++ {} (after)\n", label, label);
+    let mut hidden = Vec::new();
+    for (i, (mark, name)) in lines.iter().enumerate() {
+        if *mark == ' ' && !near_change(i) {
+            hidden.push(*name);
+            continue;
+        }
+        flush_hidden(&mut out, &mut hidden);
+        out.push_str(&format!("{} {}\n", mark, name));
+    }
+    flush_hidden(&mut out, &mut hidden);
+    out
+}
+
+/// Write a run of unchanged entries as a count, unless it's just the one
+fn flush_hidden(out: &mut String, hidden: &mut Vec<&String>) {
+    match hidden.as_slice() {
+        [] => {}
+        [name] => out.push_str(&format!("  {}\n", name)),
+        names => out.push_str(&format!("  ... {} unchanged\n", names.len())),
+    }
+    hidden.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let before = set(&["a.txt", "b.txt", "c.txt", "d.txt", "e.txt", "photos/"]);
+        let after = set(&["a.txt", "b.txt", "c.txt", "d.txt", "e.txt", "img_1.jpg"]);
+        assert_eq!(
+            render("root", &before, &after),
+            "--- root (before)\n+++ root (after)\n  \
+             ... 2 unchanged\n  \
+             c.txt\n  \
+             d.txt\n  \
+             e.txt\n\
+             + img_1.jpg\n\
+             - photos/\n"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! `rflatten doctor`: problems a flatten of a tree would run into, found before
+//! anything is moved, with the options that deal with them
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::naming::{
+    MAX_NAME_LEN, UnicodeForm, name_len, normalize_name, sanitize_chars, sanitize_reserved_name,
+};
+use crate::{FlattenOptions, SkipReason, WalkEvent, Walker, device_id, display_path};
+
+/// Room a conflict suffix like `_12` needs; names longer than the limit minus this
+/// are shortened when they conflict
+const SUFFIX_ROOM: usize = 4;
+
+/// A kind of problem, in the order they're reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    LongName,
+    ReservedName,
+    InvalidChars,
+    NotUtf8,
+    CaseCollision,
+    SymlinkLoop,
+    OtherDevice,
+}
+
+impl Problem {
+    fn title(self) -> &'static str {
+        match self {
+            Problem::LongName => "Names at the length limit",
+            Problem::ReservedName => "Names Windows reserves",
+            Problem::InvalidChars => "Names with characters Windows filesystems don't allow",
+            Problem::NotUtf8 => "Paths that aren't valid UTF-8",
+            Problem::CaseCollision => "Names that only differ in case",
+            Problem::SymlinkLoop => "Symbolic links that loop",
+            Problem::OtherDevice => "Directories on another filesystem",
+        }
+    }
+
+    fn suggestion(self) -> &'static str {
+        match self {
+            Problem::LongName => {
+                "A conflict suffix shortens these, adding a hash of the full name; \
+                 --provenance keeps a record of the original"
+            }
+            Problem::ReservedName => {
+                "--windows-names adds a suffix to them (on Windows this is always done)"
+            }
+            Problem::InvalidChars | Problem::NotUtf8 => {
+                "--sanitize replaces the characters with '_' when moving to an NTFS, \
+                 exFAT or FAT drive"
+            }
+            Problem::CaseCollision => {
+                "--case-insensitive renames them apart even on a case-sensitive root, \
+                 so the result can be copied to Windows or macOS"
+            }
+            Problem::SymlinkLoop => {
+                "Links are skipped unless --follow-junctions is given, which walks each \
+                 directory once; --exclude-dir leaves them out entirely"
+            }
+            Problem::OtherDevice => {
+                "Files there are copied and then deleted, not renamed; \
+                 -x/--one-file-system leaves them out"
+            }
+        }
+    }
+}
+
+/// What `check` found
+#[derive(Default)]
+pub struct Report {
+    /// Files a flatten would move
+    pub files: usize,
+    /// Paths with each problem, with a note where there's more to say
+    pub problems: BTreeMap<Problem, Vec<(PathBuf, Option<String>)>>,
+}
+
+impl Report {
+    fn add(&mut self, problem: Problem, path: PathBuf, note: Option<String>) {
+        self.problems.entry(problem).or_default().push((path, note));
+    }
+}
+
+/// Walk `root` as a flatten with `options` would, and collect the problems found
+pub fn check(root: &Path, options: &FlattenOptions) -> io::Result<Report> {
+    let mut report = Report::default();
+    // Names by their case-folded form, seeded with what's already in the root
+    let mut folded: HashMap<String, BTreeMap<OsString, PathBuf>> = HashMap::new();
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        add_folded(&mut folded, &path);
+    }
+    let mut other_devices: Vec<PathBuf> = Vec::new();
+
+    let walker = Walker::new(root, options)?;
+    walker.walk(&mut |event| {
+        match event {
+            WalkEvent::File { entry, .. } => {
+                report.files += 1;
+                let path = entry.path();
+                let name = entry.file_name();
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+
+                if relative.to_str().is_none() {
+                    report.add(Problem::NotUtf8, path.clone(), None);
+                } else if sanitize_chars(&name).is_some() {
+                    report.add(Problem::InvalidChars, path.clone(), None);
+                }
+                if sanitize_reserved_name(&name, "_").is_some() {
+                    report.add(Problem::ReservedName, path.clone(), None);
+                }
+                if name_len(&name) + SUFFIX_ROOM > MAX_NAME_LEN {
+                    report.add(Problem::LongName, path.clone(), None);
+                }
+                add_folded(&mut folded, &path);
+
+                if !other_devices.iter().any(|dir| path.starts_with(dir))
+                    && device_id(&entry.metadata()?) != walker.root_device
+                    && let Some(dir) = device_boundary(root, &path, walker.root_device)
+                {
+                    other_devices.push(dir);
+                }
+            }
+            WalkEvent::Skipped { path, reason }
+                if matches!(reason, SkipReason::Symlink | SkipReason::AlreadyWalked)
+                    && is_loop(&path) =>
+            {
+                report.add(Problem::SymlinkLoop, path, None);
+            }
+            WalkEvent::Skipped { .. } | WalkEvent::Walked { .. } => {}
+        }
+        Ok(())
+    })?;
+
+    for names in folded.into_values().filter(|names| names.len() > 1) {
+        for (name, path) in &names {
+            let others = names
+                .keys()
+                .filter(|other| *other != name)
+                .map(|other| other.to_string_lossy())
+                .collect::<Vec<_>>();
+            let note = format!("same name as {} ignoring case", others.join(", "));
+            report.add(Problem::CaseCollision, path.clone(), Some(note));
+        }
+    }
+    for dir in other_devices {
+        report.add(Problem::OtherDevice, dir, None);
+    }
+    for paths in report.problems.values_mut() {
+        paths.sort();
+    }
+    Ok(report)
+}
+
+/// Record the name `path` would have in the root under its case-folded form. Only
+/// the first path with each exact name is kept; those are ordinary conflicts.
+fn add_folded(folded: &mut HashMap<String, BTreeMap<OsString, PathBuf>>, path: &Path) {
+    let Some(name) = path.file_name() else {
+        return;
+    };
+    let Some(key) = normalize_name(name, UnicodeForm::Nfc)
+        .to_str()
+        .map(str::to_lowercase)
+    else {
+        return;
+    };
+    folded
+        .entry(key)
+        .or_default()
+        .entry(name.to_os_string())
+        .or_insert_with(|| path.to_path_buf());
+}
+
+/// The highest directory above `file` (and below `root`) on another device than the
+/// root: where the other filesystem is mounted
+fn device_boundary(root: &Path, file: &Path, root_device: Option<u64>) -> Option<PathBuf> {
+    let mut boundary = None;
+    for dir in file.ancestors().skip(1) {
+        if dir == root {
+            break;
+        }
+        if device_id(&fs::metadata(dir).ok()?) != root_device {
+            boundary = Some(dir.to_path_buf());
+        }
+    }
+    boundary
+}
+
+/// Whether the link at `path` leads back to one of its own parents, or around a
+/// chain of links that never ends
+fn is_loop(path: &Path) -> bool {
+    match fs::canonicalize(path) {
+        Ok(target) => path
+            .parent()
+            .and_then(|parent| fs::canonicalize(parent).ok())
+            .is_some_and(|parent| parent.starts_with(&target)),
+        Err(e) => is_loop_error(&e),
+    }
+}
+
+#[cfg(unix)]
+fn is_loop_error(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ELOOP)
+}
+
+#[cfg(not(unix))]
+fn is_loop_error(_error: &io::Error) -> bool {
+    false
+}
+
+/// Print the problems in `report`, grouped by kind, each with its suggestion. Paths
+/// are shown relative to the root.
+pub fn print(root: &Path, report: &Report) {
+    println!(
+        "Checked {} file(s) in '{}'.",
+        report.files,
+        display_path(root)
+    );
+    if report.problems.is_empty() {
+        println!("No problems found.");
+        return;
+    }
+
+    for (problem, paths) in &report.problems {
+        println!("\n{} ({}):", problem.title(), paths.len());
+        for (path, note) in paths {
+            let path = path.strip_prefix(root).unwrap_or(path);
+            match note {
+                Some(note) => println!("  - {} ({})", display_path(path), note),
+                None => println!("  - {}", display_path(path)),
+            }
+        }
+        println!("  Suggestion: {}", problem.suggestion());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_check() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("README.txt"), "").unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/readme.txt"), "").unwrap();
+        fs::write(root.join("a/CON.txt"), "").unwrap();
+        fs::write(root.join("b/what?.txt"), "").unwrap();
+        fs::write(root.join("b").join("x".repeat(MAX_NAME_LEN - 2)), "").unwrap();
+        fs::write(root.join("b/fine.txt"), "").unwrap();
+
+        let report = check(root, &FlattenOptions::default()).unwrap();
+        assert_eq!(report.files, 5);
+        let paths = |problem| {
+            report.problems[&problem]
+                .iter()
+                .map(|(path, _)| path.strip_prefix(root).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(Problem::ReservedName), [PathBuf::from("a/CON.txt")]);
+        assert_eq!(paths(Problem::InvalidChars), [PathBuf::from("b/what?.txt")]);
+        assert_eq!(paths(Problem::LongName).len(), 1);
+        assert_eq!(
+            paths(Problem::CaseCollision),
+            [PathBuf::from("README.txt"), PathBuf::from("a/readme.txt")]
+        );
+        assert!(!report.problems.contains_key(&Problem::OtherDevice));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_finds_loops_and_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("a/b/up")).unwrap();
+        std::os::unix::fs::symlink("self", root.join("a/self")).unwrap();
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        // Some filesystems only take UTF-8 names
+        let non_utf8 = fs::write(root.join("a").join(name), "").is_ok();
+
+        let report = check(root, &FlattenOptions::default()).unwrap();
+        assert_eq!(
+            report.problems[&Problem::SymlinkLoop],
+            [(root.join("a/b/up"), None), (root.join("a/self"), None)]
+        );
+        if non_utf8 {
+            assert_eq!(
+                report.problems[&Problem::NotUtf8],
+                [(root.join("a").join(name), None)]
+            );
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Classifying files into broad content types by extension or magic bytes
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Broad class of file content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum FileClass {
+    Image,
+    Video,
+    Audio,
+    Document,
+    Archive,
+}
+
+impl FileClass {
+    /// Lowercase extensions (without the dot) that belong to this class
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            FileClass::Image => &[
+                "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "bmp", "tif", "tiff", "svg",
+                "ico", "avif", "raw", "cr2", "cr3", "nef", "arw", "dng", "orf", "rw2", "psd",
+            ],
+            FileClass::Video => &[
+                "mp4", "m4v", "mov", "mkv", "avi", "wmv", "webm", "flv", "mpg", "mpeg", "3gp",
+                "ts", "mts", "m2ts", "vob", "ogv",
+            ],
+            FileClass::Audio => &[
+                "mp3", "flac", "wav", "aac", "m4a", "ogg", "oga", "opus", "wma", "aiff", "aif",
+                "alac", "ape", "mid", "midi",
+            ],
+            FileClass::Document => &[
+                "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv",
+                "ppt", "pptx", "odp", "epub", "mobi", "pages", "numbers", "key", "tex",
+            ],
+            FileClass::Archive => &[
+                "zip", "rar", "7z", "tar", "gz", "tgz", "bz2", "tbz2", "xz", "txz", "zst", "lz",
+                "lzma", "cab", "iso", "dmg",
+            ],
+        }
+    }
+
+    /// Class of a file based on its extension alone
+    pub fn from_extension(path: &Path) -> Option<FileClass> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        [
+            FileClass::Image,
+            FileClass::Video,
+            FileClass::Audio,
+            FileClass::Document,
+            FileClass::Archive,
+        ]
+        .into_iter()
+        .find(|class| class.extensions().contains(&extension.as_str()))
+    }
+
+    /// Class of a file based on the signature at the start of its content
+    pub fn from_magic(header: &[u8]) -> Option<FileClass> {
+        let starts = |magic: &[u8]| header.starts_with(magic);
+        let at = |offset: usize, magic: &[u8]| {
+            header.get(offset..).is_some_and(|h| h.starts_with(magic))
+        };
+
+        if starts(b"\xFF\xD8\xFF")
+            || starts(b"\x89PNG\r\n\x1a\n")
+            || starts(b"GIF87a")
+            || starts(b"GIF89a")
+            || starts(b"BM")
+            || starts(b"II*\0")
+            || starts(b"MM\0*")
+            || (starts(b"RIFF") && at(8, b"WEBP"))
+            || (at(4, b"ftyp")
+                && (at(8, b"heic") || at(8, b"heix") || at(8, b"mif1") || at(8, b"avif")))
+        {
+            return Some(FileClass::Image);
+        }
+
+        if at(4, b"ftyp")
+            || starts(b"\x1A\x45\xDF\xA3")
+            || (starts(b"RIFF") && at(8, b"AVI "))
+            || starts(b"\x00\x00\x01\xBA")
+        {
+            return Some(FileClass::Video);
+        }
+
+        if starts(b"ID3")
+            || starts(b"\xFF\xFB")
+            || starts(b"\xFF\xF3")
+            || starts(b"fLaC")
+            || starts(b"OggS")
+            || (starts(b"RIFF") && at(8, b"WAVE"))
+            || starts(b"FORM")
+        {
+            return Some(FileClass::Audio);
+        }
+
+        if starts(b"%PDF") || starts(b"{\\rtf") || starts(b"\xD0\xCF\x11\xE0") {
+            return Some(FileClass::Document);
+        }
+
+        if starts(b"PK\x03\x04")
+            || starts(b"\x1F\x8B")
+            || starts(b"7z\xBC\xAF\x27\x1C")
+            || starts(b"Rar!\x1A\x07")
+            || starts(b"BZh")
+            || starts(b"\xFD7zXZ\0")
+            || starts(b"\x28\xB5\x2F\xFD")
+            || at(257, b"ustar")
+        {
+            return Some(FileClass::Archive);
+        }
+
+        None
+    }
+}
+
+/// A named set of extensions for `@name` in --route rules: the extensions of one
+/// of the --type classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    Images,
+    Videos,
+    Audio,
+    Documents,
+    Archives,
+}
+
+impl Preset {
+    pub fn class(self) -> FileClass {
+        match self {
+            Preset::Images => FileClass::Image,
+            Preset::Videos => FileClass::Video,
+            Preset::Audio => FileClass::Audio,
+            Preset::Documents => FileClass::Document,
+            Preset::Archives => FileClass::Archive,
+        }
+    }
+}
+
+/// Extensions browsers and download clients give files while they're being written:
+/// Firefox, Chrome, generic temporaries, Safari (a directory) and qBittorrent
+const PARTIAL_DOWNLOAD_EXTENSIONS: &[&str] = &["part", "crdownload", "tmp", "download", "!qb"];
+
+/// Whether `name` looks like a download that hasn't finished
+pub fn is_partial_download(name: &OsStr) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| PARTIAL_DOWNLOAD_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Number of bytes read from the start of a file for content sniffing
+const SNIFF_LEN: usize = 512;
+
+/// Read the first bytes of a file for content sniffing
+pub fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    fs::File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Whether a file's content is text or binary, for --only
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ContentKind {
+    Text,
+    Binary,
+}
+
+impl ContentKind {
+    /// Judge a file by the start of its content: text is UTF-8 (or ASCII) without NUL
+    /// bytes. Empty files count as text.
+    pub fn of(header: &[u8]) -> ContentKind {
+        if header.contains(&0) {
+            return ContentKind::Binary;
+        }
+        match std::str::from_utf8(header) {
+            Ok(_) => ContentKind::Text,
+            // The header can end partway through a character
+            Err(e) if e.error_len().is_none() => ContentKind::Text,
+            Err(_) => ContentKind::Binary,
+        }
+    }
+}
+
+/// Classify a file, preferring its content signature when `sniff` is set and
+/// falling back to the extension, also for files that can't be read
+pub fn classify(path: &Path, sniff: bool) -> Option<FileClass> {
+    let by_extension = FileClass::from_extension(path);
+    if sniff && let Ok(header) = read_header(path) {
+        // Office documents are zip files; trust the extension for those
+        match FileClass::from_magic(&header) {
+            Some(FileClass::Archive) if by_extension == Some(FileClass::Document) => {}
+            Some(class) => return Some(class),
+            None => {}
+        }
+    }
+
+    by_extension
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_from_extension() {
+        assert_eq!(
+            FileClass::from_extension(Path::new("a.JPG")),
+            Some(FileClass::Image)
+        );
+        assert_eq!(
+            FileClass::from_extension(Path::new("a.mkv")),
+            Some(FileClass::Video)
+        );
+        assert_eq!(
+            FileClass::from_extension(Path::new("a.flac")),
+            Some(FileClass::Audio)
+        );
+        assert_eq!(
+            FileClass::from_extension(Path::new("a.pdf")),
+            Some(FileClass::Document)
+        );
+        assert_eq!(
+            FileClass::from_extension(Path::new("a.tar.gz")),
+            Some(FileClass::Archive)
+        );
+        assert_eq!(FileClass::from_extension(Path::new("a.rs")), None);
+        assert_eq!(FileClass::from_extension(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn test_content_kind() {
+        assert_eq!(ContentKind::of(b"# Notes\n- milk\n"), ContentKind::Text);
+        assert_eq!(ContentKind::of("caf\u{e9}".as_bytes()), ContentKind::Text);
+        assert_eq!(
+            ContentKind::of(&"\u{e9}".as_bytes()[..1]),
+            ContentKind::Text
+        );
+        assert_eq!(ContentKind::of(b""), ContentKind::Text);
+        assert_eq!(
+            ContentKind::of(b"\x89PNG\r\n\x1a\n\0\0"),
+            ContentKind::Binary
+        );
+        assert_eq!(ContentKind::of(b"caf\xe9 au lait"), ContentKind::Binary);
+    }
+
+    #[test]
+    fn test_is_partial_download() {
+        for name in [
+            "movie.mkv.part",
+            "setup.exe.crdownload",
+            "~report.TMP",
+            "archive.zip.download",
+            "album.flac.!qB",
+        ] {
+            assert!(is_partial_download(OsStr::new(name)), "{}", name);
+        }
+        assert!(!is_partial_download(OsStr::new("movie.mkv")));
+        assert!(!is_partial_download(OsStr::new("part")));
+        assert!(!is_partial_download(OsStr::new("partial.txt")));
+    }
+
+    #[test]
+    fn test_from_magic() {
+        assert_eq!(
+            FileClass::from_magic(b"\xFF\xD8\xFF\xE0rest"),
+            Some(FileClass::Image)
+        );
+        assert_eq!(
+            FileClass::from_magic(b"\x89PNG\r\n\x1a\n"),
+            Some(FileClass::Image)
+        );
+        assert_eq!(
+            FileClass::from_magic(b"\0\0\0\x18ftypheic"),
+            Some(FileClass::Image)
+        );
+        assert_eq!(
+            FileClass::from_magic(b"\0\0\0\x18ftypisom"),
+            Some(FileClass::Video)
+        );
+        assert_eq!(
+            FileClass::from_magic(b"RIFF\0\0\0\0WAVEfmt "),
+            Some(FileClass::Audio)
+        );
+        assert_eq!(FileClass::from_magic(b"ID3\x04"), Some(FileClass::Audio));
+        assert_eq!(
+            FileClass::from_magic(b"%PDF-1.7"),
+            Some(FileClass::Document)
+        );
+        assert_eq!(
+            FileClass::from_magic(b"PK\x03\x04"),
+            Some(FileClass::Archive)
+        );
+        assert_eq!(FileClass::from_magic(b"plain text"), None);
+        assert_eq!(FileClass::from_magic(b""), None);
+    }
+
+    #[test]
+    fn test_classify_sniff() {
+        let temp_dir = TempDir::new().unwrap();
+
+        // A JPEG saved without an extension
+        let photo = temp_dir.path().join("IMG_0001");
+        fs::write(&photo, b"\xFF\xD8\xFF\xE0\0\x10JFIF").unwrap();
+        assert_eq!(classify(&photo, false), None);
+        assert_eq!(classify(&photo, true), Some(FileClass::Image));
+
+        // A .docx is a zip file but still a document
+        let docx = temp_dir.path().join("report.docx");
+        fs::write(&docx, b"PK\x03\x04rest").unwrap();
+        assert_eq!(classify(&docx, true), Some(FileClass::Document));
+
+        // Unrecognized content falls back to the extension
+        let notes = temp_dir.path().join("notes.txt");
+        fs::write(&notes, "hello").unwrap();
+        assert_eq!(classify(&notes, true), Some(FileClass::Document));
+
+        // So does a file that can't be read
+        let missing = temp_dir.path().join("missing.jpg");
+        assert_eq!(classify(&missing, true), Some(FileClass::Image));
+    }
+}
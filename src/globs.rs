@@ -0,0 +1,213 @@
+//! Glob patterns matched against paths relative to the root
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobMatcher};
+
+/// A `--path-glob` pattern. Patterns starting with `!` exclude matching paths.
+#[derive(Debug, Clone)]
+pub struct PathGlob {
+    matcher: GlobMatcher,
+    negated: bool,
+}
+
+impl PathGlob {
+    pub fn parse(pattern: &str) -> Result<PathGlob, String> {
+        let (negated, glob) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+
+        // `*` stays within one path component; `**` crosses them
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid glob '{}': {}", pattern, e))?
+            .compile_matcher();
+
+        Ok(PathGlob { matcher, negated })
+    }
+}
+
+impl std::fmt::Display for PathGlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = if self.negated { "!" } else { "" };
+        write!(f, "{}{}", prefix, self.matcher.glob())
+    }
+}
+
+/// An `--only-dirs` pattern. Patterns with a `/` are matched against directory
+/// paths relative to the root, others against directory names.
+#[derive(Debug, Clone)]
+pub struct DirGlob {
+    matcher: GlobMatcher,
+    full_path: bool,
+}
+
+impl DirGlob {
+    pub fn parse(pattern: &str) -> Result<DirGlob, String> {
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid glob '{}': {}", pattern, e))?
+            .compile_matcher();
+
+        Ok(DirGlob {
+            matcher,
+            full_path: pattern.contains('/'),
+        })
+    }
+
+    /// The directory closest to the root on the way to `relative_file` (a file's
+    /// path relative to the root) that matches, as a path relative to the root
+    pub fn outermost_match<'a>(&self, relative_file: &'a Path) -> Option<&'a Path> {
+        let dirs = relative_file.parent()?.ancestors();
+        dirs.filter(|dir| !dir.as_os_str().is_empty())
+            .filter(|dir| match self.full_path {
+                true => self.matcher.is_match(dir),
+                false => dir
+                    .file_name()
+                    .is_some_and(|name| self.matcher.is_match(name)),
+            })
+            .last()
+    }
+}
+
+impl std::fmt::Display for DirGlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.matcher.glob())
+    }
+}
+
+/// A glob matched against file names, ignoring case
+#[derive(Debug, Clone)]
+pub struct NameGlob {
+    matcher: GlobMatcher,
+}
+
+impl NameGlob {
+    pub fn parse(pattern: &str) -> Result<NameGlob, String> {
+        let matcher = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid glob '{}': {}", pattern, e))?
+            .compile_matcher();
+
+        Ok(NameGlob { matcher })
+    }
+
+    pub fn is_match(&self, name: &OsStr) -> bool {
+        self.matcher.is_match(name)
+    }
+}
+
+impl std::fmt::Display for NameGlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.matcher.glob())
+    }
+}
+
+/// Check a relative path against a list of globs: it must match at least one
+/// inclusion pattern (if there are any) and no exclusion pattern
+pub fn matches_path_globs(globs: &[PathGlob], relative_path: &Path) -> bool {
+    let mut has_includes = false;
+    let mut included = false;
+
+    for glob in globs {
+        let matched = glob.matcher.is_match(relative_path);
+        if glob.negated {
+            if matched {
+                return false;
+            }
+        } else {
+            has_includes = true;
+            included |= matched;
+        }
+    }
+
+    included || !has_includes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn globs(patterns: &[&str]) -> Vec<PathGlob> {
+        patterns
+            .iter()
+            .map(|p| PathGlob::parse(p).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_no_globs_matches_everything() {
+        assert!(matches_path_globs(&[], Path::new("a/b/c.txt")));
+    }
+
+    #[test]
+    fn test_include_glob() {
+        let globs = globs(&["**/*.jpg"]);
+        assert!(matches_path_globs(&globs, Path::new("a/photo.jpg")));
+        assert!(matches_path_globs(&globs, Path::new("a/b/c/photo.jpg")));
+        assert!(!matches_path_globs(&globs, Path::new("a/notes.txt")));
+    }
+
+    #[test]
+    fn test_exclude_glob_at_any_depth() {
+        let globs = globs(&["!**/extras/**"]);
+        assert!(!matches_path_globs(&globs, Path::new("extras/a.txt")));
+        assert!(!matches_path_globs(
+            &globs,
+            Path::new("movie/extras/trailer.mp4")
+        ));
+        assert!(matches_path_globs(&globs, Path::new("movie/movie.mp4")));
+        assert!(matches_path_globs(&globs, Path::new("movie/extras.txt")));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let globs = globs(&["season_*/**", "!season_*/extras/**"]);
+        assert!(matches_path_globs(&globs, Path::new("season_01/e01.mkv")));
+        assert!(!matches_path_globs(
+            &globs,
+            Path::new("season_01/extras/blooper.mkv")
+        ));
+        assert!(!matches_path_globs(&globs, Path::new("specials/e01.mkv")));
+    }
+
+    #[test]
+    fn test_single_star_stays_in_component() {
+        let globs = globs(&["*/*.txt"]);
+        assert!(matches_path_globs(&globs, Path::new("a/b.txt")));
+        assert!(!matches_path_globs(&globs, Path::new("a/b/c.txt")));
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        assert!(PathGlob::parse("a/[b").is_err());
+        assert!(DirGlob::parse("a/[b").is_err());
+    }
+
+    #[test]
+    fn test_dir_glob_outermost_match() {
+        let glob = DirGlob::parse("Season *").unwrap();
+        assert_eq!(
+            glob.outermost_match(Path::new("show/Season 1/extras/Season 1b/e01.mkv")),
+            Some(Path::new("show/Season 1"))
+        );
+        assert_eq!(glob.outermost_match(Path::new("show/e01.mkv")), None);
+        assert_eq!(glob.outermost_match(Path::new("Season 2.mkv")), None);
+
+        let glob = DirGlob::parse("photos/*").unwrap();
+        assert_eq!(
+            glob.outermost_match(Path::new("photos/2024/img.jpg")),
+            Some(Path::new("photos/2024"))
+        );
+        assert_eq!(
+            glob.outermost_match(Path::new("old/photos/2024/img.jpg")),
+            None
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! History of past runs, one JSON object per line in `rflatten/history.jsonl` under
+//! the user's data directory, listed by `rflatten history`
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Done,
+    /// Stopped at a --confirm-every prompt
+    Cancelled,
+    /// Finished, but some files couldn't be moved
+    Errors,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Done => "done",
+            Outcome::Cancelled => "cancelled",
+            Outcome::Errors => "errors",
+        }
+    }
+}
+
+/// One recorded run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run {
+    /// When the run finished, in seconds since the Unix epoch
+    pub time: u64,
+    pub root: PathBuf,
+    /// The command-line arguments after the program name
+    pub args: Vec<String>,
+    pub moved: usize,
+    pub moved_bytes: u64,
+    pub renamed: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub outcome: Outcome,
+    /// The --manifest the moves were recorded in, to replay or undo them
+    pub manifest: Option<PathBuf>,
+}
+
+/// Where the history is kept: `$XDG_DATA_HOME/rflatten/history.jsonl`, falling back
+/// to `~/.local/share`, or `%LOCALAPPDATA%\rflatten\history.jsonl` on Windows
+pub fn path() -> Option<PathBuf> {
+    let var = |name| env::var_os(name).filter(|value| !value.is_empty());
+    let data_dir = if cfg!(windows) {
+        var("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        var("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::home_dir().map(|home| home.join(".local").join("share")))
+    }?;
+    Some(data_dir.join("rflatten").join("history.jsonl"))
+}
+
+/// Append `run` to the history at `path`, creating it if needed
+pub fn record(path: &Path, run: &Run) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let line = serde_json::to_string(run).map_err(io::Error::other)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+/// The runs recorded at `path`, oldest first. Lines that can't be read (e.g. cut off
+/// by a crash) are left out.
+pub fn read(path: &Path) -> io::Result<Vec<Run>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_record_and_read() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("rflatten").join("history.jsonl");
+        assert!(read(&path).unwrap().is_empty());
+
+        let run = Run {
+            time: 1_700_000_000,
+            root: PathBuf::from("/photos"),
+            args: vec!["-y".to_string(), "/photos".to_string()],
+            moved: 3,
+            moved_bytes: 1024,
+            renamed: 1,
+            skipped: 0,
+            errors: 0,
+            outcome: Outcome::Done,
+            manifest: Some(PathBuf::from("/tmp/moves.json")),
+        };
+        record(&path, &run).unwrap();
+        // A line cut off halfway is skipped
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"time\": 17\n")
+            .unwrap();
+        record(&path, &run).unwrap();
+
+        assert_eq!(read(&path).unwrap(), vec![run.clone(), run]);
+    }
+}
@@ -0,0 +1,113 @@
+//! User commands run around moves: --pre-move-cmd, --post-move-cmd and --post-run-cmd
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Run `template` with the shell after replacing each `{name}` placeholder in `vars`
+/// with its path, quoted for the shell. Fails if the command exits unsuccessfully.
+pub fn run(template: &str, vars: &[(&str, &Path)]) -> io::Result<()> {
+    let command = expand(template, vars);
+    let status = shell(&command)
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("could not run '{}': {}", template, e)))?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "'{}' failed ({})",
+            template, status
+        )));
+    }
+    Ok(())
+}
+
+fn expand(template: &str, vars: &[(&str, &Path)]) -> OsString {
+    let mut command = OsString::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let var = rest[start + 1..].find('}').and_then(|end| {
+            let name = &rest[start + 1..start + 1 + end];
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, path)| (end, path))
+        });
+        match var {
+            Some((end, path)) => {
+                command.push(&rest[..start]);
+                command.push(quote(path.as_os_str()));
+                rest = &rest[start + end + 2..];
+            }
+            // Braces that aren't placeholders are left for the shell
+            None => {
+                command.push(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    command.push(rest);
+    command
+}
+
+#[cfg(unix)]
+fn quote(value: &OsStr) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let mut quoted = vec![b'\''];
+    for &byte in value.as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    OsString::from_vec(quoted)
+}
+
+#[cfg(not(unix))]
+fn quote(value: &OsStr) -> OsString {
+    // Windows paths can't contain double quotes
+    let mut quoted = OsString::from("\"");
+    quoted.push(value);
+    quoted.push("\"");
+    quoted
+}
+
+#[cfg(unix)]
+fn shell(command: &OsStr) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &OsStr) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_expand_quotes_paths() {
+        let src = Path::new("/tmp/it's here.txt");
+        assert_eq!(
+            expand("chown me {src} && echo {dest} {other}", &[("src", src)]),
+            OsString::from("chown me '/tmp/it'\\''s here.txt' && echo {dest} {other}")
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dest = temp_dir.path().join("a b.txt");
+        run("echo moved > {dest}", &[("dest", &dest)]).unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "moved\n");
+        assert!(run("exit 3", &[]).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Translations of prompts and the answers they accept, picked from the locale in
+//! LC_ALL, LC_MESSAGES or LANG. Untranslated languages fall back to English.
+
+use std::env;
+
+/// A language with translated prompts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    German,
+    French,
+    Spanish,
+    Italian,
+    Portuguese,
+    Dutch,
+}
+
+/// A user-facing string with translations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Proceed,
+    Continue,
+    /// Asks for the directory name before a large run
+    TypeName,
+    FlattenCancelled,
+    MergeCancelled,
+    ReplayCancelled,
+}
+
+impl Language {
+    /// The language of the current locale, as the first of LC_ALL, LC_MESSAGES and
+    /// LANG that is set says
+    pub fn current() -> Self {
+        let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .unwrap_or_default();
+        Language::from_locale(&locale)
+    }
+
+    /// The language of a locale name like `de_DE.UTF-8`
+    fn from_locale(locale: &str) -> Self {
+        let code = locale
+            .split(['_', '-', '.', '@'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match code.as_str() {
+            "de" => Language::German,
+            "fr" => Language::French,
+            "es" => Language::Spanish,
+            "it" => Language::Italian,
+            "pt" => Language::Portuguese,
+            "nl" => Language::Dutch,
+            _ => Language::English,
+        }
+    }
+
+    pub fn text(self, message: Message) -> &'static str {
+        use Language::*;
+        use Message::*;
+
+        match (self, message) {
+            (English, Proceed) => "Proceed?",
+            (English, Continue) => "Continue?",
+            (English, TypeName) => "Type the directory name to proceed:",
+            (English, FlattenCancelled) => "Flatten cancelled.",
+            (English, MergeCancelled) => "Merge cancelled.",
+            (English, ReplayCancelled) => "Replay cancelled.",
+
+            (German, Proceed) => "Fortfahren?",
+            (German, Continue) => "Weitermachen?",
+            (German, TypeName) => "Zum Fortfahren den Verzeichnisnamen eingeben:",
+            (German, FlattenCancelled) => "Abgebrochen, nichts wurde verschoben.",
+            (German, MergeCancelled) => "Zusammenführen abgebrochen.",
+            (German, ReplayCancelled) => "Wiederholung abgebrochen.",
+
+            (French, Proceed) => "Continuer ?",
+            (French, Continue) => "Poursuivre ?",
+            (French, TypeName) => "Saisissez le nom du répertoire pour continuer :",
+            (French, FlattenCancelled) => "Annulé, aucun fichier n'a été déplacé.",
+            (French, MergeCancelled) => "Fusion annulée.",
+            (French, ReplayCancelled) => "Rejeu annulé.",
+
+            (Spanish, Proceed) => "¿Continuar?",
+            (Spanish, Continue) => "¿Seguir?",
+            (Spanish, TypeName) => "Escriba el nombre del directorio para continuar:",
+            (Spanish, FlattenCancelled) => "Cancelado, no se movió ningún archivo.",
+            (Spanish, MergeCancelled) => "Fusión cancelada.",
+            (Spanish, ReplayCancelled) => "Repetición cancelada.",
+
+            (Italian, Proceed) => "Procedere?",
+            (Italian, Continue) => "Continuare?",
+            (Italian, TypeName) => "Digita il nome della cartella per procedere:",
+            (Italian, FlattenCancelled) => "Annullato, nessun file è stato spostato.",
+            (Italian, MergeCancelled) => "Unione annullata.",
+            (Italian, ReplayCancelled) => "Ripetizione annullata.",
+
+            (Portuguese, Proceed) => "Prosseguir?",
+            (Portuguese, Continue) => "Continuar?",
+            (Portuguese, TypeName) => "Digite o nome do diretório para prosseguir:",
+            (Portuguese, FlattenCancelled) => "Cancelado, nenhum arquivo foi movido.",
+            (Portuguese, MergeCancelled) => "Mesclagem cancelada.",
+            (Portuguese, ReplayCancelled) => "Repetição cancelada.",
+
+            (Dutch, Proceed) => "Doorgaan?",
+            (Dutch, Continue) => "Verdergaan?",
+            (Dutch, TypeName) => "Typ de mapnaam om door te gaan:",
+            (Dutch, FlattenCancelled) => "Geannuleerd, er is niets verplaatst.",
+            (Dutch, MergeCancelled) => "Samenvoegen geannuleerd.",
+            (Dutch, ReplayCancelled) => "Herhalen geannuleerd.",
+        }
+    }
+
+    /// The hint after a yes/no prompt, e.g. `(Y/n)`
+    pub fn yes_no_hint(self) -> &'static str {
+        match self {
+            Language::English => "(Y/n)",
+            Language::German | Language::Dutch => "(J/n)",
+            Language::French => "(O/n)",
+            Language::Spanish | Language::Italian | Language::Portuguese => "(S/n)",
+        }
+    }
+
+    /// Whether `answer` means yes. English answers are accepted in every language,
+    /// as scripts and habits often send them.
+    pub fn is_yes(self, answer: &str) -> bool {
+        let answer = answer.trim().to_lowercase();
+        let words: &[&str] = match self {
+            Language::English => &[],
+            Language::German | Language::Dutch => &["j", "ja"],
+            Language::French => &["o", "oui"],
+            Language::Spanish => &["s", "si", "sí"],
+            Language::Italian => &["s", "si", "sì"],
+            Language::Portuguese => &["s", "sim"],
+        };
+        answer == "y" || answer == "yes" || words.contains(&answer.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_locale() {
+        assert_eq!(Language::from_locale("de_DE.UTF-8"), Language::German);
+        assert_eq!(Language::from_locale("pt-BR"), Language::Portuguese);
+        assert_eq!(Language::from_locale("C"), Language::English);
+        assert_eq!(Language::from_locale(""), Language::English);
+    }
+
+    #[test]
+    fn test_is_yes() {
+        assert!(Language::English.is_yes("Y\n"));
+        assert!(!Language::English.is_yes("ja"));
+        assert!(Language::German.is_yes("Ja"));
+        assert!(Language::German.is_yes("yes"));
+        assert!(Language::Spanish.is_yes("Sí"));
+        assert!(!Language::French.is_yes("non"));
+        assert!(!Language::French.is_yes(""));
+    }
+}
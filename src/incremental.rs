@@ -0,0 +1,163 @@
+//! State for --incremental: the directories whose entries haven't changed since the
+//! last run, so the walker can skip reading them
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the state file, kept in the root
+pub const STATE_FILE_NAME: &str = ".rflatten-state.json";
+
+const STATE_VERSION: u32 = 1;
+
+/// Directories modified this recently aren't recorded, since an entry added in the
+/// same timestamp tick as the recorded mtime would go unnoticed
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    version: u32,
+    /// Filter settings the state was recorded with; other settings select other files
+    fingerprint: String,
+    /// Directories by path relative to the root, with `/` separators
+    dirs: BTreeMap<String, DirState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DirState {
+    /// Modification time as seconds and nanoseconds since the Unix epoch
+    mtime: (u64, u32),
+    /// Subdirectories the walker descended into
+    subdirs: Vec<String>,
+}
+
+impl State {
+    /// Load the state in `root`. Returns `None` if there is none yet or it was
+    /// recorded with other filter settings.
+    pub fn load(root: &Path, fingerprint: &str) -> io::Result<Option<Self>> {
+        let json = match fs::read_to_string(root.join(STATE_FILE_NAME)) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let state: State = serde_json::from_str(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((state.version == STATE_VERSION && state.fingerprint == fingerprint).then_some(state))
+    }
+
+    /// If `dir` hasn't changed since the state was recorded, return the
+    /// subdirectories that were walked in it
+    pub fn unchanged_subdirs(&self, root: &Path, dir: &Path) -> io::Result<Option<Vec<OsString>>> {
+        let Some(recorded) = relative_key(root, dir).and_then(|key| self.dirs.get(&key)) else {
+            return Ok(None);
+        };
+        if mtime(&fs::metadata(dir)?) != Some(recorded.mtime) {
+            return Ok(None);
+        }
+        Ok(Some(recorded.subdirs.iter().map(OsString::from).collect()))
+    }
+}
+
+/// Record `dirs` (each with the subdirectories walked in it) as the state in `root`.
+/// Directories that no longer exist, were modified too recently to trust their
+/// mtime, or have names that aren't valid UTF-8 are left out and read in full on
+/// the next run.
+pub fn save(root: &Path, fingerprint: &str, dirs: &[(PathBuf, Vec<OsString>)]) -> io::Result<()> {
+    let cutoff = SystemTime::now() - RACY_WINDOW;
+    let mut state = State {
+        version: STATE_VERSION,
+        fingerprint: fingerprint.to_string(),
+        dirs: BTreeMap::new(),
+    };
+
+    for (dir, subdirs) in dirs {
+        let Some(key) = relative_key(root, dir) else {
+            continue;
+        };
+        let Some(subdirs) = subdirs
+            .iter()
+            .map(|name| name.to_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let Ok(metadata) = fs::metadata(dir) else {
+            continue;
+        };
+        if metadata
+            .modified()
+            .map_or(true, |modified| modified > cutoff)
+        {
+            continue;
+        }
+        if let Some(mtime) = mtime(&metadata) {
+            state.dirs.insert(key, DirState { mtime, subdirs });
+        }
+    }
+
+    let json = serde_json::to_string(&state).map_err(io::Error::other)?;
+    fs::write(root.join(STATE_FILE_NAME), json + "\n")
+}
+
+fn mtime(metadata: &fs::Metadata) -> Option<(u64, u32)> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+/// `dir` relative to `root` with `/` separators, if every component is valid UTF-8
+fn relative_key(root: &Path, dir: &Path) -> Option<String> {
+    dir.strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .map(|parts| parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn set_mtime(dir: &Path, mtime: SystemTime) {
+        fs::File::open(dir).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn test_unchanged_until_modified() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let dir = root.join("a");
+        fs::create_dir_all(dir.join("b")).unwrap();
+        set_mtime(&dir, SystemTime::now() - Duration::from_secs(60));
+
+        save(root, "filters", &[(dir.clone(), vec![OsString::from("b")])]).unwrap();
+
+        let state = State::load(root, "filters").unwrap().unwrap();
+        assert_eq!(
+            state.unchanged_subdirs(root, &dir).unwrap(),
+            Some(vec![OsString::from("b")])
+        );
+        assert!(State::load(root, "other filters").unwrap().is_none());
+
+        fs::write(dir.join("new.txt"), "new").unwrap();
+        assert_eq!(state.unchanged_subdirs(root, &dir).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recently_modified_dirs_are_not_recorded() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let dir = root.join("a");
+        fs::create_dir(&dir).unwrap();
+
+        save(root, "filters", &[(dir.clone(), Vec::new())]).unwrap();
+
+        let state = State::load(root, "filters").unwrap().unwrap();
+        assert_eq!(state.unchanged_subdirs(root, &dir).unwrap(), None);
+    }
+}
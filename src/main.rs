@@ -1,7 +1,68 @@
+mod archive;
+mod checksums;
+mod completions;
+mod dates;
+mod dedupe;
+mod diff;
+mod doctor;
+mod filetype;
+mod globs;
+mod history;
+mod hooks;
+mod i18n;
+mod incremental;
+mod manifest;
+mod naming;
+mod permissions;
+mod porcelain;
+mod priority;
+mod progress;
+mod provenance;
+mod routes;
+#[cfg(unix)]
+mod server;
+mod space;
+mod sparse;
+mod summary_format;
+mod term;
+mod throttle;
+
+use archive::ArchiveFormat;
+use checksums::Checksums;
 use clap::Parser;
+use dedupe::ContentIndex;
+use filetype::{ContentKind, FileClass};
+use globs::{DirGlob, NameGlob, PathGlob};
+use i18n::{Language, Message};
+use manifest::Manifest;
+use naming::{
+    Backup, ConflictSuffix, MAX_NAME_LEN, NameTemplate, NameTransform, RootNames, UnicodeForm,
+    conflict_name, fit_name_length, normalize_name, sanitize_chars, sanitize_reserved_name,
+    transform_name,
+};
+use progress::Progress;
+use provenance::Provenance;
+use routes::{Route, SubdirTemplate};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+use summary_format::SummaryFormat;
+
+/// Exit status of a --no run that found files it would have moved
+const EXIT_WOULD_MOVE: i32 = 3;
+
+/// Exit status of a run that left files behind because of errors
+const EXIT_FAILED_FILES: i32 = 4;
+
+/// How long to wait before retrying files that were locked by another process
+const LOCKED_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 /// Helper function to display paths without Windows UNC prefix (\\?\)
 fn display_path(path: &Path) -> String {
@@ -10,6 +71,9 @@ fn display_path(path: &Path) -> String {
     // Strip the Windows UNC prefix if present
     #[cfg(target_os = "windows")]
     {
+        if let Some(share) = path_str.strip_prefix(r"\\?\UNC\") {
+            return format!(r"\\{}", share);
+        }
         if let Some(stripped) = path_str.strip_prefix(r"\\?\") {
             return stripped.to_string();
         }
@@ -18,982 +82,7929 @@ fn display_path(path: &Path) -> String {
     path_str
 }
 
+/// Paths this long or longer fail on Windows without the extended-length prefix
+/// (unless long paths are enabled in the registry)
+#[cfg(target_os = "windows")]
+const MAX_PATH: usize = 260;
+
+/// Add the Windows extended-length prefix (\\?\) to long absolute paths so deeply
+/// nested files can be moved on any system. The inverse of `display_path`.
+fn extended_length_path(path: &Path) -> std::borrow::Cow<'_, Path> {
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use std::path::{Component, Prefix};
+
+        if path.as_os_str().encode_wide().count() >= MAX_PATH
+            && let Some(Component::Prefix(prefix)) = path.components().next()
+        {
+            // Verbatim paths are passed to the filesystem as-is, so they must use
+            // backslashes and can't contain . or .. components
+            let mut verbatim = match prefix.kind() {
+                Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:\", letter as char)),
+                Prefix::UNC(server, share) => {
+                    let mut unc = OsString::from(r"\\?\UNC\");
+                    unc.push(server);
+                    unc.push(r"\");
+                    unc.push(share);
+                    unc.push(r"\");
+                    PathBuf::from(unc)
+                }
+                // Already verbatim, or a device path
+                _ => return std::borrow::Cow::Borrowed(path),
+            };
+
+            for component in path.components() {
+                match component {
+                    Component::Normal(part) => verbatim.push(part),
+                    Component::ParentDir => {
+                        verbatim.pop();
+                    }
+                    _ => {}
+                }
+            }
+
+            return std::borrow::Cow::Owned(verbatim);
+        }
+    }
+
+    std::borrow::Cow::Borrowed(path)
+}
+
+/// Format a byte count for display (e.g. "1.5 GiB")
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format an elapsed time, e.g. `12.3s`, `4m 05s` or `1h 02m`
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", elapsed.as_secs_f64())
+    } else if secs < 3600 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+/// Identifier of the device a file lives on, where the platform exposes one
+fn device_id(metadata: &fs::Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.dev())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "rflatten")]
 #[command(version)]
 #[command(about = "Flatten subdirectories by moving all files to the root directory", long_about = None)]
 #[command(arg_required_else_help = true)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(disable_help_subcommand = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory to flatten
     #[arg(required = true)]
-    directory: PathBuf,
-
-    /// Maximum depth to traverse (default: unlimited)
-    #[arg(short = 'n', long = "depth")]
-    max_depth: Option<usize>,
+    directory: Option<PathBuf>,
 
     /// Skip confirmation prompt
     #[arg(short = 'y', long = "yes")]
     skip_confirmation: bool,
 
+    /// Answer no: scan and show the summary, then exit without moving anything, with
+    /// status 3 if there were files to move
+    #[arg(
+        long = "no",
+        conflicts_with_all = ["skip_confirmation", "no_summary", "summary_format"]
+    )]
+    assume_no: bool,
+
+    /// Show the root's entries before and after the run, diff-style, with the
+    /// summary: what the root gains and which directories go away
+    #[arg(long = "diff", conflicts_with_all = ["no_summary", "summary_format"])]
+    diff: bool,
+
     /// Quiet mode - suppress all output except errors
     #[arg(short = 'q', long = "quiet")]
     quiet: bool,
 
-    /// Include only directories that start with these patterns (comma-separated)
+    /// Print one tab-separated line per file (status, source, destination) instead
+    /// of the usual messages. The format is stable across versions. Implies --quiet
+    #[arg(long = "porcelain")]
+    porcelain: bool,
+
+    /// Pause after every N moves to show progress and ask whether to continue
+    #[arg(long = "confirm-every", value_name = "N", conflicts_with = "quiet")]
+    confirm_every: Option<NonZeroUsize>,
+
+    /// Move only the first N files, in the order the run would move them, and stop:
+    /// a trial run to check names, conflicts and permissions on the real tree
+    #[arg(long = "sample", value_name = "N", conflicts_with = "dest_archive")]
+    sample: Option<NonZeroUsize>,
+
+    /// Abort without moving anything if more than this many files would be moved
+    #[arg(long = "max-files", value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Exit successfully without doing anything (or printing the summary) if fewer
+    /// than this many files would be moved
+    #[arg(long = "min-files", value_name = "N")]
+    min_files: Option<usize>,
+
+    /// Refuse to proceed if the files to move total more than this size (e.g. 500M, 2G)
+    #[arg(long = "max-bytes", value_name = "SIZE", value_parser = space::parse_size)]
+    max_bytes: Option<u64>,
+
+    /// Runs moving more than this many files must be confirmed by typing the
+    /// directory name instead of "y"
+    #[arg(
+        long = "typed-confirm-files",
+        value_name = "N",
+        default_value_t = 10_000
+    )]
+    typed_confirm_files: usize,
+
+    /// Runs moving more than this size must be confirmed by typing the directory name
+    /// instead of "y"
+    #[arg(
+        long = "typed-confirm-bytes",
+        value_name = "SIZE",
+        default_value = "50G",
+        value_parser = space::parse_size
+    )]
+    typed_confirm_bytes: u64,
+
+    /// Proceed even when a safety check fails (--max-bytes, flattening /, the home
+    /// directory or a git working copy)
+    #[arg(short = 'f', long = "force")]
+    force: bool,
+
+    /// List every skipped directory and file with the reason it was skipped
+    #[arg(long = "explain")]
+    explain: bool,
+
+    #[command(flatten)]
+    filters: FilterArgs,
+
+    /// Treat names differing only in case as conflicts (default: detect from the filesystem)
+    #[arg(long = "case-insensitive")]
+    case_insensitive: bool,
+
+    /// Replace characters that are invalid on NTFS/exFAT/FAT (: * ? " < > | \) with '_'.
+    /// Implies --windows-names.
+    #[arg(long = "sanitize")]
+    sanitize: bool,
+
+    /// Rename files that would get names Windows reserves (CON, NUL, trailing dots...).
+    /// Always enabled on Windows.
+    #[arg(long = "windows-names")]
+    windows_names: bool,
+
+    /// Suffix appended to reserved names
+    #[arg(
+        long = "reserved-suffix",
+        value_name = "SUFFIX",
+        default_value = "_",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    reserved_suffix: String,
+
+    /// Suffix added to conflicting names. Placeholders: {counter}, {counter:03}
+    /// (zero-padded), {timestamp} (modification time) and {hash} (content hash).
+    #[arg(
+        long = "conflict-suffix",
+        value_name = "FORMAT",
+        default_value = "_{counter}",
+        value_parser = ConflictSuffix::parse
+    )]
+    conflict_suffix: ConflictSuffix,
+
+    /// Counter given to the first conflicting copy of a name
+    #[arg(long = "conflict-start", value_name = "N", default_value_t = 1)]
+    conflict_start: usize,
+
+    /// Zero-pad conflict counters to this many digits (e.g. 3 for _001), so numbered
+    /// copies sort in order in file managers
+    #[arg(long = "conflict-pad", value_name = "WIDTH", default_value_t = 0)]
+    conflict_pad: usize,
+
+    /// Number a conflicting copy with the lowest free counter, instead of continuing
+    /// after the highest one already used for the name (report_1, report_2 -> report_3)
+    #[arg(long = "fill-counter-gaps")]
+    fill_counter_gaps: bool,
+
+    /// Move files whose names conflict into this directory under the root instead of
+    /// renaming them
+    #[arg(
+        long = "conflict-dir",
+        value_name = "DIR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "_conflicts",
+        value_parser = parse_conflict_dir
+    )]
+    conflict_dir: Option<PathBuf>,
+
+    /// With --conflict-dir, keep each conflicting file's original parent directories
+    #[arg(long = "nest-conflicts", requires = "conflict_dir")]
+    nest_conflicts: bool,
+
+    /// What to do when a file's name is already taken in the root
+    #[arg(
+        long = "on-conflict",
+        value_name = "POLICY",
+        default_value = "rename",
+        conflicts_with = "conflict_dir"
+    )]
+    on_conflict: ConflictPolicy,
+
+    /// The conflict policy for files whose names match a glob, e.g. "*.jpg=dedupe"
+    /// or "*.log=skip". Can be repeated; the first matching rule applies, and
+    /// --on-conflict covers everything else
+    #[arg(
+        long = "on-conflict-for",
+        value_name = "GLOB=POLICY",
+        value_parser = ConflictRule::parse,
+        conflicts_with = "conflict_dir"
+    )]
+    conflict_rules: Vec<ConflictRule>,
+
+    /// With --on-conflict overwrite, rename replaced files to name.bak (or another
+    /// suffix), or to numbered backups (name.~1~) with --backup=numbered
+    #[arg(
+        long = "backup",
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ".bak",
+        value_parser = Backup::parse
+    )]
+    backup: Option<Backup>,
+
+    /// Move only one copy of each unique content, deleting the duplicates (or leaving
+    /// them in place with --dedupe=skip, or hard linking them to the kept copy with
+    /// --dedupe=hardlink)
+    #[arg(
+        long = "dedupe",
+        value_name = "ACTION",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "delete"
+    )]
+    dedupe: Option<DedupeAction>,
+
+    /// Unpack archives of these formats (zip, tar, tar.gz) found in subdirectories and
+    /// flatten their files into the root too. Uses the system's tar and unzip
+    #[arg(long = "extract", value_name = "FORMATS", value_delimiter = ',')]
+    extract: Option<Vec<ArchiveFormat>>,
+
+    /// Delete archives after their files are extracted, instead of moving them into
+    /// the root
+    #[arg(long = "delete-archives", requires = "extract")]
+    delete_archives: bool,
+
+    /// Delete empty (zero-byte) files found in subdirectories instead of moving them
+    /// into the root
+    #[arg(long = "prune-empty-files")]
+    prune_empty_files: bool,
+
+    /// Instead of moving files into the root, write them into this flat archive
+    /// (.zip, .tar, .tar.gz or .tgz) under conflict-resolved names, then remove them
+    #[arg(
+        long = "dest-archive",
+        value_name = "FILE",
+        conflicts_with_all = [
+            "no_summary", "dedupe", "extract", "checksums", "provenance", "manifest",
+            "conflict_dir", "incremental", "pre_move_cmd", "post_move_cmd", "post_run_cmd",
+            "prune_empty_files",
+        ]
+    )]
+    dest_archive: Option<PathBuf>,
+
+    /// Write SHA-256 checksums of every file placed in the root to this file, in
+    /// `sha256sum -c` format (paths relative to the root)
+    #[arg(long = "checksums", value_name = "FILE")]
+    checksums: Option<PathBuf>,
+
+    /// Record each file's original path (relative to the root) in an extended attribute
+    /// (user.rflatten.origin) or a <name>.origin sidecar file
+    #[arg(long = "provenance", value_name = "MODE")]
+    provenance: Option<Provenance>,
+
+    /// Record every move in this JSON file, to repeat them on a mirror of the tree with
+    /// `rflatten replay`
+    #[arg(long = "manifest", value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Limit copies across filesystems to this many bytes per second (e.g. 50M), so
+    /// flattening across a network mount doesn't saturate the link
+    #[arg(long = "bwlimit", value_name = "SIZE", value_parser = space::parse_size)]
+    bwlimit: Option<u64>,
+
+    /// Flush every placed file and directory entry to disk before its source is
+    /// removed, so a power loss right after the run can't lose data
+    #[arg(long = "fsync")]
+    fsync: bool,
+
+    /// Remember which directories are settled in a state file in the root
+    /// (.rflatten-state.json), and on later runs skip those that haven't changed
+    #[arg(long = "incremental")]
+    incremental: bool,
+
+    /// Skip the scan that counts files before moving, going straight to the moves.
+    /// Needs --yes or --quiet; --max-files, --max-bytes and the free space check need the scan
+    #[arg(long = "no-summary", conflicts_with_all = ["max_files", "min_files", "max_bytes", "explain"])]
+    no_summary: bool,
+
+    /// Print the summary as JSON or YAML (file counts and bytes, overall and per
+    /// top-level directory) and exit without moving anything, so tools can decide
+    /// whether to run it
+    #[arg(
+        long = "summary-format",
+        value_name = "FORMAT",
+        conflicts_with = "no_summary"
+    )]
+    summary_format: Option<SummaryFormat>,
+
+    /// Order to move files in: depth-first, name, mtime (newest first) or size-desc
+    /// (largest first). Files moved earlier keep their names on conflicts
+    #[arg(long = "order", value_name = "ORDER", default_value = "depth-first")]
+    order: Order,
+
+    /// Process entries in the order the filesystem returns them instead of sorted by
+    /// name. Faster on huge directories, but conflict numbering can differ between runs
+    #[arg(long = "unsorted")]
+    unsorted: bool,
+
+    /// Use the directory path as given instead of resolving symlinks, for network
+    /// filesystems where that is slow or fails, or a symlinked root to keep as-is
+    #[arg(long = "no-canonicalize")]
+    no_canonicalize: bool,
+
+    /// Run with idle I/O priority, so a large flatten on a live server doesn't starve
+    /// other workloads
+    #[arg(long = "nice-io")]
+    nice_io: bool,
+
+    /// Normalize destination names to this Unicode form
+    #[arg(long = "normalize", value_name = "FORM")]
+    normalize: Option<UnicodeForm>,
+
+    /// Clean up destination names: lowercase, slugify or spaces-to-underscores.
+    /// Several can be given, separated by commas, and are applied in order.
+    /// Conflicts are checked after the names are transformed
+    #[arg(long = "transform", value_name = "TRANSFORMS", value_delimiter = ',')]
+    transform: Vec<NameTransform>,
+
+    /// Name files after their modification date (UTC) with a template such as
+    /// "{yyyy}-{mm}-{dd}_{stem}.{ext}". Must contain {stem}
+    #[arg(long = "rename-by-date", value_name = "TEMPLATE", value_parser = NameTemplate::parse)]
+    rename_by_date: Option<NameTemplate>,
+
+    /// Write progress events (scanned, planned, moved, errored, finished) as JSON
+    /// lines to this open file descriptor, for wrappers. Unix only
+    #[arg(long = "progress-fd", value_name = "N")]
+    progress_fd: Option<i32>,
+
+    /// Route files into directories under the root by extension, e.g.
+    /// "jpg,png -> Pictures; mp3,flac -> Music; * -> root". Rules are separated by
+    /// semicolons and the option can be repeated. Files no rule matches stay in the root
+    #[arg(long = "route", value_name = "RULES", value_parser = Route::parse, value_delimiter = ';')]
+    routes: Vec<Route>,
+
+    /// Put files in dated subdirectories under the root instead of directly in it,
+    /// e.g. "{yyyy}/{mm}". Placeholders are {yyyy}, {mm} and {dd}. Combined with
+    /// --route, the subdirectories go inside the route's directory
+    #[arg(long = "dest-subdir", value_name = "TEMPLATE", value_parser = SubdirTemplate::parse)]
+    dest_subdir: Option<SubdirTemplate>,
+
+    /// Date --dest-subdir buckets files by: each file's modification date, or the
+    /// date of the run
+    #[arg(
+        long = "subdir-date",
+        value_name = "DATE",
+        default_value = "mtime",
+        requires = "dest_subdir"
+    )]
+    subdir_date: SubdirDate,
+
+    /// Where the contents of --only-dirs directories go: the root, or the parent of
+    /// each matching directory
+    #[arg(
+        long = "only-dirs-to",
+        value_name = "TO",
+        default_value = "root",
+        requires = "only_dirs",
+        conflicts_with_all = ["routes", "dest_subdir", "dest_archive"]
+    )]
+    only_dirs_to: OnlyDirsTo,
+
+    /// What to do with FIFOs, sockets and device nodes: skip them (listed in the
+    /// summary) or move them like files. Moving them can't be combined with options
+    /// that read file contents
+    #[arg(
+        long = "special-files",
+        value_name = "ACTION",
+        default_value = "skip",
+        conflicts_with_all = ["dedupe", "checksums", "sniff", "only", "dest_archive"]
+    )]
+    special_files: SpecialFiles,
+
+    /// Shell command to run once before each move, with {src} and {dest} replaced by
+    /// the quoted paths. Files are left in place if it fails
+    #[arg(long = "pre-move-cmd", value_name = "COMMAND")]
+    pre_move_cmd: Option<String>,
+
+    /// Shell command to run after each move, with {src} and {dest} replaced by the
+    /// quoted paths
+    #[arg(long = "post-move-cmd", value_name = "COMMAND")]
+    post_move_cmd: Option<String>,
+
+    /// Shell command to run once the flatten is done, with {root} replaced by the
+    /// quoted root directory
+    #[arg(long = "post-run-cmd", value_name = "COMMAND")]
+    post_run_cmd: Option<String>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Show which top-level directories (and optionally files) the filters select,
+    /// without moving anything
+    TestPattern {
+        /// Directory to check
+        directory: PathBuf,
+
+        /// Also list the files that would be moved
+        #[arg(long = "files")]
+        files: bool,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// Check a tree for problems a flatten would run into (long, reserved, non-UTF-8
+    /// or case-colliding names, link loops, other filesystems), without moving anything
+    Doctor {
+        /// Directory to check
+        directory: PathBuf,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// List the top-level directories, one per line, with how many files each holds
+    /// and whether the filters select it: `included|excluded<TAB>FILES<TAB>NAME`
+    List {
+        /// Directory to list
+        directory: PathBuf,
+
+        /// Print a JSON array of {name, files, included} objects instead
+        #[arg(long = "json")]
+        json: bool,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// Flatten several directories into one destination, resolving conflicts between
+    /// them with the conflict policy
+    Merge {
+        /// Directories to merge. Files directly inside them are moved too
+        #[arg(required = true)]
+        sources: Vec<PathBuf>,
+
+        /// Directory to merge into, created if it doesn't exist
+        #[arg(long = "dest", value_name = "DIR")]
+        dest: PathBuf,
+
+        /// Skip confirmation prompt
+        #[arg(short = 'y', long = "yes")]
+        skip_confirmation: bool,
+
+        /// Quiet mode - suppress all output except errors
+        #[arg(short = 'q', long = "quiet")]
+        quiet: bool,
+
+        /// What to do when a name is already taken in the destination
+        #[arg(long = "on-conflict", value_name = "POLICY", default_value = "rename")]
+        on_conflict: ConflictPolicy,
+
+        /// Remove (or skip) files with the same contents as one already merged
+        #[arg(
+            long = "dedupe",
+            value_name = "ACTION",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "delete"
+        )]
+        dedupe: Option<DedupeAction>,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+    /// List past runs, newest first, with the manifests their moves were recorded in
+    History {
+        /// Show at most this many runs
+        #[arg(short = 'n', long = "limit", value_name = "N", default_value_t = 20)]
+        limit: usize,
+    },
+    /// Perform the moves recorded with --manifest on another, identically structured tree
+    Replay {
+        /// Manifest written by a previous run
+        manifest: PathBuf,
+
+        /// Root of the tree to apply the moves to
+        directory: PathBuf,
+
+        /// Skip confirmation prompt
+        #[arg(short = 'y', long = "yes")]
+        skip_confirmation: bool,
+
+        /// Quiet mode - suppress all output except errors
+        #[arg(short = 'q', long = "quiet")]
+        quiet: bool,
+    },
+    /// Print a shell completion script. It also completes --include and --exclude
+    /// with the top-level directories of the directory on the command line
+    Completions {
+        /// Shell to complete in
+        shell: completions::Shell,
+    },
+    /// Serve plan, apply, status, cancel and undo requests as JSON-RPC over a Unix
+    /// socket, for front ends that drive flattens
+    Serve {
+        /// Path of the socket to listen on
+        #[arg(long = "socket", value_name = "PATH")]
+        socket: PathBuf,
+    },
+}
+
+/// Options that select which directories and files are flattened
+#[derive(clap::Args)]
+struct FilterArgs {
+    /// Maximum depth to traverse (default: unlimited)
+    #[arg(short = 'n', long = "depth")]
+    max_depth: Option<usize>,
+
+    /// Where --depth counts from: the root being flattened, or inside each
+    /// top-level directory (one level deeper)
+    #[arg(
+        long = "depth-from",
+        value_name = "FROM",
+        default_value = "root",
+        requires = "max_depth"
+    )]
+    depth_from: DepthFrom,
+
+    /// Only flatten top-level directories holding at most this many files (counted
+    /// recursively), leaving big collections alone
+    #[arg(long = "max-dir-files", value_name = "N")]
+    max_dir_files: Option<usize>,
+
+    /// Stop descending after this many directories, leaving the rest of the tree out:
+    /// a guard against unexpectedly huge or looping trees
+    #[arg(long = "max-dirs", value_name = "N")]
+    max_dirs: Option<usize>,
+
+    /// Include only directories that match these patterns (comma-separated)
     #[arg(short = 'i', long = "include", value_delimiter = ',')]
     include: Option<Vec<String>>,
 
-    /// Exclude directories that start with these patterns (comma-separated)
+    /// Exclude directories that match these patterns (comma-separated)
     #[arg(short = 'e', long = "exclude", value_delimiter = ',')]
     exclude: Option<Vec<String>>,
+
+    /// Only flatten files modified after this date (YYYY-MM-DD[THH:MM[:SS]], UTC) or
+    /// within this duration (e.g. 30d, 12h)
+    #[arg(
+        long = "newer-than",
+        value_name = "DATE|DURATION",
+        value_parser = dates::parse_time_spec
+    )]
+    newer_than: Option<SystemTime>,
+
+    /// Only flatten files modified before this date or longer ago than this duration
+    #[arg(
+        long = "older-than",
+        value_name = "DATE|DURATION",
+        value_parser = dates::parse_time_spec
+    )]
+    older_than: Option<SystemTime>,
+
+    /// Only flatten files whose path relative to the root matches this glob
+    /// (e.g. "**/*.jpg"). Prefix with ! to exclude instead (e.g. "!**/extras/**").
+    /// Can be repeated.
+    #[arg(long = "path-glob", value_name = "GLOB", value_parser = PathGlob::parse)]
+    path_globs: Vec<PathGlob>,
+
+    /// Only flatten files inside directories matching this glob, at any depth
+    /// (e.g. "Season *"). Patterns with a / match paths relative to the root
+    #[arg(long = "only-dirs", value_name = "GLOB", value_parser = DirGlob::parse)]
+    only_dirs: Option<DirGlob>,
+
+    /// Only flatten files of these types (comma-separated), classified by extension
+    #[arg(short = 't', long = "type", value_name = "TYPE", value_delimiter = ',')]
+    types: Option<Vec<FileClass>>,
+
+    /// With --type, classify files by their content signature (falling back to the extension)
+    #[arg(long = "sniff", requires = "types")]
+    sniff: bool,
+
+    /// Only flatten text files or only binary files, judged by the first bytes of
+    /// each file
+    #[arg(long = "only", value_name = "KIND")]
+    only: Option<ContentKind>,
+
+    /// Only flatten files owned by this user (name or uid, Unix only)
+    #[arg(long = "owned-by", value_name = "USER", value_parser = permissions::resolve_user)]
+    owned_by: Option<u32>,
+
+    /// Only flatten files this process has permission to move
+    #[arg(long = "writable-only")]
+    writable_only: bool,
+
+    /// Also move unfinished downloads (.part, .crdownload, .tmp, .download, .!qB),
+    /// which are skipped by default
+    #[arg(long = "include-partial")]
+    include_partial: bool,
+
+    /// Skip files modified within this many seconds (or duration, e.g. 5m), as they
+    /// may still be being written
+    #[arg(long = "settle", value_name = "SECONDS", value_parser = dates::parse_seconds)]
+    settle: Option<Duration>,
+
+    /// Skip directories that match these patterns at any depth (comma-separated)
+    #[arg(
+        long = "exclude-dir",
+        value_name = "EXCLUDE_DIR",
+        value_delimiter = ','
+    )]
+    exclude_dirs: Option<Vec<String>>,
+
+    /// How directory patterns are compared with names
+    #[arg(long = "match", value_name = "MODE", default_value = "prefix")]
+    match_mode: MatchMode,
+
+    /// Compare directory patterns case-sensitively
+    #[arg(long = "case-sensitive")]
+    case_sensitive: bool,
+
+    /// Don't descend into directories on other filesystems
+    #[arg(short = 'x', long = "one-file-system", visible_alias = "xdev")]
+    one_file_system: bool,
+
+    /// Walk into directory symlinks (and junctions on Windows) instead of skipping
+    /// them. Files found through them are moved; the links themselves are kept.
+    /// Directories already walked are skipped, so loops end
+    #[arg(long = "follow-junctions")]
+    follow_junctions: bool,
 }
 
-/// Summary of files to be flattened
-struct FileSummary {
-    file_count: usize,
-    top_level_dirs: std::collections::HashSet<String>,
+/// Parse a --conflict-dir value, which must be a relative path inside the root
+fn parse_conflict_dir(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!(
+            "'{}' must be a relative path inside the root directory",
+            s
+        ));
+    }
+    Ok(path)
 }
 
-/// Prefix match: checks if the target starts with the pattern (case-insensitive)
-fn starts_with_pattern(target: &str, pattern: &str) -> bool {
-    target.to_lowercase().starts_with(&pattern.to_lowercase())
+/// Options shared by the summary and flatten passes
+#[derive(Default)]
+struct FlattenOptions {
+    max_depth: Option<usize>,
+    /// Number of directories below the root the walker descends into at most
+    max_dirs: Option<usize>,
+    /// Top-level directories with more files than this are left alone
+    max_dir_files: Option<usize>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    quiet: bool,
+    /// Print --porcelain lines for each file
+    porcelain: bool,
+    one_file_system: bool,
+    /// Walk into links to directories
+    follow_junctions: bool,
+    /// Whether FIFOs, sockets and device nodes are moved
+    special_files: SpecialFiles,
+    normalize: Option<UnicodeForm>,
+    /// Cleanups applied to destination names, in order
+    transform: Vec<NameTransform>,
+    /// Template naming files after their modification date
+    rename_by_date: Option<NameTemplate>,
+    case_insensitive: bool,
+    /// Suffix for names Windows reserves; `None` leaves them as they are
+    reserved_suffix: Option<String>,
+    sanitize: bool,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    settle: Option<Duration>,
+    types: Option<Vec<FileClass>>,
+    sniff: bool,
+    /// Only text or only binary files
+    only: Option<ContentKind>,
+    owned_by: Option<u32>,
+    writable_only: bool,
+    /// Move unfinished downloads instead of skipping them
+    include_partial: bool,
+    path_globs: Vec<PathGlob>,
+    /// Only files below a matching directory are flattened
+    only_dirs: Option<DirGlob>,
+    /// Files go to the parent of their --only-dirs directory instead of the root
+    only_dirs_to_parent: bool,
+    exclude_dirs: Option<Vec<String>>,
+    matching: PatternMatch,
+    /// Record every skipped entry in the summary, not just other filesystems
+    explain: bool,
+    /// Ask whether to continue after every this many moves
+    confirm_every: Option<NonZeroUsize>,
+    /// Stop after handling this many files
+    sample: Option<NonZeroUsize>,
+    conflict_suffix: ConflictSuffix,
+    /// Use the lowest free conflict counter rather than the one after the highest
+    fill_counter_gaps: bool,
+    /// Directory under the root that conflicting files are moved into
+    conflict_dir: Option<PathBuf>,
+    /// Recreate each conflicting file's original parent directories under `conflict_dir`
+    nest_conflicts: bool,
+    on_conflict: ConflictPolicy,
+    /// Per-pattern overrides of `on_conflict`
+    conflict_rules: Vec<ConflictRule>,
+    /// Keep files replaced by the overwrite policy under a backup name
+    backup: Option<Backup>,
+    dedupe: Option<DedupeAction>,
+    /// Archive formats to unpack and flatten the contents of
+    extract: Option<Vec<ArchiveFormat>>,
+    /// Delete archives once extracted
+    delete_archives: bool,
+    /// Delete empty files instead of moving them
+    prune_empty_files: bool,
+    /// File to write SHA-256 checksums of the placed files to
+    checksums: Option<PathBuf>,
+    /// Where to record each file's original path
+    provenance: Option<Provenance>,
+    /// File to record the moves in, for `rflatten replay`
+    manifest: Option<PathBuf>,
+    /// Bytes per second to copy files across filesystems at, at most
+    bwlimit: Option<u64>,
+    /// Walk entries in the order the filesystem returns them instead of by name
+    unsorted: bool,
+    /// Order to move files in
+    order: Order,
+    /// Flush each placed file and its directory to disk before deleting the source
+    fsync: bool,
+    /// Skip directories that haven't changed since the last run
+    incremental: bool,
+    /// Directories under the root to send files to by extension
+    routes: Vec<Route>,
+    /// Template for the dated subdirectory files are placed in
+    dest_subdir: Option<SubdirTemplate>,
+    /// Date --dest-subdir uses for every file; `None` uses each file's modification date
+    dest_subdir_date: Option<SystemTime>,
+    /// Shell commands run before and after each move
+    pre_move_cmd: Option<String>,
+    post_move_cmd: Option<String>,
+    /// Reported to --progress-fd, or shared with `rflatten serve`, which polls it
+    /// and can stop the run
+    progress: Option<Arc<Progress>>,
 }
 
-/// Check if a top-level directory should be included based on include/exclude patterns
-fn should_include_top_level_dir(
-    dir_name: &str,
-    include: &Option<Vec<String>>,
-    exclude: &Option<Vec<String>>,
-) -> bool {
-    // Check include patterns
-    if let Some(include_patterns) = include {
-        return include_patterns
-            .iter()
-            .any(|p| starts_with_pattern(dir_name, p));
+impl FlattenOptions {
+    /// The settings that decide which files the walker reports. --incremental state
+    /// recorded under other settings is ignored. Time, owner and permission filters
+    /// are left out, as files they skip are never recorded as settled.
+    fn selection_fingerprint(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                self.max_depth,
+                &self.include,
+                &self.exclude,
+                &self.exclude_dirs,
+                self.matching,
+                (self.one_file_system, self.follow_junctions),
+                (&self.types, self.sniff, self.only),
+                (
+                    self.path_globs
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>(),
+                    self.only_dirs.as_ref().map(ToString::to_string),
+                ),
+                &self.conflict_dir,
+                &self.routes,
+                &self.dest_subdir,
+            )
+        )
     }
 
-    // Check exclude patterns
-    if let Some(exclude_patterns) = exclude {
-        return !exclude_patterns
+    /// Whether `name`, a directory directly in the root, is one --route or
+    /// --dest-subdir places files in
+    fn is_placement_dir(&self, name: &OsStr) -> bool {
+        Route::is_route_dir(&self.routes, name)
+            || self
+                .dest_subdir
+                .as_ref()
+                .is_some_and(|template| template.is_bucket(name))
+    }
+
+    /// The conflict policy for a file named `name`: the first --on-conflict-for rule
+    /// that matches, or --on-conflict
+    fn conflict_policy(&self, name: &OsStr) -> ConflictPolicy {
+        self.conflict_rules
             .iter()
-            .any(|p| starts_with_pattern(dir_name, p));
+            .find(|rule| rule.glob.is_match(name))
+            .map_or(self.on_conflict, |rule| rule.policy)
     }
 
-    // No filters, include everything
-    true
-}
+    /// With --only-dirs-to parent, the directory a file at `relative_path` goes to:
+    /// the parent of the --only-dirs directory it's in, relative to the root. `None`
+    /// when that's the root itself.
+    fn only_dirs_parent<'a>(&self, relative_path: &'a Path) -> Option<&'a Path> {
+        if !self.only_dirs_to_parent {
+            return None;
+        }
+        self.only_dirs
+            .as_ref()?
+            .outermost_match(relative_path)?
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+    }
 
-/// Collect summary of files
-fn collect_file_summary(
-    dir: &Path,
-    max_depth: Option<usize>,
-    include: &Option<Vec<String>>,
-    exclude: &Option<Vec<String>>,
-) -> io::Result<FileSummary> {
-    let mut summary = FileSummary {
-        file_count: 0,
-        top_level_dirs: std::collections::HashSet::new(),
-    };
+    /// Check if a directory is pruned by --exclude-dir
+    fn excludes_dir(&self, dir_name: &str) -> bool {
+        self.exclude_dirs
+            .as_ref()
+            .is_some_and(|patterns| patterns.iter().any(|p| self.matching.matches(dir_name, p)))
+    }
+}
 
-    collect_file_summary_recursive(dir, dir, max_depth, 0, include, exclude, &mut summary, None)?;
+impl From<&FilterArgs> for FlattenOptions {
+    fn from(filters: &FilterArgs) -> Self {
+        FlattenOptions {
+            // The walker counts from the root, where top-level directories are level 1
+            max_depth: filters.max_depth.map(|depth| match filters.depth_from {
+                DepthFrom::Root => depth,
+                DepthFrom::TopDir => depth + 1,
+            }),
+            max_dirs: filters.max_dirs,
+            max_dir_files: filters.max_dir_files,
+            include: filters.include.clone(),
+            exclude: filters.exclude.clone(),
+            one_file_system: filters.one_file_system,
+            follow_junctions: filters.follow_junctions,
+            newer_than: filters.newer_than,
+            older_than: filters.older_than,
+            settle: filters.settle,
+            types: filters.types.clone(),
+            sniff: filters.sniff,
+            only: filters.only,
+            owned_by: filters.owned_by,
+            writable_only: filters.writable_only,
+            include_partial: filters.include_partial,
+            path_globs: filters.path_globs.clone(),
+            only_dirs: filters.only_dirs.clone(),
+            exclude_dirs: filters.exclude_dirs.clone(),
+            matching: PatternMatch {
+                mode: filters.match_mode,
+                case_sensitive: filters.case_sensitive,
+            },
+            ..Default::default()
+        }
+    }
+}
 
-    Ok(summary)
+impl Cli {
+    /// Every conflict policy the run can use: --on-conflict and those of the
+    /// --on-conflict-for rules
+    fn conflict_policies(&self) -> impl Iterator<Item = ConflictPolicy> + '_ {
+        std::iter::once(self.on_conflict).chain(self.conflict_rules.iter().map(|rule| rule.policy))
+    }
 }
 
-fn collect_file_summary_recursive(
-    root: &Path,
-    current: &Path,
-    max_depth: Option<usize>,
-    current_depth: usize,
-    include: &Option<Vec<String>>,
-    exclude: &Option<Vec<String>>,
-    summary: &mut FileSummary,
-    top_level_dir: Option<String>,
-) -> io::Result<()> {
-    if let Some(max) = max_depth {
-        if current_depth > max {
-            return Ok(());
+impl From<&Cli> for FlattenOptions {
+    fn from(cli: &Cli) -> Self {
+        FlattenOptions {
+            quiet: cli.quiet,
+            porcelain: cli.porcelain,
+            normalize: cli.normalize,
+            transform: cli.transform.clone(),
+            rename_by_date: cli.rename_by_date.clone(),
+            case_insensitive: cli.case_insensitive,
+            reserved_suffix: (cfg!(target_os = "windows") || cli.windows_names || cli.sanitize)
+                .then(|| cli.reserved_suffix.clone()),
+            sanitize: cli.sanitize,
+            explain: cli.explain,
+            confirm_every: cli.confirm_every,
+            sample: cli.sample,
+            conflict_suffix: cli
+                .conflict_suffix
+                .clone()
+                .with_counter(cli.conflict_start, cli.conflict_pad),
+            fill_counter_gaps: cli.fill_counter_gaps,
+            conflict_dir: cli.conflict_dir.clone(),
+            nest_conflicts: cli.nest_conflicts,
+            on_conflict: cli.on_conflict,
+            conflict_rules: cli.conflict_rules.clone(),
+            backup: cli.backup.clone(),
+            dedupe: cli.dedupe,
+            extract: cli.extract.clone(),
+            delete_archives: cli.delete_archives,
+            prune_empty_files: cli.prune_empty_files,
+            checksums: cli.checksums.clone(),
+            provenance: cli.provenance,
+            manifest: cli.manifest.clone(),
+            bwlimit: cli.bwlimit,
+            unsorted: cli.unsorted,
+            order: cli.order,
+            fsync: cli.fsync,
+            incremental: cli.incremental,
+            routes: cli.routes.clone(),
+            special_files: cli.special_files,
+            dest_subdir: cli.dest_subdir.clone(),
+            dest_subdir_date: (cli.subdir_date == SubdirDate::Run).then(SystemTime::now),
+            only_dirs_to_parent: cli.only_dirs_to == OnlyDirsTo::Parent,
+            pre_move_cmd: cli.pre_move_cmd.clone(),
+            post_move_cmd: cli.post_move_cmd.clone(),
+            ..FlattenOptions::from(&cli.filters)
         }
     }
+}
 
-    for entry in fs::read_dir(current)? {
-        let entry = entry?;
-        let path = entry.path();
-        let file_type = entry.file_type()?;
-
-        if file_type.is_dir() {
-            // Determine the top-level directory name
-            let new_top_level_dir = if current == root {
-                // We're at the root, so this subdirectory is a top-level directory
-                if let Some(dir_name) = path.file_name().and_then(|n| n.to_str()) {
-                    // Check if we should include this top-level directory
-                    if !should_include_top_level_dir(dir_name, include, exclude) {
-                        continue; // Skip this entire subtree
-                    }
-                    Some(dir_name.to_string())
-                } else {
-                    continue;
-                }
-            } else {
-                // We're in a subdirectory, inherit the top-level directory
-                top_level_dir.clone()
-            };
+/// Summary of files to be flattened
+#[derive(Default)]
+struct FileSummary {
+    file_count: usize,
+    total_bytes: u64,
+    top_level_dirs: HashSet<OsString>,
+    /// Files on a different filesystem than the root, which must be copied
+    cross_device_files: usize,
+    cross_device_bytes: u64,
+    /// Files with the same contents as another file (--dedupe)
+    duplicate_files: usize,
+    duplicate_bytes: u64,
+    /// Empty files that --prune-empty-files will delete
+    empty_files: usize,
+    /// Git working copies containing the root, or found directly below it
+    git_working_copies: Vec<PathBuf>,
+    /// Files that --extract will unpack
+    archives: usize,
+    /// Every file found, as it was when found
+    snapshot: Snapshot,
+    /// Directories walked, minus those with files a later run should look at again.
+    /// Only meaningful when no files were found, for the --incremental state.
+    settled_dirs: Vec<(PathBuf, Vec<OsString>)>,
+    /// Directories that were not traversed and files that were filtered out, and why
+    skipped: Vec<(PathBuf, SkipReason)>,
+    /// The walk stopped descending at --max-dirs
+    dir_limit_reached: bool,
+}
 
-            // Recursively traverse subdirectories
-            collect_file_summary_recursive(
-                root,
-                &path,
-                max_depth,
-                current_depth + 1,
-                include,
-                exclude,
-                summary,
-                new_top_level_dir,
-            )?;
-        } else if file_type.is_file() {
-            // Only count files that are in subdirectories (not in root)
-            if path.parent() != Some(root) {
-                summary.file_count += 1;
+/// Result of the flatten pass
+#[derive(Default)]
+struct FlattenReport {
+    moved_count: usize,
+    moved_bytes: u64,
+    /// Files given a new name because theirs was taken
+    renamed: usize,
+    /// Files left in place at an --on-conflict prompt or by the policy
+    skipped: usize,
+    /// Files that couldn't be moved (or deleted, extracted or checked) because of
+    /// an error
+    failed: Vec<FailedFile>,
+    /// Files that were still locked by another process after the retry pass
+    locked: Vec<PathBuf>,
+    /// Files renamed to be valid on the destination: original path and new name
+    sanitized: Vec<(PathBuf, OsString)>,
+    /// The run was stopped early: at a --confirm-every prompt, by a cancel request or
+    /// after the --sample files
+    cancelled: bool,
+    /// The run stopped after the --sample files, with more left to move
+    sampled: bool,
+    /// The walk stopped descending at --max-dirs
+    dir_limit_reached: bool,
+    /// Files moved into --conflict-dir instead of the root
+    quarantined: usize,
+    /// Existing root files replaced by --on-conflict overwrite
+    overwritten: usize,
+    /// Files with the same contents as one already in the root (--dedupe)
+    duplicates: usize,
+    duplicate_bytes: u64,
+    /// Archives unpacked with --extract
+    extracted: usize,
+    /// Empty files deleted by --prune-empty-files
+    pruned_empty: usize,
+    /// Directories with nothing left to do in them and the subdirectories walked in
+    /// each, for the --incremental state
+    settled_dirs: Vec<(PathBuf, Vec<OsString>)>,
+    /// Files left in place because they changed after the summary was shown
+    changed_since_scan: Vec<PathBuf>,
+    /// Files left in place because they weren't there when the summary was shown
+    appeared_since_scan: Vec<PathBuf>,
+    /// Files in the summary that were gone by the time they were to be moved
+    vanished_since_scan: Vec<PathBuf>,
+}
 
-                // Track the top-level directory
-                if let Some(ref dir) = top_level_dir {
-                    summary.top_level_dirs.insert(dir.clone());
-                }
-            }
+/// A file an error got in the way of
+#[derive(Debug)]
+struct FailedFile {
+    path: PathBuf,
+    /// What was being done to the file: "move", "delete", "extract" or "check"
+    action: &'static str,
+    kind: io::ErrorKind,
+    message: String,
+}
+
+/// What to do about errors of a kind before running again
+fn remedy(kind: io::ErrorKind) -> &'static str {
+    match kind {
+        io::ErrorKind::PermissionDenied => {
+            "check the permissions of the files and their directories, or run as a user who can change them"
+        }
+        io::ErrorKind::NotFound => {
+            "the files were moved or removed during the run; run again to pick up anything left"
+        }
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => {
+            "free up space on the destination"
+        }
+        io::ErrorKind::FileTooLarge => "the destination filesystem can't hold files this large",
+        io::ErrorKind::ReadOnlyFilesystem => "remount the filesystem read-write",
+        io::ErrorKind::InvalidFilename => {
+            "the names aren't valid on the destination; use --sanitize"
         }
+        io::ErrorKind::ResourceBusy => "close the programs that have the files open",
+        _ => "fix the cause and run again",
     }
-
-    Ok(())
 }
 
-fn get_confirmation() -> io::Result<bool> {
-    print!("Proceed? (Y/n): ");
-    io::stdout().flush()?;
+impl FlattenReport {
+    /// Record a file an error got in the way of, for the report at the end of the run
+    fn fail(&mut self, path: &Path, action: &'static str, error: &io::Error) {
+        self.failed.push(FailedFile {
+            path: path.to_path_buf(),
+            action,
+            kind: error.kind(),
+            message: error.to_string(),
+        });
+    }
 
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    let input = input.trim().to_uppercase();
+    /// Files errors got in the way of, locked ones included
+    fn error_count(&self) -> usize {
+        self.failed.len() + self.locked.len()
+    }
 
-    Ok(input == "Y" || input == "YES")
-}
+    /// Files left in place on purpose: declined in a conflict, or changed or new
+    /// since the scan
+    fn skipped_count(&self) -> usize {
+        self.skipped + self.changed_since_scan.len() + self.appeared_since_scan.len()
+    }
 
-/// Flatten directory
-fn flatten_directory_by_traversal(
-    root: &Path,
-    max_depth: Option<usize>,
-    include: &Option<Vec<String>>,
-    exclude: &Option<Vec<String>>,
-    quiet: bool,
-) -> io::Result<usize> {
-    let mut moved_count = 0;
+    /// End-of-run statistics for the `finished` --progress-fd event and `rflatten serve`
+    fn statistics(&self, elapsed: Duration) -> serde_json::Value {
+        let seconds = elapsed.as_secs_f64();
+        serde_json::json!({
+            "moved": self.moved_count,
+            "moved_bytes": self.moved_bytes,
+            "elapsed_seconds": seconds,
+            "bytes_per_second": if seconds > 0.0 {
+                (self.moved_bytes as f64 / seconds) as u64
+            } else {
+                0
+            },
+            "renamed": self.renamed,
+            "skipped": self.skipped_count(),
+            "errors": self.error_count(),
+            "failed": self.failed.iter().map(|failed| serde_json::json!({
+                "path": failed.path,
+                "action": failed.action,
+                "kind": failed.kind.to_string(),
+                "error": failed.message,
+                "remedy": remedy(failed.kind),
+            })).collect::<Vec<_>>(),
+            "locked": self.locked.len(),
+            "pruned_empty": self.pruned_empty,
+            "cancelled": self.cancelled,
+        })
+    }
 
-    flatten_directory_by_traversal_recursive(
-        root,
-        root,
-        max_depth,
-        0,
-        include,
-        exclude,
-        &mut moved_count,
-        None,
-        quiet,
-    )?;
+    /// Print how much was moved, how fast, and what didn't go to plan
+    fn print_statistics(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let throughput = if seconds > 0.0 {
+            format!(
+                " ({}/s)",
+                format_bytes((self.moved_bytes as f64 / seconds) as u64)
+            )
+        } else {
+            String::new()
+        };
+        println!(
+            "Moved {} in {}{}",
+            format_bytes(self.moved_bytes),
+            format_elapsed(elapsed),
+            throughput
+        );
+        println!(
+            "{} conflict(s) renamed, {} file(s) skipped, {} error(s)",
+            self.renamed,
+            self.skipped_count(),
+            self.error_count()
+        );
+    }
 
-    Ok(moved_count)
+    /// List the files errors got in the way of, grouped by the kind of error, so
+    /// they aren't lost among the progress lines
+    fn print_failures(&self) {
+        if self.failed.is_empty() {
+            return;
+        }
+        eprintln!("\n{} file(s) failed:", self.failed.len());
+        let mut kinds = Vec::new();
+        for failed in &self.failed {
+            if !kinds.contains(&failed.kind) {
+                kinds.push(failed.kind);
+            }
+        }
+        for kind in kinds {
+            let failed: Vec<_> = self.failed.iter().filter(|f| f.kind == kind).collect();
+            eprintln!("  {} ({}): {}", kind, failed.len(), remedy(kind));
+            for failed in failed {
+                eprintln!(
+                    "    - {}: couldn't {}: {}",
+                    display_path(&failed.path),
+                    failed.action,
+                    failed.message
+                );
+            }
+        }
+    }
 }
 
-fn flatten_directory_by_traversal_recursive(
-    root: &Path,
-    current: &Path,
-    max_depth: Option<usize>,
-    current_depth: usize,
-    include: &Option<Vec<String>>,
-    exclude: &Option<Vec<String>>,
-    moved_count: &mut usize,
-    top_level_dir: Option<String>,
-    quiet: bool,
-) -> io::Result<()> {
-    if let Some(max) = max_depth {
-        if current_depth > max {
-            return Ok(());
+/// Size and modification time of a file, to notice it changing between passes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    fn of(metadata: &fs::Metadata) -> Self {
+        FileStamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
         }
     }
+}
 
-    for entry in fs::read_dir(current)? {
-        let entry = entry?;
-        let path = entry.path();
-        let file_type = entry.file_type()?;
-
-        if file_type.is_dir() {
-            // Determine the top-level directory name
-            let new_top_level_dir = if current == root {
-                // We're at the root, so this subdirectory is a top-level directory
-                if let Some(dir_name) = path.file_name().and_then(|n| n.to_str()) {
-                    // Check if we should include this top-level directory
-                    if !should_include_top_level_dir(dir_name, include, exclude) {
-                        continue; // Skip this entire subtree
-                    }
-                    Some(dir_name.to_string())
-                } else {
-                    continue;
-                }
-            } else {
-                // We're in a subdirectory, inherit the top-level directory
-                top_level_dir.clone()
-            };
+/// The files the summary pass found, which the flatten pass then moves
+type Snapshot = HashMap<PathBuf, FileStamp>;
+
+/// What to do with files whose contents are already in the root
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum DedupeAction {
+    /// Delete the duplicate
+    Delete,
+    /// Leave the duplicate where it is
+    Skip,
+    /// Move the duplicate's name into the root as a hard link to the existing copy
+    Hardlink,
+}
 
-            // Recursively traverse subdirectories
-            flatten_directory_by_traversal_recursive(
-                root,
-                &path,
-                max_depth,
-                current_depth + 1,
-                include,
-                exclude,
-                moved_count,
-                new_top_level_dir,
-                quiet,
-            )?;
-        } else if file_type.is_file() {
-            // Only move files that are in subdirectories (not in root)
-            if path.parent() != Some(root) {
-                // Move the file to root
-                let file_name = match path.file_name() {
-                    Some(name) => name,
-                    None => continue,
-                };
+/// What to do with FIFOs, sockets and device nodes in the tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum SpecialFiles {
+    /// Leave them in place and list them in the summary
+    #[default]
+    Skip,
+    /// Move them into the root like regular files
+    Move,
+}
 
-                let mut dest = root.join(file_name);
+/// Date --dest-subdir buckets files by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SubdirDate {
+    /// Each file's modification date
+    Mtime,
+    /// The date the run started
+    Run,
+}
 
-                // Handle filename conflicts by appending a number
-                let mut counter = 1;
-                while dest.exists() {
-                    // If the destination exists but is a directory, don't try to rename
-                    // Let fs::rename fail and handle the error below
-                    if dest.is_dir() {
-                        break;
-                    }
+/// What to do when a file's name is already taken in the root
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum ConflictPolicy {
+    /// Rename the incoming file with a suffix
+    #[default]
+    Rename,
+    /// Replace the existing file
+    Overwrite,
+    /// Ask for each conflict whether to keep the existing file, overwrite it, rename
+    /// the incoming file or skip it
+    Ask,
+    /// Keep whichever file is larger. The smaller one is removed, or moved into
+    /// --conflict-dir if one is given
+    KeepLarger,
+    /// Prefix the incoming file's name with its parent directory's
+    /// (`docs/readme.txt` -> `docs_readme.txt`), adding a suffix only if that is
+    /// taken too
+    PrefixParent,
+    /// Leave the incoming file where it is
+    Skip,
+    /// Remove the incoming file if it has the same contents as the existing one,
+    /// and rename it otherwise
+    Dedupe,
+}
 
-                    let stem = Path::new(file_name)
-                        .file_stem()
-                        .and_then(|s| s.to_str())
-                        .unwrap_or("file");
-                    let extension = Path::new(file_name)
-                        .extension()
-                        .and_then(|s| s.to_str())
-                        .unwrap_or("");
+/// An --on-conflict-for rule: the conflict policy for files whose names match a glob
+#[derive(Debug, Clone)]
+struct ConflictRule {
+    glob: NameGlob,
+    policy: ConflictPolicy,
+}
 
-                    let new_name = if extension.is_empty() {
-                        format!("{}_{}", stem, counter)
-                    } else {
-                        format!("{}_{}.{}", stem, counter, extension)
-                    };
+impl ConflictRule {
+    /// Parse a rule like `*.jpg=dedupe` (used as a clap value parser)
+    fn parse(s: &str) -> Result<Self, String> {
+        let (pattern, policy) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected GLOB=POLICY in '{}'", s))?;
+        let policy = <ConflictPolicy as clap::ValueEnum>::from_str(policy.trim(), true)
+            .map_err(|_| format!("unknown conflict policy '{}' in '{}'", policy.trim(), s))?;
+        Ok(ConflictRule {
+            glob: NameGlob::parse(pattern.trim())?,
+            policy,
+        })
+    }
+}
 
-                    dest = root.join(new_name);
-                    counter += 1;
-                }
+/// An answer to an --on-conflict ask prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictChoice {
+    /// Remove the incoming file
+    KeepExisting,
+    Overwrite,
+    Rename,
+    /// Leave the incoming file where it is
+    Skip,
+}
 
-                match fs::rename(&path, &dest) {
-                    Ok(_) => {
-                        *moved_count += 1;
-                        if !quiet {
-                            println!("Moved: {} -> {}", display_path(&path), display_path(&dest));
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error moving {}: {}", display_path(&path), e);
-                    }
-                }
-            }
+/// A file that wasn't moved because of an answer at an --on-conflict ask prompt
+#[derive(Debug)]
+struct Declined {
+    /// The incoming file was removed in favor of the existing one
+    removed: bool,
+}
+
+impl std::fmt::Display for Declined {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.removed {
+            write!(f, "Removed (kept the existing file)")
+        } else {
+            write!(f, "Skipped")
         }
     }
-
-    Ok(())
 }
 
-fn main() -> io::Result<()> {
-    let cli = Cli::parse();
+impl std::error::Error for Declined {}
 
-    // Validate that both include and exclude aren't used together
-    if cli.include.is_some() && cli.exclude.is_some() {
-        eprintln!("Error: Cannot use both --include and --exclude options at the same time");
-        std::process::exit(1);
-    }
+/// Check if an error is a file left unmoved at an --on-conflict ask prompt
+fn is_declined(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|e| e.is::<Declined>())
+}
 
-    // Verify directory exists
-    if !cli.directory.exists() {
-        eprintln!(
-            "Error: Directory '{}' does not exist",
-            display_path(&cli.directory)
-        );
-        std::process::exit(1);
+/// The --porcelain status for a file left unmoved in a conflict
+fn declined_status(error: &io::Error) -> porcelain::Status {
+    match error.get_ref().and_then(|e| e.downcast_ref::<Declined>()) {
+        Some(Declined { removed: true }) => porcelain::Status::Removed,
+        _ => porcelain::Status::Skipped,
     }
+}
 
-    if !cli.directory.is_dir() {
-        eprintln!(
-            "Error: '{}' is not a directory",
-            display_path(&cli.directory)
+/// Show both sides of a conflict and ask what to do. Returns the choice and whether
+/// it applies to all remaining conflicts.
+fn ask_conflict(incoming: &Path, existing: &Path) -> io::Result<(ConflictChoice, bool)> {
+    let describe = |path: &Path| -> io::Result<String> {
+        let metadata = fs::metadata(path)?;
+        Ok(format!(
+            "{}, modified {}",
+            format_bytes(metadata.len()),
+            dates::format_timestamp(metadata.modified()?)
+        ))
+    };
+    let mut prompt = format!(
+        "\nConflict: {} already exists\n  existing: {}\n  incoming: {} ({})\n",
+        display_path(existing),
+        describe(existing)?,
+        display_path(incoming),
+        describe(incoming)?
+    );
+
+    loop {
+        prompt.push_str(
+            "[k]eep existing, [o]verwrite, [r]ename, [s]kip (capital letter: all remaining): ",
         );
-        std::process::exit(1);
+        let input = term::prompt(&prompt)?.unwrap_or_default();
+        if input.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no answer to the conflict prompt",
+            ));
+        }
+        prompt.clear();
+        let input = input.trim();
+        let choice = match input.to_lowercase().as_str() {
+            "k" | "keep" => ConflictChoice::KeepExisting,
+            "o" | "overwrite" => ConflictChoice::Overwrite,
+            "r" | "rename" => ConflictChoice::Rename,
+            "s" | "skip" => ConflictChoice::Skip,
+            _ => continue,
+        };
+        let all = input.chars().next().is_some_and(char::is_uppercase);
+        return Ok((choice, all));
     }
+}
 
-    // Canonicalize the path to get the full absolute path
-    let canonical_directory = cli.directory.canonicalize()?;
+/// Order files are moved in. The first file to claim a name keeps it on a conflict.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum Order {
+    /// Directory by directory, in name order
+    #[default]
+    DepthFirst,
+    /// By file name across all directories
+    Name,
+    /// Newest first
+    Mtime,
+    /// Largest first
+    SizeDesc,
+}
 
-    // Collect summary of files to be moved (memory efficient - doesn't store all paths)
-    let summary = collect_file_summary(
-        &canonical_directory,
-        cli.max_depth,
-        &cli.include,
-        &cli.exclude,
-    )?;
+/// What --depth counts levels from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum DepthFrom {
+    /// The root: --depth 1 walks only the top-level directories
+    Root,
+    /// Inside each top-level directory: --depth 1 walks their subdirectories too
+    #[value(name = "topdir")]
+    TopDir,
+}
 
-    if summary.file_count == 0 {
-        if !cli.quiet {
-            println!("No files found in subdirectories to flatten.");
-        }
-        return Ok(());
-    }
+/// Where --only-dirs sends the files it selects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OnlyDirsTo {
+    /// The root being flattened
+    Root,
+    /// The directory each matching directory is in
+    Parent,
+}
 
-    // Show summary and get confirmation
-    if !cli.quiet {
-        println!(
-            "Found {} file(s) to move to '{}'",
-            summary.file_count,
-            display_path(&canonical_directory)
-        );
+/// How directory patterns are compared with directory names
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum MatchMode {
+    /// The name must equal the pattern
+    Exact,
+    /// The name must start with the pattern
+    #[default]
+    Prefix,
+    /// The name must contain the pattern
+    Substring,
+}
 
-        if !summary.top_level_dirs.is_empty() {
-            println!("Top-level directories to be flattened:");
-            let mut dirs: Vec<_> = summary.top_level_dirs.iter().cloned().collect();
-            dirs.sort();
-            for dir in dirs {
-                println!("  - {}", dir);
-            }
-        }
-    }
+/// Settings for comparing --include/--exclude/--exclude-dir patterns with names.
+/// The default is case-insensitive prefix matching.
+#[derive(Debug, Clone, Copy, Default)]
+struct PatternMatch {
+    mode: MatchMode,
+    case_sensitive: bool,
+}
 
-    // Skip confirmation if -y or -q is provided
-    if !cli.skip_confirmation && !cli.quiet {
-        if !get_confirmation()? {
-            println!("Flatten cancelled.");
-            return Ok(());
+impl PatternMatch {
+    fn matches(&self, target: &str, pattern: &str) -> bool {
+        let (target, pattern) = if self.case_sensitive {
+            (target.to_string(), pattern.to_string())
+        } else {
+            (target.to_lowercase(), pattern.to_lowercase())
+        };
+
+        match self.mode {
+            MatchMode::Exact => target == pattern,
+            MatchMode::Prefix => target.starts_with(&pattern),
+            MatchMode::Substring => target.contains(&pattern),
         }
     }
+}
 
-    // Perform the flattening (re-traverses the filesystem)
-    let moved_count = flatten_directory_by_traversal(
-        &canonical_directory,
-        cli.max_depth,
-        &cli.include,
-        &cli.exclude,
-        cli.quiet,
-    )?;
+/// Check if a top-level directory should be included based on include/exclude patterns
+fn should_include_top_level_dir(
+    dir_name: &str,
+    include: &Option<Vec<String>>,
+    exclude: &Option<Vec<String>>,
+    matching: PatternMatch,
+) -> bool {
+    top_level_skip_reason(dir_name, include, exclude, matching).is_none()
+}
 
-    if !cli.quiet {
-        println!("\nSuccessfully moved {} file(s)", moved_count);
+/// Check a top-level directory against the include/exclude patterns. Include patterns
+/// select candidates first, then exclude patterns remove from that set.
+fn top_level_skip_reason(
+    dir_name: &str,
+    include: &Option<Vec<String>>,
+    exclude: &Option<Vec<String>>,
+    matching: PatternMatch,
+) -> Option<SkipReason> {
+    // Check include patterns
+    if let Some(include_patterns) = include
+        && !include_patterns
+            .iter()
+            .any(|p| matching.matches(dir_name, p))
+    {
+        return Some(SkipReason::NotIncluded);
     }
 
-    // Delete the now-empty top-level directories
-    for dir in &summary.top_level_dirs {
-        let dir_path = canonical_directory.join(dir);
-        if dir_path.exists() && dir_path.is_dir() {
-            match fs::remove_dir_all(&dir_path) {
-                Ok(_) => {}
-                Err(e) => eprintln!("Error removing directory {}: {}", dir, e),
-            }
-        }
+    // Check exclude patterns
+    if let Some(exclude_patterns) = exclude
+        && exclude_patterns
+            .iter()
+            .any(|p| matching.matches(dir_name, p))
+    {
+        return Some(SkipReason::Excluded);
     }
 
-    Ok(())
+    None
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::TempDir;
+/// Size of a file that was just moved to `dest`, for byte-based progress
+fn moved_len(dest: &Path) -> u64 {
+    fs::symlink_metadata(dest).map_or(0, |metadata| metadata.len())
+}
 
-    fn create_test_structure(root: &Path) -> io::Result<()> {
-        // Create a nested directory structure:
-        // root/
-        //   file0.txt (should not be moved - already in root)
-        //   level1/
-        //     file1.txt (depth 1)
-        //     level2/
-        //       file2.txt (depth 2)
-        //       level3/
-        //         file3.txt (depth 3)
-        //         level4/
-        //           file4.txt (depth 4)
+/// Check if an error means the file is held open by another process
+/// (e.g. a sync client such as Dropbox or OneDrive)
+fn is_locked_error(error: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION (32) and ERROR_LOCK_VIOLATION (33)
+    #[cfg(target_os = "windows")]
+    {
+        matches!(error.raw_os_error(), Some(32) | Some(33))
+    }
 
-        fs::write(root.join("file0.txt"), "root level")?;
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = error;
+        false
+    }
+}
 
-        let level1 = root.join("level1");
-        fs::create_dir(&level1)?;
-        fs::write(level1.join("file1.txt"), "depth 1")?;
+/// Why the walker did not descend into a directory or report a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SkipReason {
+    /// The directory is on a different filesystem than the root (--one-file-system)
+    OtherDevice,
+    /// The directory is deeper than --depth allows
+    MaxDepth,
+    /// The walker had already descended into --max-dirs directories
+    MaxDirs,
+    /// The top-level directory matches none of the --include patterns
+    NotIncluded,
+    /// The top-level directory matches an --exclude pattern
+    Excluded,
+    /// The top-level directory holds more than --max-dir-files files
+    TooManyFiles,
+    /// The directory matches an --exclude-dir pattern
+    ExcludedDir,
+    /// Symbolic links are never followed or moved. On Windows this includes NTFS
+    /// junctions and volume mount points, which the standard library reports as links.
+    Symlink,
+    /// The directory was already walked, through a link or directly (--follow-junctions)
+    AlreadyWalked,
+    /// FIFOs, sockets and device nodes are only moved with --special-files move
+    SpecialFile,
+    /// The file's path doesn't pass the --path-glob filters
+    PathGlob,
+    /// The file isn't below a directory matching --only-dirs
+    NotInOnlyDirs,
+    /// The file isn't one of the --type classes
+    FileType,
+    /// The file's content isn't the kind --only selects
+    ContentKind,
+    /// The file couldn't be read to check its content for --only
+    Unreadable,
+    /// The file isn't owned by the --owned-by user
+    Owner,
+    /// The file can't be moved by this process (--writable-only)
+    NotWritable,
+    /// The file wasn't modified after --newer-than
+    TooOld,
+    /// The file wasn't modified before --older-than
+    TooNew,
+    /// The file was modified within the --settle window
+    Settling,
+    /// The file or directory is a download still in progress (see --include-partial)
+    PartialDownload,
+    /// The directory holds files set aside by --conflict-dir
+    ConflictDir,
+    /// The directory holds files sent there by --route or --dest-subdir
+    RouteDir,
+    /// The directory's entries haven't changed since the last --incremental run;
+    /// only its subdirectories are walked
+    Unchanged,
+}
 
-        let level2 = level1.join("level2");
-        fs::create_dir(&level2)?;
-        fs::write(level2.join("file2.txt"), "depth 2")?;
+impl SkipReason {
+    /// Whether a file skipped for this reason stays skipped as long as its
+    /// directory doesn't change. Times, owners and permissions can change without
+    /// touching the directory.
+    fn is_stable(self) -> bool {
+        !matches!(
+            self,
+            SkipReason::Owner
+                | SkipReason::NotWritable
+                | SkipReason::TooOld
+                | SkipReason::TooNew
+                | SkipReason::Settling
+                | SkipReason::PartialDownload
+                | SkipReason::Unreadable
+        )
+    }
+}
 
-        let level3 = level2.join("level3");
-        fs::create_dir(&level3)?;
-        fs::write(level3.join("file3.txt"), "depth 3")?;
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::OtherDevice => write!(f, "on a different filesystem"),
+            SkipReason::MaxDepth => write!(f, "beyond the maximum depth"),
+            SkipReason::MaxDirs => write!(f, "beyond the --max-dirs limit"),
+            SkipReason::NotIncluded => write!(f, "not matched by --include"),
+            SkipReason::Excluded => write!(f, "excluded by --exclude"),
+            SkipReason::TooManyFiles => write!(f, "more files than --max-dir-files"),
+            SkipReason::ExcludedDir => write!(f, "excluded by --exclude-dir"),
+            SkipReason::Symlink if cfg!(windows) => {
+                write!(f, "symbolic link, junction or mount point")
+            }
+            SkipReason::Symlink => write!(f, "symbolic link"),
+            SkipReason::AlreadyWalked => write!(f, "already walked through another path"),
+            SkipReason::SpecialFile => write!(
+                f,
+                "pipe, socket or device (use --special-files move to move it)"
+            ),
+            SkipReason::PathGlob => write!(f, "filtered by --path-glob"),
+            SkipReason::NotInOnlyDirs => write!(f, "not inside an --only-dirs directory"),
+            SkipReason::FileType => write!(f, "not a selected --type"),
+            SkipReason::ContentKind => write!(f, "not the kind of content --only selects"),
+            SkipReason::Unreadable => write!(f, "couldn't be read to check its content (--only)"),
+            SkipReason::Owner => write!(f, "not owned by the --owned-by user"),
+            SkipReason::NotWritable => write!(f, "no permission to move"),
+            SkipReason::TooOld => write!(f, "not modified after --newer-than"),
+            SkipReason::TooNew => write!(f, "not modified before --older-than"),
+            SkipReason::Settling => write!(f, "modified within the --settle window"),
+            SkipReason::PartialDownload => {
+                write!(f, "download in progress (use --include-partial to move it)")
+            }
+            SkipReason::ConflictDir => write!(f, "holds conflicting files (--conflict-dir)"),
+            SkipReason::RouteDir => write!(f, "holds placed files (--route, --dest-subdir)"),
+            SkipReason::Unchanged => write!(f, "unchanged since the last run (--incremental)"),
+        }
+    }
+}
 
-        let level4 = level3.join("level4");
-        fs::create_dir(&level4)?;
-        fs::write(level4.join("file4.txt"), "depth 4")?;
+/// Something the walker found while traversing the tree
+enum WalkEvent<'a> {
+    /// A file in a subdirectory that should be flattened
+    File {
+        entry: fs::DirEntry,
+        top_level_dir: Option<&'a OsStr>,
+    },
+    /// A directory that was not descended into, or a file that was filtered out
+    Skipped { path: PathBuf, reason: SkipReason },
+    /// A directory below the root has been walked, with the subdirectories walked in
+    /// it (only with --incremental)
+    Walked {
+        path: PathBuf,
+        subdirs: Vec<OsString>,
+    },
+}
+
+/// Traverses the tree below a root, applying the depth limit and filters shared
+/// by the summary and flatten passes
+struct Walker<'a> {
+    root: &'a Path,
+    root_device: Option<u64>,
+    options: &'a FlattenOptions,
+    /// State from the last --incremental run
+    previous: Option<incremental::State>,
+    /// Resolved paths of the directories walked, with --follow-junctions
+    walked: RefCell<HashSet<PathBuf>>,
+    /// Directories below the root descended into so far, for --max-dirs
+    dirs: Cell<usize>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(root: &'a Path, options: &'a FlattenOptions) -> io::Result<Self> {
+        let previous = if options.incremental {
+            incremental::State::load(root, &options.selection_fingerprint())?
+        } else {
+            None
+        };
+
+        Ok(Walker {
+            root,
+            root_device: device_id(&fs::metadata(root)?),
+            options,
+            previous,
+            walked: RefCell::new(HashSet::new()),
+            dirs: Cell::new(0),
+        })
+    }
+
+    fn walk(&self, visit: &mut dyn FnMut(WalkEvent) -> io::Result<()>) -> io::Result<()> {
+        self.walk_dir(self.root, 0, None, visit)
+    }
+
+    /// Check a file against the file-level filters, returning why it was filtered out
+    fn file_skip_reason(&self, entry: &fs::DirEntry) -> io::Result<Option<SkipReason>> {
+        let options = self.options;
+
+        if !options.include_partial && filetype::is_partial_download(&entry.file_name()) {
+            return Ok(Some(SkipReason::PartialDownload));
+        }
+
+        if !options.path_globs.is_empty() {
+            let path = entry.path();
+            let relative_path = path.strip_prefix(self.root).unwrap_or(&path);
+            if !globs::matches_path_globs(&options.path_globs, relative_path) {
+                return Ok(Some(SkipReason::PathGlob));
+            }
+        }
+
+        if let Some(only_dirs) = &options.only_dirs {
+            let path = entry.path();
+            let relative_path = path.strip_prefix(self.root).unwrap_or(&path);
+            if only_dirs.outermost_match(relative_path).is_none() {
+                return Ok(Some(SkipReason::NotInOnlyDirs));
+            }
+        }
+
+        if let Some(types) = &options.types {
+            match filetype::classify(&entry.path(), options.sniff) {
+                Some(class) if types.contains(&class) => {}
+                _ => return Ok(Some(SkipReason::FileType)),
+            }
+        }
+
+        // A file that can't be read is left out, rather than ending the walk
+        if let Some(kind) = options.only {
+            match filetype::read_header(&entry.path()) {
+                Ok(header) if ContentKind::of(&header) == kind => {}
+                Ok(_) => return Ok(Some(SkipReason::ContentKind)),
+                Err(_) => return Ok(Some(SkipReason::Unreadable)),
+            }
+        }
+
+        // Everything below needs the file's metadata
+        let time_filtered = options.newer_than.is_some()
+            || options.older_than.is_some()
+            || options.settle.is_some();
+        if !time_filtered && options.owned_by.is_none() && !options.writable_only {
+            return Ok(None);
+        }
+        let metadata = entry.metadata()?;
+
+        if let Some(uid) = options.owned_by
+            && permissions::owner(&metadata) != Some(uid)
+        {
+            return Ok(Some(SkipReason::Owner));
+        }
+
+        // Leave out files the move would fail on, so the count is accurate up front
+        if options.writable_only && !permissions::can_move(&entry.path(), &metadata) {
+            return Ok(Some(SkipReason::NotWritable));
+        }
+
+        if !time_filtered {
+            return Ok(None);
+        }
+
+        let modified = metadata.modified()?;
+        if options.newer_than.is_some_and(|t| modified <= t) {
+            return Ok(Some(SkipReason::TooOld));
+        }
+        if options.older_than.is_some_and(|t| modified >= t) {
+            return Ok(Some(SkipReason::TooNew));
+        }
+
+        // Files modified very recently may still be downloading or being written.
+        // Checked against the current time so the flatten pass re-evaluates it.
+        if let Some(settle) = options.settle {
+            let age = SystemTime::now().duration_since(modified);
+            if age.is_err() || age.is_ok_and(|age| age < settle) {
+                return Ok(Some(SkipReason::Settling));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Walk `current` and everything below it.
+    ///
+    /// Each directory is opened once by path; the entries in it are stat'ed relative
+    /// to the open directory (std uses `fstatat` on Unix), so paths aren't resolved
+    /// from the root per entry. The walk hands out `fs::DirEntry`s and the moves that
+    /// follow work on paths, so switching to descriptor-relative (`openat`) traversal
+    /// alone wouldn't make a run safe against directories renamed during it.
+    fn walk_dir(
+        &self,
+        current: &Path,
+        current_depth: usize,
+        top_level_dir: Option<&OsStr>,
+        visit: &mut dyn FnMut(WalkEvent) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // Followed links can lead back to a directory already walked, or around a loop
+        if self.options.follow_junctions
+            && !self.walked.borrow_mut().insert(fs::canonicalize(current)?)
+        {
+            visit(WalkEvent::Skipped {
+                path: current.to_path_buf(),
+                reason: SkipReason::AlreadyWalked,
+            })?;
+            return Ok(());
+        }
+
+        if current != self.root
+            && let Some(max_dirs) = self.options.max_dirs
+        {
+            if self.dirs.get() >= max_dirs {
+                visit(WalkEvent::Skipped {
+                    path: current.to_path_buf(),
+                    reason: SkipReason::MaxDirs,
+                })?;
+                return Ok(());
+            }
+            self.dirs.set(self.dirs.get() + 1);
+        }
+
+        // A directory whose entries haven't changed only needs its subdirectories walked
+        if current != self.root
+            && let Some(previous) = &self.previous
+            && let Some(subdirs) = previous.unchanged_subdirs(self.root, current)?
+        {
+            visit(WalkEvent::Skipped {
+                path: current.to_path_buf(),
+                reason: SkipReason::Unchanged,
+            })?;
+            for name in &subdirs {
+                let path = current.join(name);
+                if path.is_dir() {
+                    self.walk_dir(&path, current_depth + 1, top_level_dir, visit)?;
+                }
+            }
+            visit(WalkEvent::Walked {
+                path: current.to_path_buf(),
+                subdirs,
+            })?;
+            return Ok(());
+        }
+
+        let mut entries = fs::read_dir(current)?.collect::<io::Result<Vec<_>>>()?;
+        // read_dir order differs between runs and platforms, and decides which file
+        // keeps its name on a conflict
+        if !self.options.unsorted {
+            entries.sort_by_key(|entry| entry.file_name());
+        }
+
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            // Links to directories are walked like directories with --follow-junctions
+            let followed_link =
+                self.options.follow_junctions && file_type.is_symlink() && path.is_dir();
+
+            if file_type.is_dir() || followed_link {
+                // Determine the top-level directory name
+                let new_top_level_dir = if current == self.root {
+                    // We're at the root, so this subdirectory is a top-level directory
+                    if let Some(dir_name) = path.file_name() {
+                        // Never flatten files set aside by --conflict-dir
+                        if self.options.conflict_dir.as_ref().is_some_and(|dir| {
+                            dir.components().next() == Some(Component::Normal(dir_name))
+                        }) {
+                            visit(WalkEvent::Skipped {
+                                path,
+                                reason: SkipReason::ConflictDir,
+                            })?;
+                            continue;
+                        }
+                        if self.options.is_placement_dir(dir_name) {
+                            visit(WalkEvent::Skipped {
+                                path,
+                                reason: SkipReason::RouteDir,
+                            })?;
+                            continue;
+                        }
+
+                        // Check if we should include this top-level directory
+                        if let Some(reason) = top_level_skip_reason(
+                            &dir_name.to_string_lossy(),
+                            &self.options.include,
+                            &self.options.exclude,
+                            self.options.matching,
+                        ) {
+                            visit(WalkEvent::Skipped { path, reason })?;
+                            continue; // Skip this entire subtree
+                        }
+                        if let Some(max) = self.options.max_dir_files
+                            && count_files(&path)? > max
+                        {
+                            visit(WalkEvent::Skipped {
+                                path,
+                                reason: SkipReason::TooManyFiles,
+                            })?;
+                            continue;
+                        }
+                        Some(dir_name.to_os_string())
+                    } else {
+                        continue;
+                    }
+                } else {
+                    // We're in a subdirectory, inherit the top-level directory
+                    top_level_dir.map(OsStr::to_os_string)
+                };
+
+                // Prune directories excluded at any depth
+                if self
+                    .options
+                    .excludes_dir(&entry.file_name().to_string_lossy())
+                {
+                    visit(WalkEvent::Skipped {
+                        path,
+                        reason: SkipReason::ExcludedDir,
+                    })?;
+                    continue;
+                }
+
+                // Safari downloads into a .download directory
+                if !self.options.include_partial
+                    && filetype::is_partial_download(&entry.file_name())
+                {
+                    visit(WalkEvent::Skipped {
+                        path,
+                        reason: SkipReason::PartialDownload,
+                    })?;
+                    continue;
+                }
+
+                if self
+                    .options
+                    .max_depth
+                    .is_some_and(|max| current_depth + 1 > max)
+                {
+                    visit(WalkEvent::Skipped {
+                        path,
+                        reason: SkipReason::MaxDepth,
+                    })?;
+                    continue;
+                }
+
+                // Don't cross into other filesystems (bind mounts, mounted drives)
+                let metadata = if followed_link {
+                    fs::metadata(&path)?
+                } else {
+                    entry.metadata()?
+                };
+                if self.options.one_file_system && device_id(&metadata) != self.root_device {
+                    visit(WalkEvent::Skipped {
+                        path,
+                        reason: SkipReason::OtherDevice,
+                    })?;
+                    continue;
+                }
+
+                // Recursively traverse subdirectories
+                if self.options.incremental {
+                    subdirs.push(entry.file_name());
+                }
+                self.walk_dir(
+                    &path,
+                    current_depth + 1,
+                    new_top_level_dir.as_deref(),
+                    visit,
+                )?;
+            } else if path.parent() == Some(self.root) {
+                // Files already in the root are not flattened
+                continue;
+            } else if file_type.is_file()
+                || (!file_type.is_symlink() && self.options.special_files == SpecialFiles::Move)
+            {
+                match self.file_skip_reason(&entry)? {
+                    None => visit(WalkEvent::File {
+                        entry,
+                        top_level_dir,
+                    })?,
+                    Some(reason) => visit(WalkEvent::Skipped { path, reason })?,
+                }
+            } else if file_type.is_symlink() {
+                visit(WalkEvent::Skipped {
+                    path,
+                    reason: SkipReason::Symlink,
+                })?;
+            } else {
+                visit(WalkEvent::Skipped {
+                    path,
+                    reason: SkipReason::SpecialFile,
+                })?;
+            }
+        }
+
+        if self.options.incremental && current != self.root {
+            visit(WalkEvent::Walked {
+                path: current.to_path_buf(),
+                subdirs,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collect summary of files
+fn collect_file_summary(dir: &Path, options: &FlattenOptions) -> io::Result<FileSummary> {
+    let mut summary = FileSummary::default();
+    let mut content = match options.dedupe {
+        Some(_) => Some(ContentIndex::with_files_in(dir)?),
+        None => None,
+    };
 
+    let mut unsettled = HashSet::new();
+    let walker = Walker::new(dir, options)?;
+    walker.walk(&mut |event| {
+        match event {
+            WalkEvent::File {
+                entry,
+                top_level_dir,
+            } => {
+                summary.file_count += 1;
+                if let Some(formats) = &options.extract
+                    && ArchiveFormat::detect(&entry.path(), formats).is_some()
+                {
+                    summary.archives += 1;
+                }
+
+                // Track the top-level directory
+                if let Some(dir) = top_level_dir {
+                    summary.top_level_dirs.insert(dir.to_os_string());
+                }
+
+                // Duplicates are removed or left in place, never moved
+                let metadata = entry.metadata()?;
+                summary
+                    .snapshot
+                    .insert(entry.path(), FileStamp::of(&metadata));
+                if options.prune_empty_files && metadata.len() == 0 {
+                    summary.empty_files += 1;
+                    return Ok(());
+                }
+                if let Some(index) = &mut content
+                    && index.find_or_add(&entry.path(), metadata.len())?.is_some()
+                {
+                    summary.duplicate_files += 1;
+                    summary.duplicate_bytes += metadata.len();
+                    return Ok(());
+                }
+
+                // Files on another filesystem can't be renamed into the root
+                summary.total_bytes += metadata.len();
+                if device_id(&metadata) != walker.root_device {
+                    summary.cross_device_files += 1;
+                    summary.cross_device_bytes += metadata.len();
+                }
+            }
+            // Other devices, special files, unfinished downloads and unreadable files
+            // are always reported; everything else only with --explain
+            WalkEvent::Skipped { path, reason } => {
+                summary.dir_limit_reached |= reason == SkipReason::MaxDirs;
+                if !reason.is_stable()
+                    && let Some(parent) = path.parent()
+                {
+                    unsettled.insert(parent.to_path_buf());
+                }
+                if options.explain
+                    || matches!(
+                        reason,
+                        SkipReason::OtherDevice
+                            | SkipReason::SpecialFile
+                            | SkipReason::PartialDownload
+                            | SkipReason::Unreadable
+                    )
+                {
+                    summary.skipped.push((path, reason));
+                }
+            }
+            WalkEvent::Walked { path, subdirs } => summary.settled_dirs.push((path, subdirs)),
+        }
+        Ok(())
+    })?;
+    summary
+        .settled_dirs
+        .retain(|(dir, _)| !unsettled.contains(dir));
+
+    summary.git_working_copies = git_working_copies(dir, summary.top_level_dirs.iter());
+
+    Ok(summary)
+}
+
+/// Tell the user the walk stopped at --max-dirs, so files in the rest of the tree were
+/// left where they are
+fn warn_dir_limit(options: &FlattenOptions) {
+    eprintln!(
+        "Warning: Stopped descending after --max-dirs {} directories; files further in \
+         the tree are left in place",
+        options.max_dirs.unwrap_or_default()
+    );
+}
+
+/// Git working copies whose structure flattening `root` would destroy: one the root
+/// is inside of, and any of the top-level directories
+fn git_working_copies<'a>(
+    root: &Path,
+    top_level_dirs: impl Iterator<Item = &'a OsString>,
+) -> Vec<PathBuf> {
+    let mut working_copies = Vec::new();
+    if let Some(repo) = root.ancestors().find(|a| a.join(".git").exists()) {
+        working_copies.push(repo.to_path_buf());
+    }
+    let mut nested: Vec<PathBuf> = top_level_dirs
+        .map(|name| root.join(name))
+        .filter(|path| path.join(".git").exists())
+        .collect();
+    nested.sort();
+    working_copies.extend(nested);
+    working_copies
+}
+
+fn get_confirmation(prompt: Message) -> io::Result<bool> {
+    let language = Language::current();
+    let prompt = format!("{} {}: ", language.text(prompt), language.yes_no_hint());
+    match term::prompt(&prompt)? {
+        Some(input) => Ok(language.is_yes(&input)),
+        // Without a terminal the prompt would wait for an answer nobody sees
+        None => {
+            println!("{}", prompt.trim_end());
+            eprintln!(
+                "Error: No terminal to ask on, so nothing was confirmed. Use --yes to proceed."
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Ask for `name` to be typed before a large run, like `gh repo delete` does
+fn get_typed_confirmation(name: &str) -> io::Result<bool> {
+    let prompt = format!("{} ", Language::current().text(Message::TypeName));
+    match term::prompt(&prompt)? {
+        Some(input) => Ok(input.trim() == name),
+        None => {
+            println!("{}", prompt.trim_end());
+            eprintln!(
+                "Error: No terminal to ask on, so nothing was confirmed. Use --yes to proceed."
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Flatten directory
+fn flatten_directory_by_traversal(
+    root: &Path,
+    options: &FlattenOptions,
+) -> io::Result<FlattenReport> {
+    flatten_directory(root, options, None)
+}
+
+/// Flatten directory, moving only the files in `snapshot` that are unchanged since it
+/// was taken. Files that changed, appeared or disappeared in the meantime are reported.
+fn flatten_from_snapshot(
+    root: &Path,
+    options: &FlattenOptions,
+    snapshot: &Snapshot,
+) -> io::Result<FlattenReport> {
+    flatten_directory(root, options, Some(snapshot))
+}
+
+fn flatten_directory(
+    root: &Path,
+    options: &FlattenOptions,
+    snapshot: Option<&Snapshot>,
+) -> io::Result<FlattenReport> {
+    let mut mover = Mover::new(root, options)?;
+    let mut deferred = Vec::new();
+    // For --incremental: directories walked, and those with files left to retry
+    let mut walked = Vec::new();
+    let mut unsettled = HashSet::new();
+    let mut unsettle = |path: &Path| {
+        if let Some(parent) = path.parent() {
+            unsettled.insert(parent.to_path_buf());
+        }
+    };
+
+    let mut handled = 0;
+    let mut process = |path: PathBuf, unsettle: &mut dyn FnMut(&Path)| -> io::Result<()> {
+        if let Some(progress) = &options.progress
+            && progress.cancel.load(Ordering::Relaxed)
+        {
+            mover.report.cancelled = true;
+        }
+        if options.sample.is_some_and(|n| handled == n.get()) {
+            mover.report.cancelled = true;
+            mover.report.sampled = true;
+        }
+        if mover.report.cancelled {
+            return Ok(());
+        }
+        handled += 1;
+
+        if let Some(format) = options
+            .extract
+            .as_deref()
+            .and_then(|formats| ArchiveFormat::detect(&path, formats))
+        {
+            match extract_archive(&mut mover, &path, format) {
+                Ok(()) if options.delete_archives => {
+                    match fs::remove_file(&path) {
+                        Ok(()) => {
+                            if !options.quiet {
+                                println!("Deleted archive: {}", display_path(&path));
+                            }
+                            if options.porcelain {
+                                porcelain::print(porcelain::Status::Deleted, &path, None);
+                            }
+                        }
+                        Err(e) => {
+                            mover.report.fail(&path, "delete", &e);
+                            if options.porcelain {
+                                porcelain::print(porcelain::Status::Error, &path, None);
+                            }
+                            if let Some(progress) = &options.progress {
+                                progress.errored(&path, &e);
+                            }
+                            unsettle(&path);
+                        }
+                    }
+                    return Ok(());
+                }
+                Ok(()) => {}
+                // The archive is still moved like any other file
+                Err(e) => {
+                    mover.report.fail(&path, "extract", &e);
+                }
+            }
+        }
+
+        // Empty placeholders left by extractions and failed downloads aren't worth a
+        // place in the root
+        if options.prune_empty_files
+            && fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.len() == 0)
+        {
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    mover.report.pruned_empty += 1;
+                    if !options.quiet {
+                        println!("Deleted empty file: {}", display_path(&path));
+                    }
+                    if options.porcelain {
+                        porcelain::print(porcelain::Status::Deleted, &path, None);
+                    }
+                }
+                Err(e) => {
+                    mover.report.fail(&path, "delete", &e);
+                    if options.porcelain {
+                        porcelain::print(porcelain::Status::Error, &path, None);
+                    }
+                    if let Some(progress) = &options.progress {
+                        progress.errored(&path, &e);
+                    }
+                    unsettle(&path);
+                }
+            }
+            return Ok(());
+        }
+
+        match mover.remove_duplicate(&path) {
+            Ok(None) => {}
+            Ok(Some(duplicate)) => {
+                if !options.quiet {
+                    let action = match (&duplicate.link, options.dedupe) {
+                        (Some(link), _) => format!("Linked {} ->", display_path(link)),
+                        (None, Some(DedupeAction::Delete)) => "Removed".to_string(),
+                        (None, _) => "Skipped".to_string(),
+                    };
+                    println!(
+                        "{} duplicate: {} (same as {})",
+                        action,
+                        display_path(&path),
+                        display_path(&duplicate.original)
+                    );
+                }
+                if options.porcelain {
+                    match (&duplicate.link, options.dedupe) {
+                        (Some(link), _) => {
+                            porcelain::print(porcelain::Status::Linked, &path, Some(link))
+                        }
+                        (None, Some(DedupeAction::Delete)) => porcelain::print(
+                            porcelain::Status::Removed,
+                            &path,
+                            Some(&duplicate.original),
+                        ),
+                        (None, _) => porcelain::print(
+                            porcelain::Status::Skipped,
+                            &path,
+                            Some(&duplicate.original),
+                        ),
+                    }
+                }
+                if options.dedupe == Some(DedupeAction::Skip) {
+                    unsettle(&path);
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                mover.report.fail(&path, "check", &e);
+                if options.porcelain {
+                    porcelain::print(porcelain::Status::Error, &path, None);
+                }
+                if let Some(progress) = &options.progress {
+                    progress.errored(&path, &e);
+                }
+                unsettle(&path);
+                return Ok(());
+            }
+        }
+
+        match mover.move_file(&path) {
+            Ok(dest) => {
+                if !options.quiet {
+                    println!("Moved: {} -> {}", display_path(&path), display_path(&dest));
+                }
+                if options.porcelain {
+                    porcelain::print(porcelain::Status::Moved, &path, Some(&dest));
+                }
+                if let Some(progress) = &options.progress {
+                    progress.moved(&path, &dest, moved_len(&dest));
+                }
+
+                // Pause periodically on large runs so they can be stopped partway
+                let moved = mover.report.moved_count;
+                if options.confirm_every.is_some_and(|n| moved % n.get() == 0) {
+                    println!("\nMoved {} file(s) so far.", moved);
+                    mover.report.cancelled = !get_confirmation(Message::Continue)?;
+                }
+            }
+            Err(e) if is_declined(&e) => {
+                if !options.quiet {
+                    println!("{}: {}", e, display_path(&path));
+                }
+                if options.porcelain {
+                    porcelain::print(declined_status(&e), &path, None);
+                }
+                if declined_status(&e) == porcelain::Status::Skipped {
+                    mover.report.skipped += 1;
+                }
+                unsettle(&path);
+            }
+            // Locked files are retried once the rest of the tree is done
+            Err(e) if is_locked_error(&e) => {
+                unsettle(&path);
+                deferred.push(path);
+            }
+            Err(e) => {
+                mover.report.fail(&path, "move", &e);
+                if options.porcelain {
+                    porcelain::print(porcelain::Status::Error, &path, None);
+                }
+                if let Some(progress) = &options.progress {
+                    progress.errored(&path, &e);
+                }
+                unsettle(&path);
+            }
+        }
         Ok(())
+    };
+
+    let walker = Walker::new(root, options)?;
+    let mut files = Vec::new();
+    let (mut seen, mut changed, mut appeared) = (HashSet::new(), Vec::new(), Vec::new());
+    let mut dir_limit_reached = false;
+    walker.walk(&mut |event| {
+        match event {
+            // Only files as they were when the run was confirmed are moved
+            WalkEvent::File { entry, .. } if snapshot.is_some() => {
+                let path = entry.path();
+                let current = entry.metadata().ok().map(|m| FileStamp::of(&m));
+                match snapshot.and_then(|s| s.get(&path)) {
+                    None => {
+                        unsettle(&path);
+                        appeared.push(path);
+                    }
+                    Some(stamp) if current.as_ref() != Some(stamp) => {
+                        unsettle(&path);
+                        seen.insert(path.clone());
+                        changed.push(path);
+                    }
+                    Some(_) if options.order != Order::DepthFirst => {
+                        seen.insert(path);
+                        files.push(entry);
+                    }
+                    Some(_) => {
+                        seen.insert(path.clone());
+                        process(path, &mut unsettle)?;
+                    }
+                }
+            }
+            // Other orders need the whole list before the first move
+            WalkEvent::File { entry, .. } if options.order != Order::DepthFirst => {
+                files.push(entry)
+            }
+            WalkEvent::File { entry, .. } => process(entry.path(), &mut unsettle)?,
+            WalkEvent::Skipped { path, reason } if !reason.is_stable() => unsettle(&path),
+            WalkEvent::Skipped { reason, .. } => {
+                dir_limit_reached |= reason == SkipReason::MaxDirs;
+            }
+            WalkEvent::Walked { path, subdirs } => walked.push((path, subdirs)),
+        }
+        Ok(())
+    })?;
+    for path in sort_files(files, options.order)? {
+        process(path, &mut unsettle)?;
+    }
+    mover.report.dir_limit_reached = dir_limit_reached;
+
+    // Retry files that were locked during the first pass
+    if mover.report.cancelled {
+        mover.report.locked.append(&mut deferred);
+    } else if !deferred.is_empty() {
+        std::thread::sleep(LOCKED_RETRY_DELAY);
+
+        for path in deferred {
+            match mover.move_file(&path) {
+                Ok(dest) => {
+                    if !options.quiet {
+                        println!("Moved: {} -> {}", display_path(&path), display_path(&dest));
+                    }
+                    if options.porcelain {
+                        porcelain::print(porcelain::Status::Moved, &path, Some(&dest));
+                    }
+                    if let Some(progress) = &options.progress {
+                        progress.moved(&path, &dest, moved_len(&dest));
+                    }
+                }
+                Err(e) if is_locked_error(&e) => mover.report.locked.push(path),
+                Err(e) if is_declined(&e) => {
+                    if !options.quiet {
+                        println!("{}: {}", e, display_path(&path));
+                    }
+                    if options.porcelain {
+                        porcelain::print(declined_status(&e), &path, None);
+                    }
+                    if declined_status(&e) == porcelain::Status::Skipped {
+                        mover.report.skipped += 1;
+                    }
+                }
+                Err(e) => {
+                    mover.report.fail(&path, "move", &e);
+                    if options.porcelain {
+                        porcelain::print(porcelain::Status::Error, &path, None);
+                    }
+                    if let Some(progress) = &options.progress {
+                        progress.errored(&path, &e);
+                    }
+                }
+            }
+        }
+    }
+
+    for path in &mover.report.locked {
+        if options.porcelain {
+            porcelain::print(porcelain::Status::Locked, path, None);
+        }
+        if let Some(progress) = &options.progress {
+            progress.errored(path, &"locked by another process");
+        }
+    }
+
+    if let (Some(path), Some(checksums)) = (&options.checksums, &mover.checksums) {
+        checksums.write(path)?;
     }
+    if let (Some(path), Some(manifest)) = (&options.manifest, &mover.manifest) {
+        manifest.write(path)?;
+    }
+
+    if let Some(snapshot) = snapshot {
+        let mut vanished: Vec<PathBuf> = snapshot
+            .keys()
+            .filter(|path| !seen.contains(*path))
+            .cloned()
+            .collect();
+        vanished.sort();
+        mover.report.changed_since_scan = changed;
+        mover.report.appeared_since_scan = appeared;
+        mover.report.vanished_since_scan = vanished;
+    }
+
+    // A stopped run leaves files unprocessed in directories it has walked
+    if !mover.report.cancelled {
+        walked.retain(|(dir, _)| !unsettled.contains(dir));
+        mover.report.settled_dirs = walked;
+    }
+
+    Ok(mover.report)
+}
+
+/// Unpack `archive` into a scratch directory in the root and move its files into the
+/// root. The scratch directory is on the root's filesystem, so the moves are renames.
+fn extract_archive(mover: &mut Mover, archive: &Path, format: ArchiveFormat) -> io::Result<()> {
+    let scratch = mover
+        .root
+        .join(format!(".rflatten-extract-{}", std::process::id()));
+    fs::create_dir(&scratch)?;
+
+    let result = archive::extract(archive, format, &scratch).and_then(|()| {
+        let archive_origin = archive.strip_prefix(mover.root).unwrap_or(archive);
+        for file in archive::files_in(&scratch)? {
+            let inner = file.strip_prefix(&scratch).unwrap_or(&file);
+            let origin = archive_origin.join(inner);
+            match mover.move_from(&file, &origin) {
+                Ok(dest) => {
+                    if !mover.options.quiet {
+                        println!(
+                            "Extracted: {} -> {}",
+                            display_path(&mover.root.join(&origin)),
+                            display_path(&dest)
+                        );
+                    }
+                    if mover.options.porcelain {
+                        let source = mover.root.join(&origin);
+                        porcelain::print(porcelain::Status::Moved, &source, Some(&dest));
+                    }
+                }
+                Err(e) => {
+                    let source = mover.root.join(&origin);
+                    mover.report.fail(&source, "move", &e);
+                    if mover.options.porcelain {
+                        porcelain::print(porcelain::Status::Error, &source, None);
+                    }
+                }
+            }
+        }
+        Ok(())
+    });
+
+    let _ = fs::remove_dir_all(&scratch);
+    if result.is_ok() {
+        mover.report.extracted += 1;
+    }
+    result
+}
+
+/// Write `files` into a new flat archive instead of moving them into the root, then
+/// remove them. Conflicting names are resolved with the conflict suffix. Files are
+/// staged as hard links (or copies) in a scratch directory in the root, so nothing
+/// is removed unless the archive was written. Returns the number of files archived.
+fn archive_files(
+    root: &Path,
+    files: &[PathBuf],
+    archive_path: &Path,
+    format: ArchiveFormat,
+    options: &FlattenOptions,
+) -> io::Result<usize> {
+    let archive_path = std::path::absolute(archive_path)?;
+    let scratch = root.join(format!(".rflatten-archive-{}", std::process::id()));
+    fs::create_dir(&scratch)?;
+
+    let mut staged = Vec::new();
+    let mut names = Vec::new();
+    let result = files
+        .iter()
+        .try_for_each(|path| {
+            let file_name = path.file_name().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "path has no file name")
+            })?;
+            let mut name = file_name.to_os_string();
+            let mut counter = options.conflict_suffix.start();
+            let mut values = None;
+            loop {
+                let dest = scratch.join(&name);
+                let result = match fs::hard_link(path, &dest) {
+                    Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                        fs::copy(path, &dest).map(|_| ())
+                    }
+                    result => result,
+                };
+                match result {
+                    Ok(()) => break,
+                    // Names differing only in case collide on case-insensitive roots too
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        let values = match values {
+                            Some(ref values) => values,
+                            None => values.insert(options.conflict_suffix.values(path)?),
+                        };
+                        let suffix = options.conflict_suffix.render(counter, values);
+                        let candidate = conflict_name(file_name, &suffix);
+                        name = fit_name_length(&candidate, MAX_NAME_LEN).unwrap_or(candidate);
+                        counter += 1;
+                    }
+                    Err(e) => {
+                        return Err(io::Error::new(
+                            e.kind(),
+                            format!("could not stage {}: {}", display_path(path), e),
+                        ));
+                    }
+                }
+            }
+            staged.push(path);
+            names.push(name);
+            Ok(())
+        })
+        .and_then(|()| archive::create(&archive_path, format, &scratch, &names));
+    let _ = fs::remove_dir_all(&scratch);
+    result?;
+
+    for (path, name) in staged.iter().zip(&names) {
+        match fs::remove_file(path) {
+            Ok(()) => {
+                if !options.quiet {
+                    println!(
+                        "Archived: {} -> {}",
+                        display_path(path),
+                        name.to_string_lossy()
+                    );
+                }
+            }
+            Err(e) => eprintln!(
+                "Error removing {} (it was archived): {}",
+                display_path(path),
+                e
+            ),
+        }
+    }
+    Ok(staged.len())
+}
+
+/// Move every selected file in `sources`, including those directly inside them,
+/// into `dest`. Conflicts between sources are resolved like those within a tree.
+fn merge_directories(
+    sources: &[PathBuf],
+    dest: &Path,
+    options: &FlattenOptions,
+) -> io::Result<FlattenReport> {
+    let mut mover = Mover::new(dest, options)?;
+    for source in sources {
+        let walker = Walker::new(source, options)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && walker.file_skip_reason(&entry)?.is_none() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+        walker.walk(&mut |event| {
+            if let WalkEvent::File { entry, .. } = event {
+                files.push(entry.path());
+            }
+            Ok(())
+        })?;
+
+        // Origins start with the source's name, so merged files can be told apart
+        let name = source.file_name().map(Path::new).unwrap_or(source);
+        for path in files {
+            match mover.remove_duplicate(&path) {
+                Ok(None) => {}
+                Ok(Some(duplicate)) => {
+                    if !options.quiet {
+                        println!(
+                            "Duplicate: {} (same as {})",
+                            display_path(&path),
+                            display_path(&duplicate.original)
+                        );
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    mover.report.fail(&path, "check", &e);
+                    continue;
+                }
+            }
+
+            let origin = name.join(path.strip_prefix(source).unwrap_or(&path));
+            match mover.move_from(&path, &origin) {
+                Ok(moved) => {
+                    if !options.quiet {
+                        println!("Moved: {} -> {}", display_path(&path), display_path(&moved));
+                    }
+                }
+                Err(e) if is_declined(&e) => {
+                    if !options.quiet {
+                        println!("{}: {}", e, display_path(&path));
+                    }
+                }
+                Err(e) => mover.report.fail(&path, "move", &e),
+            }
+        }
+
+        // Clean up the directories the merge emptied, but keep the source itself
+        let source_device = device_id(&fs::metadata(source)?);
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir()
+                && let Err(e) = remove_empty_dirs(&entry.path(), source_device)
+            {
+                eprintln!(
+                    "Error removing directory {}: {}",
+                    display_path(&entry.path()),
+                    e
+                );
+            }
+        }
+    }
+    Ok(mover.report)
+}
+
+/// Sort the files found by the walker into `order`, returning their paths. Files
+/// that compare equal stay in walk order.
+fn sort_files(files: Vec<fs::DirEntry>, order: Order) -> io::Result<Vec<PathBuf>> {
+    let mut keyed = files
+        .into_iter()
+        .map(|entry| {
+            let metadata = match order {
+                Order::Mtime | Order::SizeDesc => Some(entry.metadata()?),
+                Order::DepthFirst | Order::Name => None,
+            };
+            Ok((entry, metadata))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    match order {
+        Order::DepthFirst => {}
+        Order::Name => keyed.sort_by_key(|(entry, _)| entry.file_name()),
+        Order::Mtime => keyed.sort_by_key(|(_, metadata)| {
+            std::cmp::Reverse(metadata.as_ref().and_then(|m| m.modified().ok()))
+        }),
+        Order::SizeDesc => {
+            keyed.sort_by_key(|(_, metadata)| std::cmp::Reverse(metadata.as_ref().map(|m| m.len())))
+        }
+    }
+
+    Ok(keyed.into_iter().map(|(entry, _)| entry.path()).collect())
+}
+
+/// Remove `dir` and every directory below it that is left empty after flattening.
+/// Directories that still contain anything (files beyond the depth limit, files
+/// that failed to move, other filesystems) are kept. Returns whether `dir` was removed.
+fn remove_empty_dirs(dir: &Path, root_device: Option<u64>) -> io::Result<bool> {
+    let mut is_empty = true;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        // Never descend into another filesystem
+        if !metadata.is_dir()
+            || device_id(&metadata) != root_device
+            || !remove_empty_dirs(&entry.path(), root_device)?
+        {
+            is_empty = false;
+        }
+    }
+
+    if is_empty {
+        fs::remove_dir(dir)?;
+    }
+
+    Ok(is_empty)
+}
+
+/// Moves files into the root, keeping track of the names already taken there
+struct Mover<'a> {
+    root: &'a Path,
+    options: &'a FlattenOptions,
+    names: RootNames,
+    /// Contents already placed in the root, with --dedupe
+    content: Option<ContentIndex>,
+    /// Hashes of the files placed in the root, with --checksums
+    checksums: Option<Checksums>,
+    /// Moves made so far, with --manifest
+    manifest: Option<Manifest>,
+    /// An --on-conflict ask answer given for all remaining conflicts
+    conflict_choice: Option<ConflictChoice>,
+    /// The highest conflict counter used for each name, by destination directory
+    counters: HashMap<PathBuf, HashMap<OsString, usize>>,
+    report: FlattenReport,
+}
+
+impl<'a> Mover<'a> {
+    fn new(root: &'a Path, options: &'a FlattenOptions) -> io::Result<Self> {
+        // Names differing only in case collide on case-insensitive destinations
+        let fold_case = options.case_insensitive
+            || naming::detect_case_insensitive(root).unwrap_or_else(|e| {
+                eprintln!(
+                    "Warning: Could not detect case sensitivity of '{}': {}",
+                    display_path(root),
+                    e
+                );
+                false
+            });
+
+        // Create the checksum file up front, so a flattened file can't take its name
+        let checksums = match &options.checksums {
+            Some(path) => {
+                fs::File::create(path)?;
+                Some(Checksums::default())
+            }
+            None => None,
+        };
+
+        let manifest = match &options.manifest {
+            Some(path) => {
+                fs::File::create(path)?;
+                Some(Manifest::default())
+            }
+            None => None,
+        };
+
+        Ok(Mover {
+            root,
+            options,
+            names: RootNames::load(root, fold_case)?,
+            content: match options.dedupe {
+                Some(_) => Some(ContentIndex::with_files_in(root)?),
+                None => None,
+            },
+            checksums,
+            manifest,
+            conflict_choice: None,
+            counters: HashMap::new(),
+            report: FlattenReport::default(),
+        })
+    }
+
+    /// The highest conflict counter among the names in `dir` made from `base_name`, if
+    /// any. The directory is read the first time it's asked about, and the moves that
+    /// follow keep the numbers up to date.
+    fn highest_counter(&mut self, dir: &Path, base_name: &OsStr) -> io::Result<Option<usize>> {
+        if !self.counters.contains_key(dir) {
+            let mut counters = HashMap::new();
+            for entry in fs::read_dir(extended_length_path(dir))? {
+                let name = entry?.file_name();
+                record_counter(&mut counters, &self.options.conflict_suffix, &name);
+            }
+            self.counters.insert(dir.to_path_buf(), counters);
+        }
+        Ok(self.counters[dir].get(base_name).copied())
+    }
+
+    /// With --dedupe, check whether `path` has the same contents as a file already in
+    /// the root. Duplicates are deleted, left in place or replaced by a hard link.
+    fn remove_duplicate(&mut self, path: &Path) -> io::Result<Option<Duplicate>> {
+        let Some(index) = &mut self.content else {
+            return Ok(None);
+        };
+
+        let size = fs::metadata(path)?.len();
+        let Some(original) = index.find_or_add(path, size)? else {
+            return Ok(None);
+        };
+
+        let mut link = None;
+        match self.options.dedupe {
+            Some(DedupeAction::Delete) => fs::remove_file(path)?,
+            Some(DedupeAction::Hardlink) => match self.place_file(path, Some(&original), None) {
+                Ok(dest) => link = Some(dest),
+                // Filesystems without hard links get a regular move
+                Err(_) => return Ok(None),
+            },
+            _ => {}
+        }
+
+        self.report.duplicates += 1;
+        self.report.duplicate_bytes += size;
+        Ok(Some(Duplicate { original, link }))
+    }
+
+    /// Check whether a destination is taken, by an existing entry or, directly in the
+    /// root, by a name that only differs in normalization (or case, on
+    /// case-insensitive destinations)
+    fn conflicts(&self, dest: &Path) -> bool {
+        extended_length_path(dest).exists()
+            || (dest.parent() == Some(self.root)
+                && dest
+                    .file_name()
+                    .is_some_and(|name| self.names.contains(name)))
+    }
+
+    /// Move the existing root file `existing` into `conflict_dir`, renaming it if the
+    /// name is taken there too, so a larger incoming file can take its place
+    fn quarantine_existing(&mut self, existing: &Path, conflict_dir: &Path) -> io::Result<()> {
+        let dir = self.root.join(conflict_dir);
+        fs::create_dir_all(extended_length_path(&dir))?;
+        let name = existing
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+        let values = self.options.conflict_suffix.values(existing)?;
+        let mut target = dir.join(name);
+        let mut counter = self.options.conflict_suffix.start();
+        loop {
+            let result = if extended_length_path(&target).exists() {
+                Err(io::ErrorKind::AlreadyExists.into())
+            } else {
+                rename_noreplace(
+                    &extended_length_path(existing),
+                    &extended_length_path(&target),
+                )
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let suffix = self.options.conflict_suffix.render(counter, &values);
+                    target = dir.join(conflict_name(name, &suffix));
+                    counter += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.report.quarantined += 1;
+        Ok(())
+    }
+
+    /// Move a single file into the root, renaming it on conflict. Returns the destination path.
+    fn move_file(&mut self, path: &Path) -> io::Result<PathBuf> {
+        self.place_file(path, None, None)
+    }
+
+    /// Move a file from outside the tree (unpacked from an archive, or from another
+    /// merge source) into the root like `move_file`. `origin` is the path recorded
+    /// as where it came from.
+    fn move_from(&mut self, path: &Path, origin: &Path) -> io::Result<PathBuf> {
+        self.place_file(path, None, Some(origin))
+    }
+
+    /// Place a file in the root like `move_file`, or with `link_to`, replace it with a
+    /// hard link to that identical file instead of moving a second copy. `origin` is
+    /// where the file was in the tree relative to the root, if that isn't `path`.
+    fn place_file(
+        &mut self,
+        path: &Path,
+        link_to: Option<&Path>,
+        origin: Option<&Path>,
+    ) -> io::Result<PathBuf> {
+        let outside = origin.is_some();
+        let origin = origin.unwrap_or_else(|| path.strip_prefix(self.root).unwrap_or(path));
+        let original_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let (file_name, sanitized) = destination_name(self.options, path, original_name)?;
+
+        let mut dest_dir = self.root.to_path_buf();
+        let mut dest = self.root.join(&file_name);
+
+        // Routed and dated files go to their directory under the root instead, and
+        // --only-dirs-to parent files to the directory their match is in
+        if let Some(dir) = self.options.only_dirs_parent(origin) {
+            dest_dir.push(dir);
+        }
+        if let Some(dir) = Route::dir_for(&self.options.routes, &file_name) {
+            dest_dir.push(dir);
+        }
+        if let Some(template) = &self.options.dest_subdir {
+            let date = match self.options.dest_subdir_date {
+                Some(date) => date,
+                None => fs::metadata(path)?.modified()?,
+            };
+            dest_dir.push(template.render(date));
+        }
+        if dest_dir != self.root {
+            fs::create_dir_all(extended_length_path(&dest_dir))?;
+            dest = dest_dir.join(&file_name);
+        }
+
+        // Deeply nested sources and long names can exceed MAX_PATH on Windows
+        let src = extended_length_path(path);
+
+        // With skip, a taken name leaves the file where it is; with dedupe, an
+        // identical copy of the existing file is removed
+        let policy = self.options.conflict_policy(&file_name);
+        if link_to.is_none() && self.conflicts(&dest) {
+            let existing = extended_length_path(&dest);
+            match policy {
+                ConflictPolicy::Skip => return Err(io::Error::other(Declined { removed: false })),
+                ConflictPolicy::Dedupe
+                    if existing.is_file() && dedupe::same_contents(&src, &existing)? =>
+                {
+                    fs::remove_file(&src)?;
+                    return Err(io::Error::other(Declined { removed: true }));
+                }
+                _ => {}
+            }
+        }
+
+        // With keep-larger, a larger incoming file takes the name from the existing one
+        let keep_larger = policy == ConflictPolicy::KeepLarger
+            && link_to.is_none()
+            && extended_length_path(&dest).is_file();
+        let (incoming_larger, same_size) = if keep_larger {
+            let incoming = fs::metadata(&src)?.len();
+            let existing = fs::metadata(extended_length_path(&dest))?.len();
+            (incoming > existing, incoming == existing)
+        } else {
+            (false, false)
+        };
+        if incoming_larger && let Some(conflict_dir) = &self.options.conflict_dir {
+            self.quarantine_existing(&dest, conflict_dir)?;
+        }
+
+        // Set conflicting files aside instead of renaming them into the root
+        let quarantined = self.options.conflict_dir.is_some()
+            && !incoming_larger
+            && dest.parent() == Some(self.root)
+            && self.conflicts(&dest);
+        if let Some(conflict_dir) = &self.options.conflict_dir
+            && quarantined
+        {
+            dest_dir.push(conflict_dir);
+            if self.options.nest_conflicts
+                && let Some(parent) = origin.parent()
+            {
+                dest_dir.push(parent);
+            }
+            fs::create_dir_all(extended_length_path(&dest_dir))?;
+            dest = dest_dir.join(&file_name);
+        }
+
+        // Of two files the same size, neither is smaller: the incoming one is only
+        // removed if it's a copy, and renamed otherwise
+        if keep_larger
+            && !incoming_larger
+            && !quarantined
+            && (!same_size || dedupe::same_contents(&src, &extended_length_path(&dest))?)
+        {
+            fs::remove_file(&src)?;
+            return Err(io::Error::other(Declined { removed: true }));
+        }
+
+        // A link must never replace the file it points to
+        let mut overwrite =
+            (policy == ConflictPolicy::Overwrite || incoming_larger) && link_to.is_none();
+        if policy == ConflictPolicy::Ask
+            && link_to.is_none()
+            && !quarantined
+            && extended_length_path(&dest).is_file()
+        {
+            let choice = match self.conflict_choice {
+                Some(choice) => choice,
+                None => {
+                    let (choice, all) = ask_conflict(path, &dest)?;
+                    if all {
+                        self.conflict_choice = Some(choice);
+                    }
+                    choice
+                }
+            };
+            match choice {
+                ConflictChoice::Overwrite => overwrite = true,
+                ConflictChoice::Rename => {}
+                ConflictChoice::KeepExisting => {
+                    fs::remove_file(&src)?;
+                    return Err(io::Error::other(Declined { removed: true }));
+                }
+                ConflictChoice::Skip => return Err(io::Error::other(Declined { removed: false })),
+            }
+        }
+
+        // With prefix-parent, a conflicting file is named after its parent directory
+        // first, and only gets a suffix if that name is taken too
+        let mut base_name = file_name.clone();
+        if policy == ConflictPolicy::PrefixParent
+            && link_to.is_none()
+            && !quarantined
+            && self.conflicts(&dest)
+            && let Some(parent) = origin.parent().and_then(Path::file_name)
+        {
+            let mut prefixed = parent.to_os_string();
+            prefixed.push("_");
+            prefixed.push(&file_name);
+            if self.options.sanitize
+                && let Some(safe_name) = sanitize_chars(&prefixed)
+            {
+                prefixed = safe_name;
+            }
+            base_name = fit_name_length(&prefixed, MAX_NAME_LEN).unwrap_or(prefixed);
+            dest = dest_dir.join(&base_name);
+        }
+
+        // Handle filename conflicts by appending a number. Names that only differ in
+        // Unicode normalization (or case, on case-insensitive destinations) count as
+        // conflicts too. Numbering continues after the highest counter in use, and
+        // an incoming copy from an earlier run (report_1.pdf, next to report.pdf) is
+        // numbered from its original name rather than becoming report_1_1.pdf.
+        let mut counter = self.options.conflict_suffix.start();
+        if !overwrite && !quarantined && !self.options.fill_counter_gaps && self.conflicts(&dest) {
+            if let Some((original, _)) = self.options.conflict_suffix.parse_name(&base_name)
+                && self.conflicts(&dest_dir.join(&original))
+            {
+                base_name = original;
+            }
+            let highest = self.highest_counter(&dest_dir, &base_name)?;
+            counter = self.options.conflict_suffix.next_counter(highest);
+        }
+        let mut values = None;
+        let mut next_candidate = || -> io::Result<PathBuf> {
+            let values = match values {
+                Some(ref values) => values,
+                None => values.insert(self.options.conflict_suffix.values(path)?),
+            };
+
+            // The counter can push a name that just fit over the length limit
+            let suffix = self.options.conflict_suffix.render(counter, values);
+            let candidate = conflict_name(&base_name, &suffix);
+            counter += 1;
+            Ok(dest_dir.join(fit_name_length(&candidate, MAX_NAME_LEN).unwrap_or(candidate)))
+        };
+        // Inside --conflict-dir only existing entries count
+        let taken = |dest: &Path| {
+            if quarantined {
+                extended_length_path(dest).exists()
+            } else {
+                self.conflicts(dest)
+            }
+        };
+        let fsync = self.options.fsync;
+
+        // The pre-move hook runs once per file, even when the name it was given is
+        // taken before the move and the file goes to the next free one
+        let mut hooked = false;
+        let (dest_path, replaced) = loop {
+            while !overwrite && taken(&dest) {
+                // If the destination exists but is a directory, don't try to rename
+                // Let fs::rename fail and handle the error below
+                if extended_length_path(&dest).is_dir() {
+                    break;
+                }
+                dest = next_candidate()?;
+            }
+
+            let dest_path = extended_length_path(&dest);
+
+            if let Some(command) = &self.options.pre_move_cmd
+                && !hooked
+            {
+                hooks::run(command, &[("src", path), ("dest", &dest)])?;
+                hooked = true;
+            }
+
+            // Move the file being replaced out of the way first, so nothing is lost
+            let replaced = overwrite && dest_path.is_file();
+            let backup_path = match &self.options.backup {
+                Some(backup) if replaced => {
+                    let backup_path = backup.backup_path(&dest_path);
+                    fs::rename(&dest_path, &backup_path)?;
+                    Some(backup_path)
+                }
+                _ => None,
+            };
+
+            let rename = || {
+                if overwrite {
+                    fs::rename(&src, &dest_path)
+                } else {
+                    rename_noreplace(&src, &dest_path)
+                }
+            };
+            let result = match link_to {
+                Some(original) => fs::hard_link(original, &dest_path)
+                    .and_then(|()| if fsync { sync_dir(&dest_dir) } else { Ok(()) })
+                    .and_then(|()| {
+                        fs::remove_file(&src).inspect_err(|_| {
+                            let _ = fs::remove_file(&dest_path);
+                        })
+                    }),
+                None => match rename() {
+                    Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                        copy_across_devices(&src, &dest_path, self.options.bwlimit, fsync)
+                    }
+                    Ok(()) if fsync => sync_dir(&dest_dir),
+                    result => result,
+                },
+            };
+            match result {
+                Ok(()) => break (dest_path, replaced),
+                // Another process took the name after it was checked
+                Err(e)
+                    if e.kind() == io::ErrorKind::AlreadyExists
+                        && !overwrite
+                        && !dest_path.is_dir() =>
+                {
+                    dest = next_candidate()?;
+                }
+                Err(e) => {
+                    // Put the original back if the new file couldn't take its place
+                    if let Some(backup_path) = backup_path {
+                        let _ = fs::rename(&backup_path, &dest_path);
+                    }
+                    return Err(e);
+                }
+            }
+        };
+
+        if let Some(counters) = self.counters.get_mut(&dest_dir)
+            && let Some(name) = dest.file_name()
+        {
+            record_counter(counters, &self.options.conflict_suffix, name);
+        }
+        if replaced {
+            self.report.overwritten += 1;
+        }
+        if dest.file_name() != Some(file_name.as_os_str()) {
+            self.report.renamed += 1;
+        }
+
+        // Later duplicates should point at the file's new location
+        if let Some(index) = &mut self.content {
+            index.relocate(fs::metadata(&dest_path)?.len(), path, &dest);
+        }
+
+        if quarantined {
+            self.report.quarantined += 1;
+        } else if dest.parent() == Some(self.root)
+            && let Some(name) = dest.file_name()
+        {
+            self.names.insert(name);
+        }
+
+        // Links and files from outside the tree can't be replayed
+        if let Some(manifest) = &mut self.manifest
+            && link_to.is_none()
+            && !outside
+        {
+            manifest.record(origin, dest.strip_prefix(self.root).unwrap_or(&dest));
+        }
+
+        // Remember where the file came from
+        if let Some(provenance) = self.options.provenance {
+            match provenance::record(&dest_path, origin, provenance) {
+                Ok(()) if provenance == Provenance::Sidecar && dest.parent() == Some(self.root) => {
+                    if let Some(name) = provenance::sidecar_path(&dest).file_name() {
+                        self.names.insert(name);
+                    }
+                }
+                Ok(()) => {}
+                Err(e) => eprintln!(
+                    "Warning: Could not record the origin of {}: {}",
+                    display_path(&dest),
+                    e
+                ),
+            }
+        }
+
+        if let Some(checksums) = &mut self.checksums {
+            match checksums::sha256_file(&dest_path) {
+                Ok(hash) => {
+                    let relative_path = dest.strip_prefix(self.root).unwrap_or(&dest);
+                    checksums.insert(relative_path.to_path_buf(), hash);
+                }
+                Err(e) => eprintln!("Warning: Could not checksum {}: {}", display_path(&dest), e),
+            }
+        }
+
+        if let Some(command) = &self.options.post_move_cmd
+            && let Err(e) = hooks::run(command, &[("src", path), ("dest", &dest)])
+        {
+            eprintln!(
+                "Warning: Post-move command for {}: {}",
+                display_path(&dest),
+                e
+            );
+        }
+
+        self.report.moved_count += 1;
+        self.report.moved_bytes += fs::symlink_metadata(&dest_path).map_or(0, |m| m.len());
+        if sanitized {
+            self.report
+                .sanitized
+                .push((self.root.join(origin), file_name.clone()));
+        }
+
+        Ok(dest)
+    }
+}
+
+/// Name a file should get in the root before conflicts are resolved.
+/// Returns the name and whether it had to be sanitized for the destination.
+fn destination_name(
+    options: &FlattenOptions,
+    path: &Path,
+    file_name: &OsStr,
+) -> io::Result<(OsString, bool)> {
+    let mut name = match &options.rename_by_date {
+        Some(template) => template.render(file_name, fs::metadata(path)?.modified()?),
+        None => file_name.to_os_string(),
+    };
+    if let Some(form) = options.normalize {
+        name = normalize_name(&name, form);
+    }
+    for &transform in &options.transform {
+        name = transform_name(&name, transform);
+    }
+    let mut sanitized = false;
+
+    if options.sanitize
+        && let Some(safe_name) = sanitize_chars(&name)
+    {
+        name = safe_name;
+        sanitized = true;
+    }
+
+    if let Some(suffix) = &options.reserved_suffix
+        && let Some(safe_name) = sanitize_reserved_name(&name, suffix)
+    {
+        name = safe_name;
+        sanitized = true;
+    }
+
+    if let Some(short_name) = fit_name_length(&name, MAX_NAME_LEN) {
+        name = short_name;
+        sanitized = true;
+    }
+
+    Ok((name, sanitized))
+}
+
+/// A file found to have the same contents as one already in the root
+struct Duplicate {
+    original: PathBuf,
+    /// Where the hard link replacing it was created (--dedupe=hardlink)
+    link: Option<PathBuf>,
+}
+
+/// Keep track of `name`'s conflict counter in `counters`, the highest counter for
+/// each name the suffix format was added to
+fn record_counter(counters: &mut HashMap<OsString, usize>, suffix: &ConflictSuffix, name: &OsStr) {
+    if let Some((base, n)) = suffix.parse_name(name) {
+        let highest = counters.entry(base).or_default();
+        *highest = (*highest).max(n);
+    }
+}
+
+/// Move a file to another filesystem by copying it and removing the original
+fn copy_across_devices(
+    src: &Path,
+    dest: &Path,
+    bwlimit: Option<u64>,
+    fsync: bool,
+) -> io::Result<()> {
+    // Pipes, sockets and devices have no contents to copy
+    let metadata = fs::symlink_metadata(src)?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "special files can't be moved to another filesystem",
+        ));
+    }
+
+    let copied = match bwlimit {
+        Some(bytes_per_sec) => throttle::copy_limited(src, dest, bytes_per_sec),
+        // Holes would otherwise be written out as zeros
+        None if sparse::is_sparse(&metadata) => sparse::copy(src, dest),
+        None => fs::copy(src, dest),
+    };
+    // With --fsync, the copy must be on disk before the source is deleted
+    let synced = copied.and_then(|_| {
+        if fsync {
+            fs::File::open(dest)?.sync_all()?;
+            dest.parent().map_or(Ok(()), sync_dir)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = synced {
+        // Don't leave a partial copy behind
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
+
+    fs::remove_file(src)
+}
+
+/// Rename `src` to `dest`, failing with `AlreadyExists` instead of replacing an existing
+/// `dest`. The check and the rename are one atomic step, so a file created at `dest`
+/// by another process is never clobbered.
+#[cfg(target_os = "linux")]
+fn rename_noreplace(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_src = CString::new(src.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let c_dest = CString::new(dest.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+
+    // SAFETY: both paths are NUL-terminated and outlive the call
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            c_src.as_ptr(),
+            libc::AT_FDCWD,
+            c_dest.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Old kernels and some filesystems don't support the flag; the caller has
+        // already checked that `dest` is free
+        Some(libc::ENOSYS) | Some(libc::EINVAL) => fs::rename(src, dest),
+        _ => Err(e),
+    }
+}
+
+/// Rename `src` to `dest`. Elsewhere the caller's existence check is all that
+/// protects `dest`.
+#[cfg(not(target_os = "linux"))]
+fn rename_noreplace(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::rename(src, dest)
+}
+
+/// Flush a directory's entries to disk, so files renamed into it survive a power loss
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directory entries can't be flushed on their own here; NTFS journals them instead
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Make sure files that have to be copied across filesystems will fit in the root.
+/// Returns an error message if they won't.
+fn check_free_space(root: &Path, summary: &FileSummary) -> Result<(), String> {
+    let free = match space::free_space(root) {
+        Ok(free) => free,
+        Err(e) => {
+            eprintln!(
+                "Warning: Could not determine free space on '{}': {}",
+                display_path(root),
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    let limit = if free.quota { " under your quota" } else { "" };
+
+    if summary.cross_device_bytes > free.bytes {
+        return Err(format!(
+            "Not enough free space on '{}': {} needed, {} available{}",
+            display_path(root),
+            format_bytes(summary.cross_device_bytes),
+            format_bytes(free.bytes),
+            limit
+        ));
+    }
+
+    if let Some(inodes) = free.inodes
+        && summary.cross_device_files as u64 > inodes
+    {
+        return Err(format!(
+            "Not enough free inodes on '{}': {} needed, {} available{}",
+            display_path(root),
+            summary.cross_device_files,
+            inodes,
+            limit
+        ));
+    }
+
+    Ok(())
+}
+
+/// The checks a scanned run has to pass before anything is moved, for the command
+/// line and `rflatten serve` alike. Git working copies are only flattened with
+/// --force or after a yes, which only a caller that `can_confirm` will ask for.
+/// Returns why the run can't go ahead.
+fn check_run(
+    cli: &Cli,
+    root: &Path,
+    summary: &FileSummary,
+    can_confirm: bool,
+) -> Result<(), String> {
+    // Guard against pointing rflatten at the wrong (much larger) tree
+    if let Some(max_files) = cli.max_files
+        && summary.file_count > max_files
+    {
+        return Err(format!(
+            "{} file(s) would be moved, more than --max-files {}. Nothing was moved.",
+            summary.file_count, max_files
+        ));
+    }
+
+    if let Some(max_bytes) = cli.max_bytes
+        && summary.total_bytes > max_bytes
+    {
+        let message = format!(
+            "{} would be moved, more than --max-bytes {}",
+            format_bytes(summary.total_bytes),
+            format_bytes(max_bytes)
+        );
+        if !cli.force {
+            return Err(format!("{}. Use --force to proceed anyway.", message));
+        }
+        eprintln!("Warning: {}", message);
+    }
+
+    // Copying across filesystems needs room in the root; refuse to start rather
+    // than running out of space halfway through
+    if summary.cross_device_files > 0 {
+        check_free_space(root, summary)?;
+    }
+
+    if !summary.git_working_copies.is_empty() && !cli.force && !can_confirm {
+        return Err(format!(
+            "Refusing to destroy the structure of git working copies like '{}' without confirmation. Use --force to proceed anyway.",
+            display_path(&summary.git_working_copies[0])
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that files can be created in `dir`, so a read-only mount or missing
+/// permission is one clear error instead of one per file
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".rflatten-write-check-{}", std::process::id()));
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(extended_length_path(&probe));
+    match result {
+        Ok(_) => {
+            let _ = fs::remove_file(extended_length_path(&probe));
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::ReadOnlyFilesystem => Err(format!(
+            "'{}' is on a read-only filesystem",
+            display_path(dir)
+        )),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(format!(
+            "You don't have permission to write to '{}'",
+            display_path(dir)
+        )),
+        Err(e) => Err(format!("Could not write to '{}': {}", display_path(dir), e)),
+    }
+}
+
+/// List the top-level directories of `root`, sorted by name, with whether the
+/// directory filters select each one
+fn select_top_level_dirs(
+    root: &Path,
+    options: &FlattenOptions,
+) -> io::Result<Vec<(OsString, bool)>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let name = entry.file_name();
+        let lossy = name.to_string_lossy();
+        let mut selected = should_include_top_level_dir(
+            &lossy,
+            &options.include,
+            &options.exclude,
+            options.matching,
+        ) && !options.excludes_dir(&lossy);
+        if selected && let Some(max) = options.max_dir_files {
+            selected = count_files(&entry.path())? <= max;
+        }
+        dirs.push((name, selected));
+    }
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Check whether the run recorded in `manifest` left nothing more to do: every file
+/// it moved is in place, none is back at its source, and no top-level directory
+/// the filters select is left to walk
+fn already_flattened(
+    root: &Path,
+    options: &FlattenOptions,
+    manifest: &Manifest,
+) -> io::Result<bool> {
+    let complete = manifest
+        .resolve(root)?
+        .iter()
+        .all(|(src, dest)| dest.is_file() && !src.exists());
+    if !complete {
+        return Ok(false);
+    }
+
+    let conflict_dir = options
+        .conflict_dir
+        .as_ref()
+        .and_then(|dir| dir.components().next());
+    Ok(select_top_level_dirs(root, options)?
+        .into_iter()
+        .all(|(name, selected)| {
+            !selected
+                || conflict_dir == Some(Component::Normal(&name))
+                || options.is_placement_dir(&name)
+        }))
+}
+
+/// The summary as --summary-format prints it
+fn summary_value(root: &Path, summary: &FileSummary) -> serde_json::Value {
+    let mut directories: BTreeMap<OsString, (usize, u64)> = summary
+        .top_level_dirs
+        .iter()
+        .map(|dir| (dir.clone(), (0, 0)))
+        .collect();
+    for (path, stamp) in &summary.snapshot {
+        if let Some(Component::Normal(dir)) = path
+            .strip_prefix(root)
+            .ok()
+            .and_then(|rel| rel.components().next())
+        {
+            let (files, bytes) = directories.entry(dir.to_os_string()).or_default();
+            *files += 1;
+            *bytes += stamp.len;
+        }
+    }
+
+    serde_json::json!({
+        "root": display_path(root),
+        "files": summary.file_count,
+        "bytes": summary.total_bytes,
+        "directories": directories
+            .iter()
+            .map(|(name, (files, bytes))| serde_json::json!({
+                "name": name.to_string_lossy(),
+                "files": files,
+                "bytes": bytes,
+            }))
+            .collect::<Vec<_>>(),
+        "duplicates": {
+            "files": summary.duplicate_files,
+            "bytes": summary.duplicate_bytes,
+        },
+        "cross_device": {
+            "files": summary.cross_device_files,
+            "bytes": summary.cross_device_bytes,
+        },
+        "archives": summary.archives,
+        "empty_files": summary.empty_files,
+        "git_working_copies": summary
+            .git_working_copies
+            .iter()
+            .map(|path| display_path(path))
+            .collect::<Vec<_>>(),
+        "skipped": summary
+            .skipped
+            .iter()
+            .map(|(path, reason)| serde_json::json!({
+                "path": display_path(path),
+                "reason": reason.to_string(),
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Print which top-level directories (and optionally files) the filters select
+fn test_pattern(root: &Path, options: &FlattenOptions, show_files: bool) -> io::Result<()> {
+    println!("Top-level directories in '{}':", display_path(root));
+    for (name, selected) in select_top_level_dirs(root, options)? {
+        let marker = if selected { "+" } else { "-" };
+        println!("  {} {}", marker, name.to_string_lossy());
+    }
+
+    if show_files {
+        println!("\nMatching files:");
+    }
+
+    let mut count = 0;
+    Walker::new(root, options)?.walk(&mut |event| {
+        if let WalkEvent::File { entry, .. } = event {
+            count += 1;
+            if show_files {
+                let path = entry.path();
+                println!(
+                    "  {}",
+                    display_path(path.strip_prefix(root).unwrap_or(&path))
+                );
+            }
+        }
+        Ok(())
+    })?;
+
+    println!("\n{} file(s) would be moved", count);
+    Ok(())
+}
+
+/// Print each top-level directory with its file count and whether it's selected,
+/// for scripts that build --include lists
+fn list_top_level_dirs(root: &Path, options: &FlattenOptions, json: bool) -> io::Result<()> {
+    let mut dirs = Vec::new();
+    for (name, selected) in select_top_level_dirs(root, options)? {
+        let files = count_files(&root.join(&name))?;
+        dirs.push((name, files, selected));
+    }
+
+    if json {
+        let dirs: Vec<_> = dirs
+            .iter()
+            .map(|(name, files, selected)| {
+                serde_json::json!({
+                    "name": name.to_string_lossy(),
+                    "files": files,
+                    "included": selected,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&dirs).expect("JSON values always serialize")
+        );
+        return Ok(());
+    }
+
+    for (name, files, selected) in &dirs {
+        let included = if *selected { "included" } else { "excluded" };
+        println!(
+            "{}\t{}\t{}",
+            included,
+            files,
+            porcelain::escape(&name.to_string_lossy())
+        );
+    }
+    Ok(())
+}
+
+/// Count the files below `dir`, without following links
+fn count_files(dir: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Directories that are never flattened without --force: the user's home directory
+/// and anything listed in RFLATTEN_PROTECTED_PATHS (separated like PATH), each with
+/// a description for the error message
+fn protected_paths() -> Vec<(PathBuf, &'static str)> {
+    let mut paths: Vec<_> = std::env::home_dir()
+        .map(|home| (home, "it is your home directory"))
+        .into_iter()
+        .collect();
+    if let Some(list) = std::env::var_os("RFLATTEN_PROTECTED_PATHS") {
+        paths.extend(
+            std::env::split_paths(&list)
+                .filter(|p| !p.as_os_str().is_empty())
+                .map(|p| (p, "it is listed in RFLATTEN_PROTECTED_PATHS")),
+        );
+    }
+
+    // Compare canonical forms, as the root is canonicalized for the check too
+    paths
+        .into_iter()
+        .filter_map(|(p, reason)| Some((p.canonicalize().ok()?, reason)))
+        .collect()
+}
+
+/// Describe why flattening `root` is dangerous, if it is: a filesystem or drive
+/// root (`/`, `C:\`), or one of the protected paths
+fn dangerous_root(root: &Path, protected: &[(PathBuf, &'static str)]) -> Option<&'static str> {
+    // With --no-canonicalize, `..` segments and symlinks could hide where the root is
+    let canonical = root.canonicalize();
+    let root = canonical.as_deref().unwrap_or(root);
+    if root.parent().is_none() {
+        return Some("it is the root of a filesystem");
+    }
+
+    protected
+        .iter()
+        .find(|(path, _)| path == root)
+        .map(|(_, reason)| *reason)
+}
+
+/// Apply the moves in `manifest` to the tree at `root`. Every source must exist
+/// before anything is moved, and existing destinations are never replaced.
+/// Returns the number of files moved.
+fn replay_manifest(root: &Path, manifest: &Manifest, quiet: bool) -> io::Result<usize> {
+    let moves = manifest
+        .resolve(root)
+        .map_err(|e| io::Error::new(e.kind(), format!("invalid manifest: {}", e)))?;
+
+    let missing: Vec<_> = moves.iter().filter(|(src, _)| !src.is_file()).collect();
+    if !missing.is_empty() {
+        eprintln!("{} source file(s) are missing:", missing.len());
+        for (src, _) in &missing {
+            eprintln!("  - {}", display_path(src));
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the tree doesn't match the manifest; nothing was moved",
+        ));
+    }
+
+    let mut moved = 0;
+    for (src, dest) in &moves {
+        if dest.exists() {
+            eprintln!(
+                "Error moving {}: {} already exists",
+                display_path(src),
+                display_path(dest)
+            );
+            continue;
+        }
+
+        let result = dest
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| match rename_noreplace(src, dest) {
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                    copy_across_devices(src, dest, None, false)
+                }
+                result => result,
+            });
+        match result {
+            Ok(()) => {
+                moved += 1;
+                if !quiet {
+                    println!("Moved: {} -> {}", display_path(src), display_path(dest));
+                }
+            }
+            Err(e) => eprintln!("Error moving {}: {}", display_path(src), e),
+        }
+    }
+
+    // Delete the top-level directories the moves emptied
+    let root_device = device_id(&fs::metadata(root)?);
+    let mut top_level_dirs: Vec<PathBuf> = moves
+        .iter()
+        .filter_map(|(src, _)| {
+            let relative = src.strip_prefix(root).ok()?;
+            let mut components = relative.components();
+            let first = components.next()?;
+            components.next().map(|_| root.join(first))
+        })
+        .collect();
+    top_level_dirs.sort();
+    top_level_dirs.dedup();
+    for dir in top_level_dirs {
+        if dir.is_dir()
+            && let Err(e) = remove_empty_dirs(&dir, root_device)
+        {
+            eprintln!("Error removing directory {}: {}", display_path(&dir), e);
+        }
+    }
+
+    Ok(moved)
+}
+
+/// Check that `directory` exists and is a directory, exiting with an error if
+/// not, and return its canonical path. Without `canonicalize`, the path is only
+/// made absolute, keeping symlinks and `..` as given.
+fn resolve_directory(directory: &Path, canonicalize: bool) -> io::Result<PathBuf> {
+    // Verify directory exists
+    if !directory.exists() {
+        eprintln!(
+            "Error: Directory '{}' does not exist",
+            display_path(directory)
+        );
+        std::process::exit(1);
+    }
+
+    if !directory.is_dir() {
+        eprintln!("Error: '{}' is not a directory", display_path(directory));
+        std::process::exit(1);
+    }
+
+    if !canonicalize {
+        return std::path::absolute(directory);
+    }
+
+    // Canonicalize the path to get the full absolute path
+    directory.canonicalize()
+}
+
+/// Resolve a directory that may not exist yet without creating it: its closest
+/// existing ancestor is canonicalized and the rest of the path appended. Also
+/// returns that ancestor, which anything missing would be created in.
+fn resolve_new_directory(directory: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    let absolute = std::path::absolute(directory)?;
+    let existing = absolute
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(&absolute);
+    if !existing.is_dir() {
+        eprintln!("Error: '{}' is not a directory", display_path(existing));
+        std::process::exit(1);
+    }
+
+    let mut resolved = existing.canonicalize()?;
+    for component in absolute
+        .strip_prefix(existing)
+        .unwrap_or(Path::new(""))
+        .components()
+    {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    let existing = existing.canonicalize()?;
+    Ok((resolved, existing))
+}
+
+fn main() -> io::Result<()> {
+    let mut cli = Cli::parse();
+    // Scripts parsing --porcelain output get nothing else on stdout, and no prompts
+    cli.quiet |= cli.porcelain;
+
+    match &cli.command {
+        Some(Command::TestPattern {
+            directory,
+            files,
+            filters,
+        }) => {
+            let root = resolve_directory(directory, true)?;
+            return test_pattern(&root, &FlattenOptions::from(filters), *files);
+        }
+        Some(Command::List {
+            directory,
+            json,
+            filters,
+        }) => {
+            let root = resolve_directory(directory, true)?;
+            return list_top_level_dirs(&root, &FlattenOptions::from(filters), *json);
+        }
+        Some(Command::Doctor { directory, filters }) => {
+            let root = resolve_directory(directory, true)?;
+            let report = doctor::check(&root, &FlattenOptions::from(filters))?;
+            doctor::print(&root, &report);
+            return Ok(());
+        }
+        Some(Command::Merge {
+            sources,
+            dest,
+            skip_confirmation,
+            quiet,
+            on_conflict,
+            dedupe,
+            filters,
+        }) => {
+            if *on_conflict == ConflictPolicy::Ask && *quiet {
+                eprintln!("Error: --on-conflict ask can't be used with --quiet");
+                std::process::exit(1);
+            }
+            // The destination is only created once the merge is confirmed
+            let (dest, existing) = resolve_new_directory(dest)?;
+            if let Err(message) = check_writable(&existing) {
+                eprintln!("Error: {}", message);
+                std::process::exit(1);
+            }
+            let sources = sources
+                .iter()
+                .map(|source| resolve_directory(source, true))
+                .collect::<io::Result<Vec<_>>>()?;
+            // A destination inside a source would be merged into itself
+            if let Some(source) = sources
+                .iter()
+                .find(|source| dest.starts_with(source) || source.starts_with(&dest))
+            {
+                eprintln!(
+                    "Error: '{}' and the destination '{}' overlap",
+                    display_path(source),
+                    display_path(&dest)
+                );
+                std::process::exit(1);
+            }
+
+            let options = FlattenOptions {
+                quiet: *quiet,
+                on_conflict: *on_conflict,
+                dedupe: *dedupe,
+                ..FlattenOptions::from(filters)
+            };
+            if !quiet {
+                println!("Merging into '{}':", display_path(&dest));
+                for source in &sources {
+                    println!("  - {}", display_path(source));
+                }
+            }
+            if !skip_confirmation && !quiet && !get_confirmation(Message::Proceed)? {
+                println!("{}", Language::current().text(Message::MergeCancelled));
+                return Ok(());
+            }
+            fs::create_dir_all(&dest)?;
+
+            let started = Instant::now();
+            let report = merge_directories(&sources, &dest, &options)?;
+            record_history(&dest, &report, None);
+            if !quiet {
+                println!("\nSuccessfully moved {} file(s)", report.moved_count);
+                report.print_statistics(started.elapsed());
+                if report.duplicates > 0 {
+                    println!(
+                        "Found {} duplicate file(s) ({})",
+                        report.duplicates,
+                        format_bytes(report.duplicate_bytes)
+                    );
+                }
+            }
+            report.print_failures();
+            if report.error_count() > 0 {
+                std::process::exit(EXIT_FAILED_FILES);
+            }
+            return Ok(());
+        }
+        Some(Command::Completions { shell }) => {
+            print!("{}", completions::script(*shell));
+            return Ok(());
+        }
+        Some(Command::History { limit }) => {
+            print_history(*limit);
+            return Ok(());
+        }
+        Some(Command::Replay {
+            manifest,
+            directory,
+            skip_confirmation,
+            quiet,
+        }) => {
+            let root = resolve_directory(directory, true)?;
+            let manifest = Manifest::read(manifest).unwrap_or_else(|e| {
+                eprintln!(
+                    "Error: Could not read manifest '{}': {}",
+                    display_path(manifest),
+                    e
+                );
+                std::process::exit(1);
+            });
+
+            if !quiet {
+                println!(
+                    "Replaying {} move(s) in '{}'",
+                    manifest.moves.len(),
+                    display_path(&root)
+                );
+            }
+            if !skip_confirmation && !quiet && !get_confirmation(Message::Proceed)? {
+                println!("{}", Language::current().text(Message::ReplayCancelled));
+                return Ok(());
+            }
+
+            let moved = replay_manifest(&root, &manifest, *quiet).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            if !quiet {
+                println!("\nSuccessfully moved {} file(s)", moved);
+            }
+            return Ok(());
+        }
+        #[cfg(unix)]
+        Some(Command::Serve { socket }) => return server::serve(socket),
+        #[cfg(not(unix))]
+        Some(Command::Serve { .. }) => {
+            eprintln!("Error: rflatten serve needs Unix domain sockets");
+            std::process::exit(1);
+        }
+        None => {}
+    }
+
+    if cli.backup.is_some()
+        && !cli.conflict_policies().any(|policy| {
+            matches!(
+                policy,
+                ConflictPolicy::Overwrite | ConflictPolicy::Ask | ConflictPolicy::KeepLarger
+            )
+        })
+    {
+        eprintln!(
+            "Error: --backup can only be used with --on-conflict overwrite, ask or keep-larger"
+        );
+        std::process::exit(1);
+    }
+
+    let asks = cli
+        .conflict_policies()
+        .any(|policy| policy == ConflictPolicy::Ask);
+    if asks && cli.quiet {
+        eprintln!("Error: --on-conflict ask can't be used with --quiet");
+        std::process::exit(1);
+    }
+    if asks && !term::can_prompt() {
+        eprintln!("Error: --on-conflict ask needs a terminal to ask on");
+        std::process::exit(1);
+    }
+
+    let dest_archive = cli.dest_archive.as_deref().map(|path| {
+        match ArchiveFormat::detect(path, &ArchiveFormat::ALL) {
+            Some(format) if !path.exists() => (path, format),
+            Some(_) => {
+                eprintln!("Error: '{}' already exists", display_path(path));
+                std::process::exit(1);
+            }
+            None => {
+                eprintln!("Error: --dest-archive must end in .zip, .tar, .tar.gz or .tgz");
+                std::process::exit(1);
+            }
+        }
+    });
+
+    if cli.no_summary && !cli.skip_confirmation && !cli.quiet {
+        eprintln!("Error: --no-summary can only be used with --yes or --quiet");
+        std::process::exit(1);
+    }
+
+    let directory = cli
+        .directory
+        .as_deref()
+        .expect("clap requires a directory without a subcommand");
+    let root_dir = resolve_directory(directory, !cli.no_canonicalize)?;
+    let mut options = FlattenOptions::from(&cli);
+    if let Some(fd) = cli.progress_fd {
+        let progress = Progress::to_fd(fd).unwrap_or_else(|e| {
+            eprintln!("Error: --progress-fd: {}", e);
+            std::process::exit(1);
+        });
+        options.progress = Some(Arc::new(progress));
+    }
+
+    // A mistyped path could otherwise flatten an entire disk or home directory
+    if !cli.force
+        && let Some(reason) = dangerous_root(&root_dir, &protected_paths())
+    {
+        eprintln!(
+            "Error: Refusing to flatten '{}' because {}. Use --force to proceed anyway.",
+            display_path(&root_dir),
+            reason
+        );
+        std::process::exit(1);
+    }
+
+    // Previews don't write anything, so they work on read-only trees too
+    if cli.summary_format.is_none()
+        && !cli.assume_no
+        && let Err(message) = check_writable(&root_dir)
+    {
+        eprintln!("Error: {}", message);
+        std::process::exit(1);
+    }
+
+    // Lower the priority before walking, since the walk does I/O too
+    if cli.nice_io
+        && let Err(e) = priority::lower_io_priority()
+    {
+        eprintln!("Warning: Could not lower I/O priority: {}", e);
+    }
+
+    // A cron job pointed at an already flattened tree shouldn't rescan it
+    if cli.summary_format.is_none()
+        && let Some(path) = &options.manifest
+        && let Ok(previous) = Manifest::read(path)
+        && already_flattened(&root_dir, &options, &previous)?
+    {
+        if !cli.quiet {
+            println!(
+                "Nothing to do: the {} file(s) recorded in '{}' are already flattened.",
+                previous.moves.len(),
+                display_path(path)
+            );
+        }
+        return Ok(());
+    }
+
+    // Collect summary of files to be moved; the moves are made from the list it takes.
+    // Without one, every selected top-level directory is checked and cleaned up.
+    let summary = if cli.no_summary {
+        let top_level_dirs: HashSet<OsString> = select_top_level_dirs(&root_dir, &options)?
+            .into_iter()
+            .filter_map(|(name, selected)| selected.then_some(name))
+            .collect();
+        FileSummary {
+            git_working_copies: git_working_copies(&root_dir, top_level_dirs.iter()),
+            top_level_dirs,
+            ..Default::default()
+        }
+    } else {
+        let summary = collect_file_summary(&root_dir, &options)?;
+        if let Some(progress) = &options.progress {
+            progress.scanned(summary.file_count, summary.total_bytes);
+        }
+        if summary.dir_limit_reached {
+            warn_dir_limit(&options);
+        }
+        summary
+    };
+
+    if let Some(format) = cli.summary_format {
+        println!("{}", format.render(&summary_value(&root_dir, &summary)));
+        return Ok(());
+    }
+
+    if summary.file_count == 0 && !cli.no_summary {
+        if !cli.quiet {
+            println!("No files found in subdirectories to flatten.");
+        }
+        if options.incremental {
+            save_incremental_state(&root_dir, &options, &summary.settled_dirs);
+        }
+        return Ok(());
+    }
+
+    // A stray file or two isn't worth a run, and its directory removals
+    if cli
+        .min_files
+        .is_some_and(|min_files| summary.file_count < min_files)
+    {
+        return Ok(());
+    }
+
+    // Show summary and get confirmation
+    if !cli.quiet && !cli.no_summary {
+        for (path, reason) in &summary.skipped {
+            println!("Skipping {}: {}", display_path(path), reason);
+        }
+
+        println!(
+            "Found {} file(s) to move to '{}'",
+            summary.file_count,
+            display_path(&root_dir)
+        );
+
+        if let Some(sample) = cli.sample
+            && sample.get() < summary.file_count
+        {
+            println!("Only the first {} will be moved (--sample)", sample);
+        }
+
+        if cli.diff {
+            let (before, after) = preview_root(&root_dir, &options, &summary)?;
+            println!(
+                "{}",
+                diff::render(&display_path(&root_dir), &before, &after)
+            );
+        }
+
+        if !summary.top_level_dirs.is_empty() {
+            println!("Top-level directories to be flattened:");
+            let mut dirs: Vec<_> = summary.top_level_dirs.iter().cloned().collect();
+            dirs.sort();
+            for dir in dirs {
+                println!("  - {}", dir.to_string_lossy());
+            }
+        }
+
+        if summary.archives > 0 {
+            let then = if options.delete_archives {
+                "deleted"
+            } else {
+                "moved too"
+            };
+            println!(
+                "{} archive(s) will be extracted into the root and then {}",
+                summary.archives, then
+            );
+        }
+
+        if summary.duplicate_files > 0 {
+            let action = match options.dedupe {
+                Some(DedupeAction::Delete) => "deleted",
+                Some(DedupeAction::Hardlink) => "hard linked to the existing copy",
+                _ => "left in place",
+            };
+            println!(
+                "{} file(s) ({}) are duplicates and will be {}",
+                summary.duplicate_files,
+                format_bytes(summary.duplicate_bytes),
+                action
+            );
+        }
+
+        if summary.empty_files > 0 {
+            println!(
+                "{} file(s) are empty and will be deleted",
+                summary.empty_files
+            );
+        }
+
+        if summary.cross_device_files > 0 {
+            println!(
+                "{} file(s) ({}) are on a different filesystem and will be copied",
+                summary.cross_device_files,
+                format_bytes(summary.cross_device_bytes)
+            );
+        }
+    }
+
+    if cli.assume_no {
+        if !cli.quiet {
+            println!("Nothing was moved (--no).");
+        }
+        std::process::exit(EXIT_WOULD_MOVE);
+    }
+
+    if !summary.git_working_copies.is_empty() && !cli.force {
+        eprintln!(
+            "\n{} flattening will destroy the structure of these git working copies:",
+            term::warning("WARNING:")
+        );
+        for path in &summary.git_working_copies {
+            eprintln!("  - {}", display_path(path));
+        }
+    }
+    let can_confirm = !cli.skip_confirmation && !cli.quiet;
+    if let Err(message) = check_run(&cli, &root_dir, &summary, can_confirm) {
+        eprintln!("Error: {}", message);
+        std::process::exit(1);
+    }
+
+    // Large runs need the directory name typed, so a stray "y" can't start them
+    let large = cli.sample.is_none()
+        && (summary.file_count > cli.typed_confirm_files
+            || summary.total_bytes > cli.typed_confirm_bytes);
+    let confirmed = || {
+        if large {
+            let name = root_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| display_path(&root_dir));
+            println!(
+                "\nThis will move {} file(s) ({}) into '{}'.",
+                summary.file_count,
+                format_bytes(summary.total_bytes),
+                name
+            );
+            get_typed_confirmation(&name)
+        } else {
+            get_confirmation(Message::Proceed)
+        }
+    };
+
+    // Skip confirmation if -y or -q is provided
+    if !cli.skip_confirmation && !cli.quiet && !confirmed()? {
+        println!("{}", Language::current().text(Message::FlattenCancelled));
+        return Ok(());
+    }
+
+    if let Some((archive_path, format)) = dest_archive {
+        // Only files as they were when the run was confirmed are archived
+        let mut files: Vec<PathBuf> = summary
+            .snapshot
+            .iter()
+            .filter(|(path, stamp)| {
+                fs::symlink_metadata(path).is_ok_and(|m| FileStamp::of(&m) == **stamp)
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        files.sort();
+        if files.len() < summary.snapshot.len() {
+            eprintln!(
+                "{} file(s) changed or disappeared since the scan and were left out",
+                summary.snapshot.len() - files.len()
+            );
+        }
+
+        let archived = archive_files(&root_dir, &files, archive_path, format, &options)
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "Error: Could not write '{}': {}",
+                    display_path(archive_path),
+                    e
+                );
+                std::process::exit(1);
+            });
+        if !cli.quiet {
+            println!(
+                "\nSuccessfully archived {} file(s) into '{}'",
+                archived,
+                display_path(archive_path)
+            );
+        }
+        remove_top_level_dirs(&root_dir, &summary.top_level_dirs)?;
+        return Ok(());
+    }
+
+    if let Some(progress) = &options.progress {
+        progress.planned(summary.file_count, summary.total_bytes);
+    }
+
+    // Perform the flattening (re-traverses the filesystem, acting only on files that
+    // are as the summary found them)
+    let started = Instant::now();
+    let report = if cli.no_summary {
+        flatten_directory_by_traversal(&root_dir, &options)?
+    } else {
+        flatten_from_snapshot(&root_dir, &options, &summary.snapshot)?
+    };
+
+    if let Some(progress) = &options.progress {
+        progress.finished(report.statistics(started.elapsed()));
+    }
+
+    // With a summary, the warning came before the confirmation
+    if cli.no_summary && report.dir_limit_reached {
+        warn_dir_limit(&options);
+    }
+
+    if !cli.quiet {
+        if report.sampled {
+            println!(
+                "\nMoved a sample of {} file(s); run again without --sample to move the rest",
+                report.moved_count
+            );
+        } else if report.cancelled {
+            println!("\nStopped after moving {} file(s)", report.moved_count);
+        } else {
+            println!("\nSuccessfully moved {} file(s)", report.moved_count);
+        }
+        report.print_statistics(started.elapsed());
+
+        if let Some(conflict_dir) = &options.conflict_dir
+            && report.quarantined > 0
+        {
+            println!(
+                "{} conflicting file(s) were moved to '{}'",
+                report.quarantined,
+                display_path(&root_dir.join(conflict_dir))
+            );
+        }
+
+        if report.extracted > 0 {
+            println!("Extracted {} archive(s)", report.extracted);
+        }
+
+        if report.pruned_empty > 0 {
+            println!("Deleted {} empty file(s)", report.pruned_empty);
+        }
+
+        if report.duplicates > 0 {
+            match options.dedupe {
+                Some(DedupeAction::Delete) => println!(
+                    "Removed {} duplicate file(s), reclaiming {}",
+                    report.duplicates,
+                    format_bytes(report.duplicate_bytes)
+                ),
+                Some(DedupeAction::Hardlink) => println!(
+                    "Hard linked {} duplicate file(s), reclaiming {}",
+                    report.duplicates,
+                    format_bytes(report.duplicate_bytes)
+                ),
+                _ => println!(
+                    "Skipped {} duplicate file(s) ({})",
+                    report.duplicates,
+                    format_bytes(report.duplicate_bytes)
+                ),
+            }
+        }
+
+        if report.overwritten > 0 {
+            let backups = if options.backup.is_some() {
+                " (backups were kept)"
+            } else {
+                ""
+            };
+            println!(
+                "{} existing file(s) were overwritten{}",
+                report.overwritten, backups
+            );
+        }
+    }
+
+    // List every name that had to change for the destination filesystem
+    if !cli.quiet && !report.sanitized.is_empty() {
+        println!("\nSanitized {} file name(s):", report.sanitized.len());
+        for (path, name) in &report.sanitized {
+            println!("  - {} -> {}", display_path(path), name.to_string_lossy());
+        }
+    }
+
+    // Anything that changed after the summary was confirmed is left alone
+    for (paths, what) in [
+        (
+            &report.changed_since_scan,
+            "changed since the scan and were left in place",
+        ),
+        (
+            &report.appeared_since_scan,
+            "appeared since the scan and were not moved",
+        ),
+        (&report.vanished_since_scan, "disappeared since the scan"),
+    ] {
+        if !paths.is_empty() {
+            eprintln!("\n{} file(s) {}:", paths.len(), what);
+            for path in paths {
+                eprintln!("  - {}", display_path(path));
+            }
+        }
+    }
+
+    report.print_failures();
+
+    // Files still held open by another process are listed together instead of
+    // being interleaved with the move output
+    if !report.locked.is_empty() {
+        eprintln!(
+            "\n{} file(s) are locked by another process and were not moved:",
+            report.locked.len()
+        );
+        for path in &report.locked {
+            eprintln!("  - {}", display_path(path));
+        }
+    }
+
+    remove_top_level_dirs(&root_dir, &summary.top_level_dirs)?;
+    record_history(&root_dir, &report, options.manifest.as_deref());
+
+    // Saved last, as removing directories changes their parents' mtimes
+    if options.incremental && !report.cancelled {
+        save_incremental_state(&root_dir, &options, &report.settled_dirs);
+    }
+
+    if let Some(command) = &cli.post_run_cmd
+        && let Err(e) = hooks::run(command, &[("root", &root_dir)])
+    {
+        eprintln!("Error: Post-run command: {}", e);
+        std::process::exit(1);
+    }
+
+    if report.error_count() > 0 {
+        std::process::exit(EXIT_FAILED_FILES);
+    }
+    Ok(())
+}
+
+/// The root's entries now and as they'd be after the run, for --diff. Directories end
+/// in `/`. Names are worked out the way the run would, in the order it would move
+/// the files; which files --dedupe finds to be duplicates and the answers to
+/// --on-conflict ask aren't known ahead, so those files are shown as renamed.
+fn preview_root(
+    root: &Path,
+    options: &FlattenOptions,
+    summary: &FileSummary,
+) -> io::Result<(BTreeSet<String>, BTreeSet<String>)> {
+    let listed = |name: &OsStr, is_dir: bool| {
+        let mut name = name.to_string_lossy().into_owned();
+        if is_dir {
+            name.push('/');
+        }
+        name
+    };
+    // Entries under the root that files are placed in show up as that entry
+    let top_entry = |dir: &Path| {
+        dir.components()
+            .next()
+            .map(|component| listed(component.as_os_str(), true))
+    };
+
+    let mut before = BTreeSet::new();
+    let mut after = BTreeSet::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let is_dir = entry.file_type()?.is_dir();
+        let name = listed(&entry.file_name(), is_dir);
+        // Flattened directories are removed, but links to them are kept
+        if !(is_dir && summary.top_level_dirs.contains(&entry.file_name())) {
+            after.insert(name.clone());
+        }
+        before.insert(name);
+    }
+
+    let mut files: Vec<_> = summary.snapshot.iter().collect();
+    files.sort_by_key(|(path, _)| *path);
+    match options.order {
+        Order::DepthFirst => {}
+        Order::Name => files.sort_by(|(a, _), (b, _)| a.file_name().cmp(&b.file_name())),
+        Order::Mtime => files.sort_by_key(|(_, stamp)| std::cmp::Reverse(stamp.modified)),
+        Order::SizeDesc => files.sort_by_key(|(_, stamp)| std::cmp::Reverse(stamp.len)),
+    }
+
+    let fold_case =
+        options.case_insensitive || naming::detect_case_insensitive(root).unwrap_or(false);
+    let mut names = RootNames::load(root, fold_case)?;
+    // The highest conflict counter used for each name, as the mover keeps them
+    let mut counters = HashMap::new();
+    for entry in fs::read_dir(root)? {
+        record_counter(&mut counters, &options.conflict_suffix, &entry?.file_name());
+    }
+    for (path, stamp) in files {
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+        let (name, _) = destination_name(options, path, file_name)?;
+
+        if let Some(dir) = options.only_dirs_parent(path.strip_prefix(root).unwrap_or(path)) {
+            after.extend(top_entry(dir));
+            continue;
+        }
+        if let Some(dir) = Route::dir_for(&options.routes, &name) {
+            after.extend(top_entry(dir));
+            continue;
+        }
+        if let Some(template) = &options.dest_subdir {
+            let date = options
+                .dest_subdir_date
+                .or(stamp.modified)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            after.extend(top_entry(&template.render(date)));
+            continue;
+        }
+
+        let mut dest_name = name.clone();
+        if names.contains(&name) {
+            match options.conflict_policy(&name) {
+                // The incoming file takes the existing one's name
+                ConflictPolicy::Overwrite => {}
+                ConflictPolicy::Skip => continue,
+                ConflictPolicy::Dedupe
+                    if dedupe::same_contents(path, &root.join(&name)).unwrap_or(false) =>
+                {
+                    continue;
+                }
+                _ if options.conflict_dir.is_some() => {
+                    after.extend(options.conflict_dir.as_deref().and_then(top_entry));
+                    continue;
+                }
+                // One of the two keeps the name and the other is removed, unless they're
+                // different files of the same size
+                ConflictPolicy::KeepLarger
+                    if fs::metadata(root.join(&name))
+                        .is_ok_and(|existing| existing.len() != stamp.len)
+                        || dedupe::same_contents(path, &root.join(&name)).unwrap_or(false) =>
+                {
+                    continue;
+                }
+                policy => {
+                    let mut base_name = name.clone();
+                    if policy == ConflictPolicy::PrefixParent
+                        && let Some(parent) = path.parent().and_then(Path::file_name)
+                    {
+                        let mut prefixed = parent.to_os_string();
+                        prefixed.push("_");
+                        prefixed.push(&name);
+                        base_name = fit_name_length(&prefixed, MAX_NAME_LEN).unwrap_or(prefixed);
+                    }
+                    let mut counter = options.conflict_suffix.start();
+                    if !options.fill_counter_gaps && names.contains(&base_name) {
+                        if let Some((original, _)) = options.conflict_suffix.parse_name(&base_name)
+                            && names.contains(&original)
+                        {
+                            base_name = original;
+                        }
+                        let highest = counters.get(&base_name).copied();
+                        counter = options.conflict_suffix.next_counter(highest);
+                    }
+                    dest_name = base_name.clone();
+                    let mut values = None;
+                    while names.contains(&dest_name) {
+                        let values = match values {
+                            Some(ref values) => values,
+                            None => values.insert(options.conflict_suffix.values(path)?),
+                        };
+                        let suffix = options.conflict_suffix.render(counter, values);
+                        let candidate = conflict_name(&base_name, &suffix);
+                        dest_name = fit_name_length(&candidate, MAX_NAME_LEN).unwrap_or(candidate);
+                        counter += 1;
+                    }
+                }
+            }
+        }
+        names.insert(&dest_name);
+        record_counter(&mut counters, &options.conflict_suffix, &dest_name);
+        after.insert(listed(&dest_name, false));
+    }
+
+    Ok((before, after))
+}
+
+/// Delete the now-empty top-level directories
+fn remove_top_level_dirs(root_dir: &Path, top_level_dirs: &HashSet<OsString>) -> io::Result<()> {
+    let root_device = device_id(&fs::metadata(root_dir)?);
+    for dir in top_level_dirs {
+        let dir_path = root_dir.join(dir);
+        // A followed link is kept, along with the directories it leads to
+        if fs::symlink_metadata(&dir_path).is_ok_and(|metadata| metadata.is_dir()) {
+            match remove_empty_dirs(&dir_path, root_device) {
+                Ok(_) => {}
+                Err(e) => eprintln!(
+                    "Error removing directory {}: {}",
+                    display_path(&dir_path),
+                    e
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add a finished run to the history `rflatten history` lists. Failing to is only
+/// worth a warning, as the run itself went through.
+fn record_history(root: &Path, report: &FlattenReport, manifest: Option<&Path>) {
+    let Some(path) = history::path() else {
+        return;
+    };
+    let outcome = if report.cancelled {
+        history::Outcome::Cancelled
+    } else if report.error_count() > 0 {
+        history::Outcome::Errors
+    } else {
+        history::Outcome::Done
+    };
+    let run = history::Run {
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+        root: root.to_path_buf(),
+        args: std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        moved: report.moved_count,
+        moved_bytes: report.moved_bytes,
+        renamed: report.renamed,
+        skipped: report.skipped_count(),
+        errors: report.error_count(),
+        outcome,
+        // The run's working directory is forgotten with it
+        manifest: manifest.and_then(|manifest| std::path::absolute(manifest).ok()),
+    };
+    if let Err(e) = history::record(&path, &run) {
+        eprintln!(
+            "Warning: Could not record the run in '{}': {}",
+            display_path(&path),
+            e
+        );
+    }
+}
+
+/// Print the last `limit` runs in the history, newest first
+fn print_history(limit: usize) {
+    let Some(path) = history::path() else {
+        eprintln!("Error: No home directory to keep the history in");
+        std::process::exit(1);
+    };
+    let runs = history::read(&path).unwrap_or_else(|e| {
+        eprintln!("Error: Could not read '{}': {}", display_path(&path), e);
+        std::process::exit(1);
+    });
+    if runs.is_empty() {
+        println!("No runs recorded yet.");
+        return;
+    }
+
+    for (i, run) in runs.iter().rev().take(limit).enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "{} UTC  {}",
+            dates::format_datetime(SystemTime::UNIX_EPOCH + Duration::from_secs(run.time)),
+            display_path(&run.root)
+        );
+        println!(
+            "  {}: moved {} file(s) ({}), {} renamed, {} skipped, {} error(s)",
+            run.outcome.as_str(),
+            run.moved,
+            format_bytes(run.moved_bytes),
+            run.renamed,
+            run.skipped,
+            run.errors
+        );
+        println!("  rflatten {}", run.args.join(" "));
+        if let Some(manifest) = &run.manifest {
+            println!("  manifest: {}", display_path(manifest));
+        }
+    }
+}
+
+fn save_incremental_state(
+    root: &Path,
+    options: &FlattenOptions,
+    dirs: &[(PathBuf, Vec<OsString>)],
+) {
+    if let Err(e) = incremental::save(root, &options.selection_fingerprint(), dirs) {
+        eprintln!("Warning: Could not save the --incremental state: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn create_test_structure(root: &Path) -> io::Result<()> {
+        // Create a nested directory structure:
+        // root/
+        //   file0.txt (should not be moved - already in root)
+        //   level1/
+        //     file1.txt (depth 1)
+        //     level2/
+        //       file2.txt (depth 2)
+        //       level3/
+        //         file3.txt (depth 3)
+        //         level4/
+        //           file4.txt (depth 4)
+
+        fs::write(root.join("file0.txt"), "root level")?;
+
+        let level1 = root.join("level1");
+        fs::create_dir(&level1)?;
+        fs::write(level1.join("file1.txt"), "depth 1")?;
+
+        let level2 = level1.join("level2");
+        fs::create_dir(&level2)?;
+        fs::write(level2.join("file2.txt"), "depth 2")?;
+
+        let level3 = level2.join("level3");
+        fs::create_dir(&level3)?;
+        fs::write(level3.join("file3.txt"), "depth 3")?;
+
+        let level4 = level3.join("level4");
+        fs::create_dir(&level4)?;
+        fs::write(level4.join("file4.txt"), "depth 4")?;
+
+        Ok(())
+    }
+
+    fn create_multi_dir_structure(root: &Path) -> io::Result<()> {
+        // Create structure with multiple top-level directories:
+        // root/
+        //   docs/
+        //     readme.txt
+        //   src/
+        //     main.rs
+        //   tests/
+        //     test1.rs
+        //   documentation/
+        //     guide.txt
+
+        let docs = root.join("docs");
+        fs::create_dir(&docs)?;
+        fs::write(docs.join("readme.txt"), "docs")?;
+
+        let src = root.join("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("main.rs"), "src")?;
+
+        let tests = root.join("tests");
+        fs::create_dir(&tests)?;
+        fs::write(tests.join("test1.rs"), "tests")?;
+
+        let documentation = root.join("documentation");
+        fs::create_dir(&documentation)?;
+        fs::write(documentation.join("guide.txt"), "documentation")?;
+
+        Ok(())
+    }
+
+    // Tests for pattern matching
+    #[test]
+    fn test_starts_with_pattern() {
+        let starts_with_pattern =
+            |target: &str, pattern: &str| PatternMatch::default().matches(target, pattern);
+
+        assert!(starts_with_pattern("docs", "doc"));
+        assert!(starts_with_pattern("documentation", "doc"));
+        assert!(starts_with_pattern("DOCS", "doc"));
+        assert!(starts_with_pattern("docs", "DOC"));
+        assert!(!starts_with_pattern("src", "doc"));
+        assert!(starts_with_pattern("src", "src"));
+        assert!(starts_with_pattern("tests", "test"));
+        // Test that it's prefix matching, not substring matching
+        assert!(!starts_with_pattern("mydocs", "doc"));
+        assert!(!starts_with_pattern("src", "rc"));
+    }
+
+    #[test]
+    fn test_pattern_match_modes() {
+        let exact = PatternMatch {
+            mode: MatchMode::Exact,
+            ..Default::default()
+        };
+        assert!(exact.matches("docs", "docs"));
+        assert!(exact.matches("DOCS", "docs"));
+        assert!(!exact.matches("documentation", "doc"));
+
+        let substring = PatternMatch {
+            mode: MatchMode::Substring,
+            ..Default::default()
+        };
+        assert!(substring.matches("mydocs", "doc"));
+        assert!(substring.matches("src", "rc"));
+        assert!(!substring.matches("src", "doc"));
+    }
+
+    #[test]
+    fn test_pattern_match_case_sensitive() {
+        let matching = PatternMatch {
+            case_sensitive: true,
+            ..Default::default()
+        };
+        assert!(matching.matches("Docs", "Doc"));
+        assert!(!matching.matches("DOCS", "doc"));
+        assert!(!matching.matches("docs", "DOC"));
+    }
+
+    #[test]
+    fn test_should_include_exact_match() {
+        let include = Some(vec!["doc".to_string()]);
+        let exact = PatternMatch {
+            mode: MatchMode::Exact,
+            ..Default::default()
+        };
+        assert!(!should_include_top_level_dir(
+            "documentation",
+            &include,
+            &None,
+            exact
+        ));
+        assert!(should_include_top_level_dir("doc", &include, &None, exact));
+    }
+
+    // Tests for should_include_top_level_dir
+    #[test]
+    fn test_should_include_no_filters() {
+        assert!(should_include_top_level_dir(
+            "docs",
+            &None,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "src",
+            &None,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "tests",
+            &None,
+            &None,
+            PatternMatch::default()
+        ));
+    }
+
+    #[test]
+    fn test_should_include_with_include_filter() {
+        let include = Some(vec!["src".to_string()]);
+        assert!(!should_include_top_level_dir(
+            "docs",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "src",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(!should_include_top_level_dir(
+            "tests",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+    }
+
+    #[test]
+    fn test_should_include_with_multiple_include_filters() {
+        let include = Some(vec!["src".to_string(), "test".to_string()]);
+        assert!(!should_include_top_level_dir(
+            "docs",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "src",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "tests",
+            &include,
+            &None,
+            PatternMatch::default()
+        )); // matches "test"
+    }
+
+    #[test]
+    fn test_should_include_with_exclude_filter() {
+        let exclude = Some(vec!["src".to_string()]);
+        assert!(should_include_top_level_dir(
+            "docs",
+            &None,
+            &exclude,
+            PatternMatch::default()
+        ));
+        assert!(!should_include_top_level_dir(
+            "src",
+            &None,
+            &exclude,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "tests",
+            &None,
+            &exclude,
+            PatternMatch::default()
+        ));
+    }
+
+    #[test]
+    fn test_should_include_with_prefix_matching() {
+        let include = Some(vec!["doc".to_string()]);
+        assert!(should_include_top_level_dir(
+            "docs",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(should_include_top_level_dir(
+            "documentation",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        assert!(!should_include_top_level_dir(
+            "src",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+        // Test that it's prefix matching, not substring matching
+        assert!(!should_include_top_level_dir(
+            "mydocs",
+            &include,
+            &None,
+            PatternMatch::default()
+        ));
+    }
+
+    // Tests for collect_file_summary
+    #[test]
+    fn test_collect_summary_unlimited_depth() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let summary = collect_file_summary(root, &FlattenOptions::default()).unwrap();
+
+        // Should count all files except file0.txt (which is in root)
+        assert_eq!(summary.file_count, 4);
+        assert_eq!(summary.top_level_dirs.len(), 1);
+        assert!(summary.top_level_dirs.contains(OsStr::new("level1")));
+    }
+
+    #[test]
+    fn test_collect_summary_max_depth_1() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let summary = collect_file_summary(
+            root,
+            &FlattenOptions {
+                max_depth: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Should only count file1.txt (at depth 1)
+        assert_eq!(summary.file_count, 1);
+    }
+
+    #[test]
+    fn test_collect_summary_max_depth_2() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let summary = collect_file_summary(
+            root,
+            &FlattenOptions {
+                max_depth: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Should count file1.txt and file2.txt (depths 1 and 2)
+        assert_eq!(summary.file_count, 2);
+    }
+
+    #[test]
+    fn test_collect_summary_max_depth_0() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let summary = collect_file_summary(
+            root,
+            &FlattenOptions {
+                max_depth: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Should count no files (depth 0 means only look in root, but we don't count root files)
+        assert_eq!(summary.file_count, 0);
+    }
+
+    #[test]
+    fn test_collect_summary_max_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let options = FlattenOptions {
+            max_dirs: Some(2),
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+
+        // Only level1 and level2 are walked
+        assert_eq!(summary.file_count, 2);
+        assert!(summary.dir_limit_reached);
+
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(report.dir_limit_reached);
+        assert!(root.join("level1/level2/level3/file3.txt").exists());
+    }
+
+    #[test]
+    fn test_collect_summary_with_include() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        let include = Some(vec!["src".to_string()]);
+        let summary = collect_file_summary(
+            root,
+            &FlattenOptions {
+                include,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.file_count, 1);
+        assert!(summary.top_level_dirs.contains(OsStr::new("src")));
+        assert!(!summary.top_level_dirs.contains(OsStr::new("docs")));
+    }
+
+    #[test]
+    fn test_collect_summary_with_prefix_include() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        // "doc" should match both "docs" and "documentation" (prefix match)
+        let include = Some(vec!["doc".to_string()]);
+        let summary = collect_file_summary(
+            root,
+            &FlattenOptions {
+                include,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.file_count, 2);
+        assert!(summary.top_level_dirs.contains(OsStr::new("docs")));
+        assert!(summary.top_level_dirs.contains(OsStr::new("documentation")));
+        assert!(!summary.top_level_dirs.contains(OsStr::new("src")));
+    }
+
+    #[test]
+    fn test_collect_summary_with_exclude() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        let exclude = Some(vec!["src".to_string()]);
+        let summary = collect_file_summary(
+            root,
+            &FlattenOptions {
+                exclude,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.file_count, 3);
+        assert!(!summary.top_level_dirs.contains(OsStr::new("src")));
+        assert!(summary.top_level_dirs.contains(OsStr::new("docs")));
+    }
+
+    #[test]
+    fn test_collect_summary_empty_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let summary = collect_file_summary(root, &FlattenOptions::default()).unwrap();
+        assert_eq!(summary.file_count, 0);
+        assert_eq!(summary.top_level_dirs.len(), 0);
+    }
+
+    // Tests for flatten_directory_by_traversal
+    #[test]
+    fn test_flatten_no_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // Create subdirectory with files
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("test1.txt"), "content1").unwrap();
+        fs::write(subdir.join("test2.txt"), "content2").unwrap();
+
+        let moved_count = flatten_directory_by_traversal(root, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+
+        assert_eq!(moved_count, 2);
+        assert!(root.join("test1.txt").exists());
+        assert!(root.join("test2.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("test1.txt")).unwrap(),
+            "content1"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("test2.txt")).unwrap(),
+            "content2"
+        );
+    }
+
+    #[test]
+    fn test_flatten_with_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // Create a file in root
+        fs::write(root.join("test.txt"), "root content").unwrap();
+
+        // Create subdirectory with conflicting filename
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("test.txt"), "subdir content").unwrap();
+
+        let moved_count = flatten_directory_by_traversal(root, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+
+        assert_eq!(moved_count, 1);
+        // Original file should remain unchanged
+        assert_eq!(
+            fs::read_to_string(root.join("test.txt")).unwrap(),
+            "root content"
+        );
+
+        // Conflicting file should be renamed
+        assert!(root.join("test_1.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("test_1.txt")).unwrap(),
+            "subdir content"
+        );
+    }
+
+    #[test]
+    fn test_flatten_custom_conflict_suffix() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("test.txt"), "root content").unwrap();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("test.txt"), dir).unwrap();
+        }
+
+        let options = FlattenOptions {
+            conflict_suffix: ConflictSuffix::parse("_{counter:03}").unwrap(),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("test_001.txt").exists());
+        assert!(root.join("test_002.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_conflict_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("test.txt"), "root content").unwrap();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/test.txt"), "nested").unwrap();
+        fs::write(root.join("a/unique.txt"), "unique").unwrap();
+
+        let options = FlattenOptions {
+            conflict_dir: Some(PathBuf::from("_conflicts")),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 2);
+        assert_eq!(report.quarantined, 1);
+        assert!(root.join("unique.txt").exists());
+        assert!(!root.join("test_1.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("_conflicts/test.txt")).unwrap(),
+            "nested"
+        );
+
+        // A second run leaves the conflict directory alone
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 0);
+    }
+
+    #[test]
+    fn test_flatten_nested_conflict_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("test.txt"), "root content").unwrap();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/test.txt"), "nested").unwrap();
+
+        let options = FlattenOptions {
+            conflict_dir: Some(PathBuf::from("review")),
+            nest_conflicts: true,
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert!(root.join("review/a/b/test.txt").exists());
+    }
+
+    #[test]
+    fn test_parse_conflict_dir() {
+        assert_eq!(
+            parse_conflict_dir("_conflicts"),
+            Ok(PathBuf::from("_conflicts"))
+        );
+        assert!(parse_conflict_dir("review/dupes").is_ok());
+        assert!(parse_conflict_dir("").is_err());
+        assert!(parse_conflict_dir("../outside").is_err());
+        assert!(parse_conflict_dir("/tmp/conflicts").is_err());
+    }
+
+    #[test]
+    fn test_flatten_overwrite_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("test.txt"), "root content").unwrap();
+        fs::create_dir(root.join("subdir")).unwrap();
+        fs::write(root.join("subdir/test.txt"), "subdir content").unwrap();
+
+        let options = FlattenOptions {
+            on_conflict: ConflictPolicy::Overwrite,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.overwritten, 1);
+        assert!(!root.join("test_1.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("test.txt")).unwrap(),
+            "subdir content"
+        );
+    }
+
+    #[test]
+    fn test_flatten_overwrite_with_backup() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("test.txt"), "root content").unwrap();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("test.txt"), dir).unwrap();
+        }
+
+        let options = FlattenOptions {
+            on_conflict: ConflictPolicy::Overwrite,
+            backup: Some(Backup::Suffix(".bak".to_string())),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        // Both replaced versions are kept; the second backup is numbered
+        assert_eq!(report.overwritten, 2);
+        let mut contents: Vec<String> = ["test.txt", "test.txt.bak", "test.txt.~1~"]
+            .iter()
+            .map(|name| fs::read_to_string(root.join(name)).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["a", "b", "root content"]);
+        assert_eq!(
+            fs::read_to_string(root.join("test.txt.bak")).unwrap(),
+            "root content"
+        );
+    }
+
+    #[test]
+    fn test_flatten_dedupe_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("existing.txt"), "in root").unwrap();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("download.zip"), "zip").unwrap();
+        }
+        fs::write(root.join("b/copy.txt"), "in root").unwrap();
+
+        let options = FlattenOptions {
+            dedupe: Some(DedupeAction::Delete),
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 3);
+        assert_eq!(summary.duplicate_files, 2);
+
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 1);
+        assert_eq!(report.duplicates, 2);
+        assert_eq!(report.duplicate_bytes, 3 + 7);
+        assert!(root.join("download.zip").exists());
+        assert!(!root.join("download_1.zip").exists());
+        assert!(!root.join("copy.txt").exists());
+        assert!(!root.join("b/copy.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_dedupe_skip() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("existing.txt"), "in root").unwrap();
+        fs::create_dir(root.join("a")).unwrap();
+        fs::write(root.join("a/copy.txt"), "in root").unwrap();
+
+        let options = FlattenOptions {
+            dedupe: Some(DedupeAction::Skip),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 0);
+        assert_eq!(report.duplicates, 1);
+        assert!(root.join("a/copy.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_flatten_dedupe_hardlink() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("photo.jpg"), "pixels").unwrap();
+        fs::create_dir(root.join("backup")).unwrap();
+        fs::write(root.join("backup/photo.jpg"), "pixels").unwrap();
+
+        let options = FlattenOptions {
+            dedupe: Some(DedupeAction::Hardlink),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.duplicates, 1);
+        assert!(!root.join("backup/photo.jpg").exists());
+        let original = fs::metadata(root.join("photo.jpg")).unwrap();
+        let link = fs::metadata(root.join("photo_1.jpg")).unwrap();
+        assert_eq!(original.ino(), link.ino());
+        assert_eq!(original.nlink(), 2);
+    }
+
+    #[test]
+    fn test_flatten_writes_checksums() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("test.txt"), "root").unwrap();
+        fs::create_dir(root.join("a")).unwrap();
+        fs::write(root.join("a/test.txt"), "abc").unwrap();
+        // A file with the checksum file's name must not be overwritten by it
+        fs::write(root.join("a/sums.txt"), "abc").unwrap();
+
+        let sums = root.join("sums.txt");
+        let options = FlattenOptions {
+            checksums: Some(sums.clone()),
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(
+            fs::read_to_string(&sums).unwrap(),
+            format!("{abc}  sums_1.txt\n{abc}  test_1.txt\n")
+        );
+    }
+
+    #[test]
+    fn test_flatten_provenance_sidecar() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("2023/trip")).unwrap();
+        fs::write(root.join("2023/trip/photo.jpg"), "pixels").unwrap();
+        fs::create_dir(root.join("other")).unwrap();
+        fs::write(root.join("other/photo.jpg.origin"), "unrelated").unwrap();
+
+        let options = FlattenOptions {
+            provenance: Some(Provenance::Sidecar),
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+
+        let origin = Path::new("2023").join("trip").join("photo.jpg");
+        assert_eq!(
+            fs::read_to_string(root.join("photo.jpg.origin")).unwrap(),
+            format!("{}\n", origin.display())
+        );
+        // The sidecar's name counts as taken
+        assert_eq!(
+            fs::read_to_string(root.join("photo.jpg_1.origin")).unwrap(),
+            "unrelated"
+        );
+    }
+
+    #[test]
+    fn test_conflict_numbering_follows_name_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for dir in ["c", "a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("file.txt"), dir).unwrap();
+        }
+
+        flatten_directory_by_traversal(root, &FlattenOptions::default()).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("file.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(root.join("file_1.txt")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(root.join("file_2.txt")).unwrap(), "c");
+    }
+
+    #[test]
+    fn test_order_decides_which_file_keeps_its_name() {
+        let create = |root: &Path| {
+            let now = SystemTime::now();
+            for (dir, content, age) in [
+                ("a", "small", 0),
+                ("b", "largest", 60),
+                ("c", "larger", 120),
+            ] {
+                fs::create_dir(root.join(dir)).unwrap();
+                let path = root.join(dir).join("file.txt");
+                fs::write(&path, content).unwrap();
+                fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(now - Duration::from_secs(age))
+                    .unwrap();
+            }
+        };
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create(root);
+        let options = FlattenOptions {
+            order: Order::SizeDesc,
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("file.txt")).unwrap(),
+            "largest"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("file_1.txt")).unwrap(),
+            "larger"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("file_2.txt")).unwrap(),
+            "small"
+        );
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create(root);
+        let options = FlattenOptions {
+            order: Order::Mtime,
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(fs::read_to_string(root.join("file.txt")).unwrap(), "small");
+        assert_eq!(
+            fs::read_to_string(root.join("file_1.txt")).unwrap(),
+            "largest"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("file_2.txt")).unwrap(),
+            "larger"
+        );
+    }
+
+    #[test]
+    fn test_incremental_skips_unchanged_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/keep.log"), "keep").unwrap();
+        fs::write(root.join("a/b/photo.jpg"), "photo").unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::open(root.join("a"))
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+
+        let options = FlattenOptions {
+            incremental: true,
+            path_globs: vec![PathGlob::parse("**/*.jpg").unwrap()],
+            explain: true,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 1);
+        incremental::save(root, &options.selection_fingerprint(), &report.settled_dirs).unwrap();
+
+        // "a" only held a filtered-out file, so it isn't read again
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert!(
+            summary
+                .skipped
+                .contains(&(root.join("a"), SkipReason::Unchanged))
+        );
+
+        fs::write(root.join("a/new.jpg"), "new").unwrap();
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 1);
+    }
+
+    #[test]
+    fn test_already_flattened() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("root");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/photo.jpg"), "photo").unwrap();
+        let manifest_path = temp_dir.path().join("manifest.json");
+        let options = FlattenOptions {
+            manifest: Some(manifest_path.clone()),
+            ..Default::default()
+        };
+
+        flatten_directory_by_traversal(&root, &options).unwrap();
+        remove_empty_dirs(&root.join("a"), device_id(&fs::metadata(&root).unwrap())).unwrap();
+        let manifest = Manifest::read(&manifest_path).unwrap();
+        assert!(already_flattened(&root, &options, &manifest).unwrap());
+
+        // New directories need a real run
+        fs::create_dir(root.join("c")).unwrap();
+        assert!(!already_flattened(&root, &options, &manifest).unwrap());
+        fs::remove_dir(root.join("c")).unwrap();
+
+        // So does a file put back where it came from
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::rename(root.join("photo.jpg"), root.join("a/b/photo.jpg")).unwrap();
+        assert!(!already_flattened(&root, &options, &manifest).unwrap());
+    }
+
+    #[test]
+    fn test_flatten_from_snapshot_reports_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("a")).unwrap();
+        for name in ["same.txt", "edited.txt", "deleted.txt"] {
+            fs::write(root.join("a").join(name), "content").unwrap();
+        }
+        let summary = collect_file_summary(root, &FlattenOptions::default()).unwrap();
+        assert_eq!(summary.snapshot.len(), 3);
+
+        fs::write(root.join("a/edited.txt"), "edited content").unwrap();
+        fs::remove_file(root.join("a/deleted.txt")).unwrap();
+        fs::write(root.join("a/new.txt"), "new").unwrap();
+
+        let report =
+            flatten_from_snapshot(root, &FlattenOptions::default(), &summary.snapshot).unwrap();
+
+        assert_eq!(report.moved_count, 1);
+        assert!(root.join("same.txt").exists());
+        assert_eq!(report.changed_since_scan, vec![root.join("a/edited.txt")]);
+        assert_eq!(report.appeared_since_scan, vec![root.join("a/new.txt")]);
+        assert_eq!(report.vanished_since_scan, vec![root.join("a/deleted.txt")]);
+        assert!(root.join("a/edited.txt").exists());
+        assert!(root.join("a/new.txt").exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_flatten_extracts_archives() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("root");
+        let staging = temp_dir.path().join("staging");
+        fs::create_dir_all(staging.join("inner")).unwrap();
+        fs::write(staging.join("inner/photo.jpg"), "photo").unwrap();
+        fs::write(staging.join("notes.txt"), "notes").unwrap();
+        fs::create_dir_all(root.join("downloads")).unwrap();
+        fs::write(root.join("notes.txt"), "existing").unwrap();
+        let status = std::process::Command::new("tar")
+            .arg("-cf")
+            .arg(root.join("downloads/bundle.tar"))
+            .arg("-C")
+            .arg(&staging)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let options = FlattenOptions {
+            extract: Some(vec![ArchiveFormat::Tar]),
+            delete_archives: true,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(&root, &options).unwrap();
+
+        assert_eq!(report.extracted, 1);
+        assert_eq!(report.moved_count, 2);
+        assert_eq!(fs::read_to_string(root.join("photo.jpg")).unwrap(), "photo");
+        assert_eq!(
+            fs::read_to_string(root.join("notes_1.txt")).unwrap(),
+            "notes"
+        );
+        assert!(!root.join("downloads/bundle.tar").exists());
+        assert!(!root.join("bundle.tar").exists());
+        // The scratch directory is cleaned up
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 4);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_archive_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("root");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/notes.txt"), "a").unwrap();
+        fs::write(root.join("b/notes.txt"), "b").unwrap();
+        let archive_path = temp_dir.path().join("out.tar");
+
+        let files = vec![root.join("a/notes.txt"), root.join("b/notes.txt")];
+        let options = FlattenOptions::default();
+        let archived =
+            archive_files(&root, &files, &archive_path, ArchiveFormat::Tar, &options).unwrap();
+
+        assert_eq!(archived, 2);
+        assert!(!root.join("a/notes.txt").exists());
+        assert!(!root.join("b/notes.txt").exists());
+        let listing = std::process::Command::new("tar")
+            .arg("-tf")
+            .arg(&archive_path)
+            .output()
+            .unwrap();
+        let mut names: Vec<String> = String::from_utf8(listing.stdout)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["notes.txt", "notes_1.txt"]);
+        // The scratch directory is cleaned up
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_flatten_move_hooks() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/keep.txt"), "keep").unwrap();
+        fs::write(root.join("b/skip.txt"), "skip").unwrap();
+        let log = root.join("log");
+
+        let options = FlattenOptions {
+            quiet: true,
+            pre_move_cmd: Some("case {src} in *skip*) exit 1;; esac".to_string()),
+            post_move_cmd: Some(format!("echo {{dest}} >> '{}'", log.display())),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 1);
+        assert!(root.join("keep.txt").exists());
+        assert!(root.join("b/skip.txt").exists());
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!("{}\n", root.join("keep.txt").display())
+        );
+    }
+
+    #[test]
+    fn test_ask_choice_for_all_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("notes.txt"), "existing").unwrap();
+        fs::write(root.join("a/notes.txt"), "incoming").unwrap();
+        fs::write(root.join("a/other.txt"), "other").unwrap();
+        let options = FlattenOptions {
+            on_conflict: ConflictPolicy::Ask,
+            ..Default::default()
+        };
+
+        let mut mover = Mover::new(root, &options).unwrap();
+        mover.conflict_choice = Some(ConflictChoice::Skip);
+        assert!(is_declined(
+            &mover.move_file(&root.join("a/notes.txt")).unwrap_err()
+        ));
+        assert!(root.join("a/notes.txt").exists());
+        // Files without a conflict are moved without asking
+        mover.move_file(&root.join("a/other.txt")).unwrap();
+
+        mover.conflict_choice = Some(ConflictChoice::KeepExisting);
+        assert!(is_declined(
+            &mover.move_file(&root.join("a/notes.txt")).unwrap_err()
+        ));
+        assert!(!root.join("a/notes.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("notes.txt")).unwrap(),
+            "existing"
+        );
+    }
+
+    #[test]
+    fn test_flatten_keep_larger() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("big.jpg"), "small").unwrap();
+        fs::write(root.join("small.jpg"), "larger original").unwrap();
+        fs::write(root.join("a/big.jpg"), "much larger copy").unwrap();
+        fs::write(root.join("b/small.jpg"), "tiny").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            on_conflict: ConflictPolicy::KeepLarger,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 1);
+        assert_eq!(report.overwritten, 1);
+        assert_eq!(
+            fs::read_to_string(root.join("big.jpg")).unwrap(),
+            "much larger copy"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("small.jpg")).unwrap(),
+            "larger original"
+        );
+        assert!(!root.join("b/small.jpg").exists());
+    }
+
+    #[test]
+    fn test_flatten_keep_larger_same_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a.txt"), "aaaa").unwrap();
+        fs::write(root.join("b.txt"), "same").unwrap();
+        fs::write(root.join("a/a.txt"), "bbbb").unwrap();
+        fs::write(root.join("b/b.txt"), "same").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            on_conflict: ConflictPolicy::KeepLarger,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        // A different file of the same size is kept under a new name, a copy removed
+        assert_eq!(report.moved_count, 1);
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "aaaa");
+        assert_eq!(fs::read_to_string(root.join("a_1.txt")).unwrap(), "bbbb");
+        assert!(!root.join("b/b.txt").exists());
+        assert!(!root.join("b_1.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_keep_larger_quarantines_smaller() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("big.jpg"), "small").unwrap();
+        fs::write(root.join("small.jpg"), "larger original").unwrap();
+        fs::write(root.join("a/big.jpg"), "much larger copy").unwrap();
+        fs::write(root.join("b/small.jpg"), "tiny").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            on_conflict: ConflictPolicy::KeepLarger,
+            conflict_dir: Some(PathBuf::from("conflicts")),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.quarantined, 2);
+        assert_eq!(
+            fs::read_to_string(root.join("big.jpg")).unwrap(),
+            "much larger copy"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("conflicts/big.jpg")).unwrap(),
+            "small"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("conflicts/small.jpg")).unwrap(),
+            "tiny"
+        );
+    }
+
+    #[test]
+    fn test_flatten_prefix_parent_on_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("api/docs")).unwrap();
+        fs::create_dir_all(root.join("web/docs")).unwrap();
+        fs::create_dir_all(root.join("web/src")).unwrap();
+        fs::write(root.join("readme.txt"), "root").unwrap();
+        fs::write(root.join("api/docs/readme.txt"), "api").unwrap();
+        fs::write(root.join("web/docs/readme.txt"), "web").unwrap();
+        fs::write(root.join("web/src/main.rs"), "main").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            on_conflict: ConflictPolicy::PrefixParent,
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("readme.txt")).unwrap(), "root");
+        assert_eq!(
+            fs::read_to_string(root.join("docs_readme.txt")).unwrap(),
+            "api"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("docs_readme_1.txt")).unwrap(),
+            "web"
+        );
+        // Files without a conflict keep their plain names
+        assert!(root.join("main.rs").exists());
+    }
+
+    #[test]
+    fn test_flatten_conflict_rules() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("photo.JPG"), "photo").unwrap();
+        fs::write(root.join("other.jpg"), "other").unwrap();
+        fs::write(root.join("app.log"), "old").unwrap();
+        fs::write(root.join("notes.txt"), "old").unwrap();
+        fs::write(root.join("a/photo.JPG"), "photo").unwrap();
+        fs::write(root.join("a/other.jpg"), "changed").unwrap();
+        fs::write(root.join("b/app.log"), "new").unwrap();
+        fs::write(root.join("b/notes.txt"), "new").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            on_conflict: ConflictPolicy::Overwrite,
+            conflict_rules: vec![
+                ConflictRule::parse("*.jpg=dedupe").unwrap(),
+                ConflictRule::parse("*.log=skip").unwrap(),
+            ],
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+
+        // The identical photo is removed, the changed one renamed
+        assert!(!root.join("a/photo.JPG").exists());
+        assert!(!root.join("photo_1.JPG").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("other_1.jpg")).unwrap(),
+            "changed"
+        );
+        // The log stays behind, and everything else falls back to --on-conflict
+        assert_eq!(fs::read_to_string(root.join("app.log")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(root.join("b/app.log")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "new");
+
+        assert!(ConflictRule::parse("*.jpg").is_err());
+        assert!(ConflictRule::parse("*.jpg=sometimes").is_err());
+    }
+
+    #[test]
+    fn test_merge_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        let backup1 = temp_dir.path().join("backup1");
+        let backup2 = temp_dir.path().join("backup2");
+        let dest = temp_dir.path().join("merged");
+        fs::create_dir_all(backup1.join("photos")).unwrap();
+        fs::create_dir_all(backup2.join("old/photos")).unwrap();
+        fs::create_dir(&dest).unwrap();
+        fs::write(backup1.join("notes.txt"), "notes").unwrap();
+        fs::write(backup1.join("photos/a.jpg"), "first").unwrap();
+        fs::write(backup2.join("old/photos/a.jpg"), "second").unwrap();
+        fs::write(backup2.join("old/photos/b.jpg"), "first").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            dedupe: Some(DedupeAction::Delete),
+            ..Default::default()
+        };
+        let report =
+            merge_directories(&[backup1.clone(), backup2.clone()], &dest, &options).unwrap();
+
+        assert_eq!(report.moved_count, 3);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(fs::read_to_string(dest.join("a.jpg")).unwrap(), "first");
+        assert_eq!(fs::read_to_string(dest.join("a_1.jpg")).unwrap(), "second");
+        assert!(dest.join("notes.txt").exists());
+        assert!(!dest.join("b.jpg").exists());
+        // Emptied directories are removed, the sources themselves are kept
+        assert!(!backup2.join("old").exists());
+        assert!(backup2.exists());
+    }
+
+    #[test]
+    fn test_flatten_routes_by_extension() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("dump/a")).unwrap();
+        fs::create_dir_all(root.join("Pictures")).unwrap();
+        fs::write(root.join("Pictures/photo.jpg"), "existing").unwrap();
+        fs::write(root.join("photo.jpg"), "root").unwrap();
+        fs::write(root.join("dump/photo.jpg"), "photo").unwrap();
+        fs::write(root.join("dump/a/song.MP3"), "song").unwrap();
+        fs::write(root.join("dump/a/notes.txt"), "notes").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            routes: vec![
+                Route::parse("jpg,png -> Pictures").unwrap(),
+                Route::parse("mp3 -> Media/Music").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 3);
+        assert_eq!(
+            fs::read_to_string(root.join("Pictures/photo_1.jpg")).unwrap(),
+            "photo"
+        );
+        assert!(root.join("Media/Music/song.MP3").exists());
+        assert!(root.join("notes.txt").exists());
+        assert_eq!(fs::read_to_string(root.join("photo.jpg")).unwrap(), "root");
+
+        // Routed files aren't flattened again
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 0);
+    }
+
+    #[test]
+    fn test_flatten_dest_subdir() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("2023")).unwrap();
+        fs::write(root.join("a/old.txt"), "old").unwrap();
+        fs::write(root.join("a/b/new.txt"), "new").unwrap();
+        fs::write(root.join("2023/kept.txt"), "kept").unwrap();
+        // 2023-06-15 and 2024-01-02 UTC
+        set_mtime(
+            &root.join("a/old.txt"),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_686_830_400),
+        );
+        set_mtime(
+            &root.join("a/b/new.txt"),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_196_800),
+        );
+
+        let options = FlattenOptions {
+            quiet: true,
+            dest_subdir: Some(SubdirTemplate::parse("{yyyy}/{mm}").unwrap()),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("2023/06/old.txt").exists());
+        assert!(root.join("2024/01/new.txt").exists());
+        // Existing buckets are left alone
+        assert!(root.join("2023/kept.txt").exists());
+        assert_eq!(collect_file_summary(root, &options).unwrap().file_count, 0);
+    }
+
+    #[test]
+    fn test_summary_value() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+        fs::write(root.join("a/one.txt"), "12345").unwrap();
+        fs::write(root.join("a/b/two.txt"), "123").unwrap();
+        fs::write(root.join("c/three.txt"), "1").unwrap();
+
+        let options = FlattenOptions::default();
+        let summary = collect_file_summary(root, &options).unwrap();
+        let value = summary_value(root, &summary);
+
+        assert_eq!(value["files"], 3);
+        assert_eq!(value["bytes"], 9);
+        assert_eq!(
+            value["directories"],
+            serde_json::json!([
+                {"name": "a", "files": 2, "bytes": 8},
+                {"name": "c", "files": 1, "bytes": 1},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_flatten_sample() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let options = FlattenOptions {
+            sample: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(report.sampled);
+        // Files are taken in walk order, so the same ones every time
+        assert!(root.join("file1.txt").exists());
+        assert!(root.join("file2.txt").exists());
+        assert!(root.join("level1/level2/level3/file3.txt").exists());
+
+        // A sample larger than what's left moves everything
+        let options = FlattenOptions {
+            sample: NonZeroUsize::new(5),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(!report.sampled);
+    }
+
+    #[test]
+    fn test_preview_root() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+        fs::write(root.join("level1/file0.txt"), "another").unwrap();
+
+        let options = FlattenOptions::default();
+        let summary = collect_file_summary(root, &options).unwrap();
+        let (before, after) = preview_root(root, &options, &summary).unwrap();
+        assert_eq!(
+            before.into_iter().collect::<Vec<_>>(),
+            ["file0.txt", "level1/"]
+        );
+        assert_eq!(
+            after.into_iter().collect::<Vec<_>>(),
+            [
+                "file0.txt",
+                "file0_1.txt",
+                "file1.txt",
+                "file2.txt",
+                "file3.txt",
+                "file4.txt"
+            ]
+        );
+
+        // The run names files the same way
+        flatten_from_snapshot(root, &options, &summary.snapshot).unwrap();
+        assert!(root.join("file0_1.txt").exists());
+    }
+
+    #[test]
+    fn test_count_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        assert_eq!(count_files(&root.join("level1")).unwrap(), 4);
+        assert_eq!(count_files(&root.join("level1/level2/level3")).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_flatten_continues_conflict_counter() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for name in ["report.pdf", "report_1.pdf", "report_3.pdf", "IMG_0001.jpg"] {
+            fs::write(root.join(name), "earlier run").unwrap();
+        }
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/report.pdf"), "a").unwrap();
+        fs::write(root.join("b/report_1.pdf"), "b").unwrap();
+        fs::write(root.join("b/IMG_0001.jpg"), "b").unwrap();
+
+        flatten_directory_by_traversal(root, &FlattenOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("report_4.pdf")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(root.join("report_5.pdf")).unwrap(), "b");
+        assert_eq!(
+            fs::read_to_string(root.join("IMG_0001_1.jpg")).unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn test_flatten_fill_counter_gaps() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("report.pdf"), "earlier run").unwrap();
+        fs::write(root.join("report_3.pdf"), "earlier run").unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/report.pdf"), "a").unwrap();
+
+        let options = FlattenOptions {
+            fill_counter_gaps: true,
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(fs::read_to_string(root.join("report_1.pdf")).unwrap(), "a");
+    }
+
+    #[test]
+    fn test_flatten_multiple_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // Create a file in root
+        fs::write(root.join("test.txt"), "root").unwrap();
+
+        // Create multiple subdirectories with the same filename
+        let subdir1 = root.join("subdir1");
+        fs::create_dir(&subdir1).unwrap();
+        fs::write(subdir1.join("test.txt"), "content1").unwrap();
+
+        let subdir2 = root.join("subdir2");
+        fs::create_dir(&subdir2).unwrap();
+        fs::write(subdir2.join("test.txt"), "content2").unwrap();
+
+        let moved_count = flatten_directory_by_traversal(root, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+
+        assert_eq!(moved_count, 2);
+        assert!(root.join("test.txt").exists());
+        assert!(root.join("test_1.txt").exists());
+        assert!(root.join("test_2.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_with_max_depth() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                max_depth: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Should only move files at depths 1 and 2
+        assert_eq!(moved_count, 2);
+        assert!(root.join("file1.txt").exists());
+        assert!(root.join("file2.txt").exists());
+        assert!(!root.join("file3.txt").exists());
+        assert!(!root.join("file4.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_with_include_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        let include = Some(vec!["src".to_string()]);
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                include,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Should only move files from "src" directory
+        assert_eq!(moved_count, 1);
+        assert!(root.join("main.rs").exists());
+        assert!(!root.join("readme.txt").exists());
+        assert!(!root.join("test1.rs").exists());
+    }
+
+    #[test]
+    fn test_flatten_with_exclude_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        let exclude = Some(vec!["src".to_string()]);
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                exclude,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Should move all files except from "src" directory
+        assert_eq!(moved_count, 3);
+        assert!(!root.join("main.rs").exists());
+        assert!(root.join("readme.txt").exists());
+        assert!(root.join("test1.rs").exists());
+        assert!(root.join("guide.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_empty_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let moved_count = flatten_directory_by_traversal(root, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+        assert_eq!(moved_count, 0);
+    }
+
+    // Tests for quiet mode
+    #[test]
+    fn test_flatten_quiet_mode_basic() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // Create subdirectory with files
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("test1.txt"), "content1").unwrap();
+        fs::write(subdir.join("test2.txt"), "content2").unwrap();
+
+        // Test with quiet mode enabled
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify files were moved correctly despite quiet mode
+        assert_eq!(moved_count, 2);
+        assert!(root.join("test1.txt").exists());
+        assert!(root.join("test2.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("test1.txt")).unwrap(),
+            "content1"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("test2.txt")).unwrap(),
+            "content2"
+        );
+    }
+
+    #[test]
+    fn test_flatten_quiet_mode_with_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // Create a file in root
+        fs::write(root.join("test.txt"), "root content").unwrap();
+
+        // Create subdirectory with conflicting filename
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("test.txt"), "subdir content").unwrap();
+
+        // Test with quiet mode enabled
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify conflict resolution works in quiet mode
+        assert_eq!(moved_count, 1);
+        assert_eq!(
+            fs::read_to_string(root.join("test.txt")).unwrap(),
+            "root content"
+        );
+        assert!(root.join("test_1.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("test_1.txt")).unwrap(),
+            "subdir content"
+        );
+    }
+
+    #[test]
+    fn test_flatten_quiet_mode_with_depth() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        // Test with quiet mode and max depth
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                max_depth: Some(2),
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify depth limiting works in quiet mode
+        assert_eq!(moved_count, 2);
+        assert!(root.join("file1.txt").exists());
+        assert!(root.join("file2.txt").exists());
+        assert!(!root.join("file3.txt").exists());
+        assert!(!root.join("file4.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_quiet_mode_with_include_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        let include = Some(vec!["src".to_string()]);
+        // Test with quiet mode and include filter
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                include,
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify filtering works in quiet mode
+        assert_eq!(moved_count, 1);
+        assert!(root.join("main.rs").exists());
+        assert!(!root.join("readme.txt").exists());
+        assert!(!root.join("test1.rs").exists());
+    }
+
+    #[test]
+    fn test_flatten_quiet_mode_with_exclude_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_multi_dir_structure(root).unwrap();
+
+        let exclude = Some(vec!["src".to_string()]);
+        // Test with quiet mode and exclude filter
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                exclude,
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify excluding works in quiet mode
+        assert_eq!(moved_count, 3);
+        assert!(!root.join("main.rs").exists());
+        assert!(root.join("readme.txt").exists());
+        assert!(root.join("test1.rs").exists());
+        assert!(root.join("guide.txt").exists());
+    }
+
+    #[test]
+    fn test_flatten_quiet_vs_normal_same_result() {
+        // Verify that quiet mode produces the same file operations as normal mode
+        let temp_dir1 = TempDir::new().unwrap();
+        let root1 = temp_dir1.path();
+
+        let temp_dir2 = TempDir::new().unwrap();
+        let root2 = temp_dir2.path();
+
+        // Create identical structures
+        let subdir1 = root1.join("subdir");
+        fs::create_dir(&subdir1).unwrap();
+        fs::write(subdir1.join("file1.txt"), "content1").unwrap();
+        fs::write(subdir1.join("file2.txt"), "content2").unwrap();
+
+        let subdir2 = root2.join("subdir");
+        fs::create_dir(&subdir2).unwrap();
+        fs::write(subdir2.join("file1.txt"), "content1").unwrap();
+        fs::write(subdir2.join("file2.txt"), "content2").unwrap();
+
+        // Run with normal mode
+        let count1 = flatten_directory_by_traversal(root1, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+
+        // Run with quiet mode
+        let count2 = flatten_directory_by_traversal(
+            root2,
+            &FlattenOptions {
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify same number of files moved
+        assert_eq!(count1, count2);
+        assert_eq!(count1, 2);
+
+        // Verify same files exist in both directories
+        assert!(root1.join("file1.txt").exists());
+        assert!(root1.join("file2.txt").exists());
+        assert!(root2.join("file1.txt").exists());
+        assert!(root2.join("file2.txt").exists());
+
+        // Verify same content
+        assert_eq!(
+            fs::read_to_string(root1.join("file1.txt")).unwrap(),
+            fs::read_to_string(root2.join("file1.txt")).unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(root1.join("file2.txt")).unwrap(),
+            fs::read_to_string(root2.join("file2.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_flatten_quiet_mode_outputs_errors() {
+        // This test verifies that errors are still output even in quiet mode
+        // Quiet mode should suppress informational output but NOT error messages
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // Create a subdirectory with files
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("blocked.txt"), "will fail to move").unwrap();
+        fs::write(subdir.join("success.txt"), "will move successfully").unwrap();
+
+        // Create a DIRECTORY (not a file) in root with the same name as one of the files
+        // This will cause fs::rename to fail for blocked.txt because you can't rename
+        // a file to a path that already exists as a directory
+        let blocking_dir = root.join("blocked.txt");
+        fs::create_dir(&blocking_dir).unwrap();
+
+        // Run with quiet mode enabled
+        // The function should continue despite the error and return Ok
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Verify only the successful file was moved (count should be 1, not 2)
+        assert_eq!(moved_count, 1);
+
+        // Verify success.txt was moved successfully
+        assert!(root.join("success.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("success.txt")).unwrap(),
+            "will move successfully"
+        );
 
-    fn create_multi_dir_structure(root: &Path) -> io::Result<()> {
-        // Create structure with multiple top-level directories:
-        // root/
-        //   docs/
-        //     readme.txt
-        //   src/
-        //     main.rs
-        //   tests/
-        //     test1.rs
-        //   documentation/
-        //     guide.txt
+        // Verify blocked.txt was NOT moved (still in subdirectory)
+        assert!(subdir.join("blocked.txt").exists());
 
-        let docs = root.join("docs");
-        fs::create_dir(&docs)?;
-        fs::write(docs.join("readme.txt"), "docs")?;
+        // Verify the blocking directory still exists
+        assert!(blocking_dir.exists());
+        assert!(blocking_dir.is_dir());
 
-        let src = root.join("src");
-        fs::create_dir(&src)?;
-        fs::write(src.join("main.rs"), "src")?;
+        // Note: This test verifies the error BEHAVIOR (file not moved, operation continues)
+        // The error is collected in the report, which main prints to stderr at the end
+        // of the run even in quiet mode (see test_flatten_failure_report)
+    }
 
-        let tests = root.join("tests");
-        fs::create_dir(&tests)?;
-        fs::write(tests.join("test1.rs"), "tests")?;
+    #[test]
+    fn test_flatten_failure_report() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("subdir")).unwrap();
+        fs::write(root.join("subdir/blocked.txt"), "blocked").unwrap();
+        fs::write(root.join("subdir/moved.txt"), "moved").unwrap();
+        // A directory in the way makes the move fail
+        fs::create_dir(root.join("blocked.txt")).unwrap();
+
+        let report = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                quiet: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.error_count(), 1);
+        assert_eq!(report.failed[0].path, root.join("subdir/blocked.txt"));
+        assert_eq!(report.failed[0].action, "move");
+
+        let statistics = report.statistics(Duration::ZERO);
+        assert_eq!(statistics["errors"], report.error_count());
+        assert_eq!(statistics["errors"], 1);
+        let failed = &statistics["failed"][0];
+        assert_eq!(
+            failed["path"],
+            root.join("subdir/blocked.txt").to_str().unwrap()
+        );
+        assert_eq!(failed["kind"], report.failed[0].kind.to_string());
+        assert_eq!(failed["remedy"], remedy(report.failed[0].kind));
+
+        // Locked files count as errors too, as they do for the exit status
+        let mut report = report;
+        report.locked.push(root.join("subdir/locked.txt"));
+        let statistics = report.statistics(Duration::ZERO);
+        assert_eq!(statistics["errors"], 2);
+        assert_eq!(statistics["locked"], 1);
+    }
 
-        let documentation = root.join("documentation");
-        fs::create_dir(&documentation)?;
-        fs::write(documentation.join("guide.txt"), "documentation")?;
+    // Tests for cross-device handling
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
 
-        Ok(())
+    #[test]
+    fn test_format_elapsed() {
+        assert_eq!(format_elapsed(Duration::from_millis(12_340)), "12.3s");
+        assert_eq!(format_elapsed(Duration::from_secs(245)), "4m 05s");
+        assert_eq!(format_elapsed(Duration::from_secs(3720)), "1h 02m");
     }
 
-    // Tests for starts_with_pattern
     #[test]
-    fn test_starts_with_pattern() {
-        assert!(starts_with_pattern("docs", "doc"));
-        assert!(starts_with_pattern("documentation", "doc"));
-        assert!(starts_with_pattern("DOCS", "doc"));
-        assert!(starts_with_pattern("docs", "DOC"));
-        assert!(!starts_with_pattern("src", "doc"));
-        assert!(starts_with_pattern("src", "src"));
-        assert!(starts_with_pattern("tests", "test"));
-        // Test that it's prefix matching, not substring matching
-        assert!(!starts_with_pattern("mydocs", "doc"));
-        assert!(!starts_with_pattern("src", "rc"));
+    fn test_collect_summary_same_device() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let summary = collect_file_summary(root, &FlattenOptions::default()).unwrap();
+
+        // Everything lives on the same filesystem, so nothing needs copying
+        assert_eq!(summary.cross_device_files, 0);
+        assert_eq!(summary.cross_device_bytes, 0);
     }
 
-    // Tests for should_include_top_level_dir
     #[test]
-    fn test_should_include_no_filters() {
-        assert!(should_include_top_level_dir("docs", &None, &None));
-        assert!(should_include_top_level_dir("src", &None, &None));
-        assert!(should_include_top_level_dir("tests", &None, &None));
+    fn test_check_free_space_fits() {
+        let temp_dir = TempDir::new().unwrap();
+        let summary = FileSummary {
+            file_count: 1,
+            cross_device_files: 1,
+            cross_device_bytes: 1,
+            ..Default::default()
+        };
+        assert!(check_free_space(temp_dir.path(), &summary).is_ok());
     }
 
     #[test]
-    fn test_should_include_with_include_filter() {
-        let include = Some(vec!["src".to_string()]);
-        assert!(!should_include_top_level_dir("docs", &include, &None));
-        assert!(should_include_top_level_dir("src", &include, &None));
-        assert!(!should_include_top_level_dir("tests", &include, &None));
+    fn test_check_free_space_too_large() {
+        let temp_dir = TempDir::new().unwrap();
+        let summary = FileSummary {
+            file_count: 1,
+            cross_device_files: 1,
+            cross_device_bytes: u64::MAX,
+            ..Default::default()
+        };
+        let message = check_free_space(temp_dir.path(), &summary).unwrap_err();
+        assert!(message.contains("Not enough free space"));
     }
 
     #[test]
-    fn test_should_include_with_multiple_include_filters() {
-        let include = Some(vec!["src".to_string(), "test".to_string()]);
-        assert!(!should_include_top_level_dir("docs", &include, &None));
-        assert!(should_include_top_level_dir("src", &include, &None));
-        assert!(should_include_top_level_dir("tests", &include, &None)); // matches "test"
+    fn test_copy_across_devices() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let src = root.join("src.txt");
+        let dest = root.join("dest.txt");
+        fs::write(&src, "content").unwrap();
+
+        copy_across_devices(&src, &dest, None, false).unwrap();
+
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
     }
 
     #[test]
-    fn test_should_include_with_exclude_filter() {
-        let exclude = Some(vec!["src".to_string()]);
-        assert!(should_include_top_level_dir("docs", &None, &exclude));
-        assert!(!should_include_top_level_dir("src", &None, &exclude));
-        assert!(should_include_top_level_dir("tests", &None, &exclude));
+    #[cfg(target_os = "linux")]
+    fn test_rename_noreplace_keeps_existing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&src, "new").unwrap();
+        fs::write(&dest, "existing").unwrap();
+
+        let err = rename_noreplace(&src, &dest).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&src).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "existing");
+
+        fs::remove_file(&dest).unwrap();
+        rename_noreplace(&src, &dest).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
     }
 
     #[test]
-    fn test_should_include_with_prefix_matching() {
-        let include = Some(vec!["doc".to_string()]);
-        assert!(should_include_top_level_dir("docs", &include, &None));
-        assert!(should_include_top_level_dir(
-            "documentation",
-            &include,
-            &None
-        ));
-        assert!(!should_include_top_level_dir("src", &include, &None));
-        // Test that it's prefix matching, not substring matching
-        assert!(!should_include_top_level_dir("mydocs", &include, &None));
+    fn test_flatten_with_fsync() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        let options = FlattenOptions {
+            fsync: true,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 4);
+        assert!(root.join("file4.txt").exists());
+
+        let src = root.join("src.txt");
+        let dest = root.join("dest.txt");
+        fs::write(&src, "content").unwrap();
+        copy_across_devices(&src, &dest, None, true).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
     }
 
-    // Tests for collect_file_summary
+    // Tests for --one-file-system
     #[test]
-    fn test_collect_summary_unlimited_depth() {
+    fn test_one_file_system_same_device() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
         create_test_structure(root).unwrap();
 
-        let summary = collect_file_summary(root, None, &None, &None).unwrap();
+        let options = FlattenOptions {
+            one_file_system: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
 
-        // Should count all files except file0.txt (which is in root)
+        // Nothing is mounted inside the temp dir, so nothing is skipped
         assert_eq!(summary.file_count, 4);
-        assert_eq!(summary.top_level_dirs.len(), 1);
-        assert!(summary.top_level_dirs.contains("level1"));
+        assert!(summary.skipped.is_empty());
     }
 
     #[test]
-    fn test_collect_summary_max_depth_1() {
+    fn test_explain_records_skip_reasons() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
         create_test_structure(root).unwrap();
+        fs::create_dir(root.join("extras")).unwrap();
+        fs::write(root.join("extras/bonus.txt"), "extra").unwrap();
+        fs::write(root.join("level1/notes.md"), "notes").unwrap();
+
+        let options = FlattenOptions {
+            max_depth: Some(2),
+            exclude: Some(vec!["extras".to_string()]),
+            path_globs: vec![PathGlob::parse("**/*.txt").unwrap()],
+            explain: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+
+        assert_eq!(summary.file_count, 2);
+        let mut skipped = summary.skipped.clone();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            skipped,
+            vec![
+                (root.join("extras"), SkipReason::Excluded),
+                (root.join("level1/level2/level3"), SkipReason::MaxDepth),
+                (root.join("level1/notes.md"), SkipReason::PathGlob),
+            ]
+        );
 
-        let summary = collect_file_summary(root, Some(1), &None, &None).unwrap();
+        // Without --explain only other filesystems are recorded
+        let options = FlattenOptions {
+            explain: false,
+            ..options
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert!(summary.skipped.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_explain_reports_symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/target.txt"), "target").unwrap();
+        std::os::unix::fs::symlink(root.join("sub/target.txt"), root.join("sub/link.txt")).unwrap();
+
+        let options = FlattenOptions {
+            explain: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
 
-        // Should only count file1.txt (at depth 1)
         assert_eq!(summary.file_count, 1);
+        assert_eq!(
+            summary.skipped,
+            vec![(root.join("sub/link.txt"), SkipReason::Symlink)]
+        );
     }
 
+    #[cfg(windows)]
     #[test]
-    fn test_collect_summary_max_depth_2() {
+    fn test_junctions_are_not_followed() {
         let temp_dir = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_test_structure(root).unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/mine.txt"), "mine").unwrap();
+        fs::write(outside.path().join("unrelated.txt"), "unrelated").unwrap();
+        let junction = root.join("sub/junction");
+        let status = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(&junction)
+            .arg(outside.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let options = FlattenOptions {
+            quiet: true,
+            explain: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 1);
+        assert_eq!(
+            summary.skipped,
+            vec![(junction.clone(), SkipReason::Symlink)]
+        );
 
-        let summary = collect_file_summary(root, Some(2), &None, &None).unwrap();
+        flatten_directory_by_traversal(root, &options).unwrap();
+        remove_top_level_dirs(root, &summary.top_level_dirs).unwrap();
+        assert!(root.join("mine.txt").exists());
+        assert!(!root.join("unrelated.txt").exists());
+        assert!(outside.path().join("unrelated.txt").exists());
+        // The directory holding the junction isn't empty, so it stays
+        assert!(junction.exists());
+    }
 
-        // Should count file1.txt and file2.txt (depths 1 and 2)
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_junctions() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("root");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(outside.join("deep")).unwrap();
+        fs::write(root.join("sub/mine.txt"), "mine").unwrap();
+        fs::write(outside.join("deep/linked.txt"), "linked").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink(&root, outside.join("back")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("again")).unwrap();
+
+        // Skipped by default
+        let options = FlattenOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(&root, &options).unwrap().file_count, 1);
+
+        let options = FlattenOptions {
+            quiet: true,
+            follow_junctions: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(&root, &options).unwrap();
+        // The loop back to the root and the second link aren't walked again
         assert_eq!(summary.file_count, 2);
+
+        let report = flatten_from_snapshot(&root, &options, &summary.snapshot).unwrap();
+        remove_top_level_dirs(&root, &summary.top_level_dirs).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("linked.txt").exists());
+        assert!(!outside.join("deep/linked.txt").exists());
+        assert!(root.join("again").exists());
+        assert!(root.join("sub/link").exists());
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_collect_summary_max_depth_0() {
+    fn test_special_files() {
+        use std::os::unix::fs::FileTypeExt;
+
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_test_structure(root).unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file.txt"), "file").unwrap();
+        let fifo = root.join("sub/pipe");
+        let c_fifo = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_fifo.as_ptr(), 0o644) }, 0);
+
+        // Skipped by default, and reported even without --explain
+        let options = FlattenOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 1);
+        assert_eq!(
+            summary.skipped,
+            vec![(fifo.clone(), SkipReason::SpecialFile)]
+        );
+
+        let options = FlattenOptions {
+            quiet: true,
+            special_files: SpecialFiles::Move,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(
+            fs::symlink_metadata(root.join("pipe"))
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+    }
+
+    #[test]
+    fn test_summary_detects_git_working_copies() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("project/.git")).unwrap();
+        fs::write(root.join("project/main.rs"), "fn main() {}").unwrap();
+        fs::create_dir(root.join("photos")).unwrap();
+        fs::write(root.join("photos/a.jpg"), "jpg").unwrap();
 
-        let summary = collect_file_summary(root, Some(0), &None, &None).unwrap();
+        let summary = collect_file_summary(root, &FlattenOptions::default()).unwrap();
+        assert!(summary.git_working_copies.contains(&root.join("project")));
+        assert!(!summary.git_working_copies.contains(&root.join("photos")));
 
-        // Should count no files (depth 0 means only look in root, but we don't count root files)
-        assert_eq!(summary.file_count, 0);
+        // A root inside a working copy is reported too
+        let summary = collect_file_summary(&root.join("project"), &FlattenOptions::default());
+        assert_eq!(
+            summary.unwrap().git_working_copies.first(),
+            Some(&root.join("project"))
+        );
     }
 
+    // Tests for remove_empty_dirs
     #[test]
-    fn test_collect_summary_with_include() {
+    fn test_remove_empty_dirs_nested() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
+        let nested = root.join("a").join("b").join("c");
+        fs::create_dir_all(&nested).unwrap();
 
-        let include = Some(vec!["src".to_string()]);
-        let summary = collect_file_summary(root, None, &include, &None).unwrap();
+        let root_device = device_id(&fs::metadata(root).unwrap());
+        assert!(remove_empty_dirs(&root.join("a"), root_device).unwrap());
+        assert!(!root.join("a").exists());
+    }
+
+    #[test]
+    fn test_remove_empty_dirs_keeps_remaining_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_test_structure(root).unwrap();
+
+        flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                max_depth: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let root_device = device_id(&fs::metadata(root).unwrap());
+        assert!(!remove_empty_dirs(&root.join("level1"), root_device).unwrap());
+
+        // Files beyond the depth limit are kept along with their directories
+        let level3 = root.join("level1").join("level2").join("level3");
+        assert!(level3.join("file3.txt").exists());
+        assert!(level3.join("level4").join("file4.txt").exists());
+    }
+
+    // Tests for non-UTF-8 names
+    #[cfg(unix)]
+    #[test]
+    fn test_flatten_non_utf8_directory() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let dir_name = OsStr::from_bytes(b"caf\xe9");
+        let subdir = root.join(dir_name);
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("photo.jpg"), "photo").unwrap();
 
+        let summary = collect_file_summary(root, &FlattenOptions::default()).unwrap();
         assert_eq!(summary.file_count, 1);
-        assert!(summary.top_level_dirs.contains("src"));
-        assert!(!summary.top_level_dirs.contains("docs"));
+        assert!(summary.top_level_dirs.contains(dir_name));
+
+        let moved_count = flatten_directory_by_traversal(root, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+        assert_eq!(moved_count, 1);
+        assert!(root.join("photo.jpg").exists());
     }
 
+    // Tests for Unicode normalization
     #[test]
-    fn test_collect_summary_with_prefix_include() {
+    fn test_flatten_normalization_equivalent_conflict() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
 
-        // "doc" should match both "docs" and "documentation" (prefix match)
-        let include = Some(vec!["doc".to_string()]);
-        let summary = collect_file_summary(root, None, &include, &None).unwrap();
+        let dir1 = root.join("dir1");
+        fs::create_dir(&dir1).unwrap();
+        fs::write(dir1.join("caf\u{e9}.txt"), "nfc").unwrap();
 
-        assert_eq!(summary.file_count, 2);
-        assert!(summary.top_level_dirs.contains("docs"));
-        assert!(summary.top_level_dirs.contains("documentation"));
-        assert!(!summary.top_level_dirs.contains("src"));
+        let dir2 = root.join("dir2");
+        fs::create_dir(&dir2).unwrap();
+        fs::write(dir2.join("cafe\u{301}.txt"), "nfd").unwrap();
+
+        let moved_count = flatten_directory_by_traversal(root, &FlattenOptions::default())
+            .unwrap()
+            .moved_count;
+
+        // Both files moved, and the second one was treated as a conflict
+        assert_eq!(moved_count, 2);
+        let renamed =
+            root.join("caf\u{e9}_1.txt").exists() || root.join("cafe\u{301}_1.txt").exists();
+        assert!(renamed);
     }
 
     #[test]
-    fn test_collect_summary_with_exclude() {
+    fn test_flatten_transform_before_conflict_check() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/My Photo.JPG"), "a").unwrap();
+        fs::write(root.join("b/my photo.jpg"), "b").unwrap();
+
+        let options = FlattenOptions {
+            quiet: true,
+            transform: vec![NameTransform::Lowercase, NameTransform::SpacesToUnderscores],
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+
+        assert_eq!(report.moved_count, 2);
+        assert_eq!(fs::read_to_string(root.join("my_photo.jpg")).unwrap(), "a");
+        assert_eq!(
+            fs::read_to_string(root.join("my_photo_1.jpg")).unwrap(),
+            "b"
+        );
+    }
 
-        let exclude = Some(vec!["src".to_string()]);
-        let summary = collect_file_summary(root, None, &None, &exclude).unwrap();
+    #[test]
+    fn test_flatten_normalize_output_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
 
-        assert_eq!(summary.file_count, 3);
-        assert!(!summary.top_level_dirs.contains("src"));
-        assert!(summary.top_level_dirs.contains("docs"));
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("cafe\u{301}.txt"), "nfd").unwrap();
+
+        flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                normalize: Some(UnicodeForm::Nfc),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let names: Vec<_> = fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert!(names.contains(&OsString::from("caf\u{e9}.txt")));
     }
 
+    // Tests for case-insensitive conflicts
     #[test]
-    fn test_collect_summary_empty_directory() {
+    fn test_flatten_case_insensitive_conflict() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
 
-        let summary = collect_file_summary(root, None, &None, &None).unwrap();
-        assert_eq!(summary.file_count, 0);
-        assert_eq!(summary.top_level_dirs.len(), 0);
+        let dir1 = root.join("dir1");
+        fs::create_dir(&dir1).unwrap();
+        fs::write(dir1.join("README.txt"), "upper").unwrap();
+
+        let dir2 = root.join("dir2");
+        fs::create_dir(&dir2).unwrap();
+        fs::write(dir2.join("readme.txt"), "lower").unwrap();
+
+        let moved_count = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                case_insensitive: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .moved_count;
+
+        // Whichever file moved second went through the conflict rename
+        assert_eq!(moved_count, 2);
+        assert!(root.join("README_1.txt").exists() || root.join("readme_1.txt").exists());
     }
 
-    // Tests for flatten_directory_by_traversal
+    // Tests for Windows reserved names
     #[test]
-    fn test_flatten_no_conflicts() {
+    fn test_flatten_reserved_names() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
 
-        // Create subdirectory with files
         let subdir = root.join("subdir");
         fs::create_dir(&subdir).unwrap();
-        fs::write(subdir.join("test1.txt"), "content1").unwrap();
-        fs::write(subdir.join("test2.txt"), "content2").unwrap();
+        fs::write(subdir.join("aux.c"), "source").unwrap();
+
+        flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                reserved_suffix: Some("_".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(root.join("aux_.c").exists());
+        assert!(!root.join("aux.c").exists());
+    }
 
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, false).unwrap();
+    // Tests for --sanitize
+    #[test]
+    fn test_flatten_sanitize_reports_renames() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
 
-        assert_eq!(moved_count, 2);
-        assert!(root.join("test1.txt").exists());
-        assert!(root.join("test2.txt").exists());
-        assert_eq!(
-            fs::read_to_string(root.join("test1.txt")).unwrap(),
-            "content1"
-        );
-        assert_eq!(
-            fs::read_to_string(root.join("test2.txt")).unwrap(),
-            "content2"
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("10:30 notes?.txt"), "notes").unwrap();
+        fs::write(subdir.join("plain.txt"), "plain").unwrap();
+
+        let report = flatten_directory_by_traversal(
+            root,
+            &FlattenOptions {
+                sanitize: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("10_30 notes_.txt").exists());
+        assert!(root.join("plain.txt").exists());
+
+        // Only the renamed file is reported
+        assert_eq!(report.sanitized.len(), 1);
+        assert_eq!(report.sanitized[0].0, subdir.join("10:30 notes?.txt"));
+        assert_eq!(report.sanitized[0].1, "10_30 notes_.txt");
+    }
+
+    // Tests for name length limits
+    #[test]
+    fn test_flatten_conflict_on_max_length_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        // A name exactly at the limit that conflicts with a file in the root
+        let name = format!("{}.txt", "a".repeat(MAX_NAME_LEN - 4));
+        fs::write(root.join(&name), "root").unwrap();
+
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join(&name), "subdir").unwrap();
+
+        let report = flatten_directory_by_traversal(root, &FlattenOptions::default()).unwrap();
+
+        // The conflicting copy was shortened instead of failing to move
+        assert_eq!(report.moved_count, 1);
+        assert!(!subdir.join(&name).exists());
+        assert_eq!(fs::read_dir(root).unwrap().count(), 3);
+    }
+
+    // Tests for extended-length paths
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_extended_length_path_unchanged_off_windows() {
+        let long = PathBuf::from("/").join("a".repeat(300)).join("file.txt");
+        assert_eq!(extended_length_path(&long), long.as_path());
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_extended_length_path_windows() {
+        let short = Path::new(r"C:\data\file.txt");
+        assert_eq!(extended_length_path(short), short);
+
+        let long = PathBuf::from(r"C:\data")
+            .join("a".repeat(300))
+            .join("file.txt");
+        let extended = extended_length_path(&long);
+        assert!(extended.to_str().unwrap().starts_with(r"\\?\C:\data\"));
+        assert_eq!(display_path(&extended), long.display().to_string());
+
+        let unc = PathBuf::from(r"\\server\share").join("a".repeat(300));
+        let extended = extended_length_path(&unc);
+        assert!(
+            extended
+                .to_str()
+                .unwrap()
+                .starts_with(r"\\?\UNC\server\share\")
         );
+        assert_eq!(display_path(&extended), unc.display().to_string());
+    }
+
+    // Tests for modification-time filters
+    fn set_mtime(path: &Path, time: SystemTime) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
     }
 
     #[test]
-    fn test_flatten_with_conflicts() {
+    fn test_collect_summary_mtime_filters() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
 
-        // Create a file in root
-        fs::write(root.join("test.txt"), "root content").unwrap();
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("old.txt"), "old").unwrap();
+        fs::write(subdir.join("new.txt"), "new").unwrap();
+        set_mtime(
+            &subdir.join("old.txt"),
+            dates::parse_date("2020-01-01").unwrap(),
+        );
+
+        let cutoff = dates::parse_date("2021-01-01").unwrap();
+
+        let newer = FlattenOptions {
+            newer_than: Some(cutoff),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &newer).unwrap().file_count, 1);
+
+        let older = FlattenOptions {
+            older_than: Some(cutoff),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &older).unwrap().file_count, 1);
+
+        // An empty window matches nothing
+        let window = FlattenOptions {
+            newer_than: Some(cutoff),
+            older_than: Some(cutoff),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &window).unwrap().file_count, 0);
+    }
+
+    #[test]
+    fn test_flatten_older_than() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
 
-        // Create subdirectory with conflicting filename
         let subdir = root.join("subdir");
         fs::create_dir(&subdir).unwrap();
-        fs::write(subdir.join("test.txt"), "subdir content").unwrap();
+        fs::write(subdir.join("old.txt"), "old").unwrap();
+        fs::write(subdir.join("new.txt"), "new").unwrap();
+        set_mtime(
+            &subdir.join("old.txt"),
+            dates::parse_date("2020-01-01").unwrap(),
+        );
 
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, false).unwrap();
+        let options = FlattenOptions {
+            older_than: Some(dates::parse_time_spec("1d").unwrap()),
+            ..Default::default()
+        };
+        let moved_count = flatten_directory_by_traversal(root, &options)
+            .unwrap()
+            .moved_count;
 
         assert_eq!(moved_count, 1);
-        // Original file should remain unchanged
-        assert_eq!(
-            fs::read_to_string(root.join("test.txt")).unwrap(),
-            "root content"
+        assert!(root.join("old.txt").exists());
+        assert!(subdir.join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_settle_skips_recent_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("downloading.iso"), "partial").unwrap();
+        fs::write(subdir.join("done.iso"), "complete").unwrap();
+        set_mtime(
+            &subdir.join("done.iso"),
+            SystemTime::now() - Duration::from_secs(3600),
         );
 
-        // Conflicting file should be renamed
-        assert!(root.join("test_1.txt").exists());
-        assert_eq!(
-            fs::read_to_string(root.join("test_1.txt")).unwrap(),
-            "subdir content"
-        );
+        let options = FlattenOptions {
+            settle: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &options).unwrap().file_count, 1);
+
+        let moved_count = flatten_directory_by_traversal(root, &options)
+            .unwrap()
+            .moved_count;
+        assert_eq!(moved_count, 1);
+        assert!(root.join("done.iso").exists());
+        assert!(subdir.join("downloading.iso").exists());
+    }
+
+    // Tests for --type
+    #[test]
+    fn test_collect_summary_type_filter() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("photo.jpg"), "").unwrap();
+        fs::write(subdir.join("song.mp3"), "").unwrap();
+        fs::write(subdir.join("main.rs"), "").unwrap();
+        fs::write(subdir.join("IMG_0002"), b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let images = FlattenOptions {
+            types: Some(vec![FileClass::Image]),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &images).unwrap().file_count, 1);
+
+        let media = FlattenOptions {
+            types: Some(vec![FileClass::Image, FileClass::Audio]),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &media).unwrap().file_count, 2);
+
+        // Sniffing also picks up the extensionless PNG
+        let sniffed = FlattenOptions {
+            types: Some(vec![FileClass::Image]),
+            sniff: true,
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &sniffed).unwrap().file_count, 2);
+    }
+
+    #[test]
+    fn test_collect_summary_only_text() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("README"), "# Readme\n").unwrap();
+        fs::write(subdir.join("notes.md"), "- milk\n").unwrap();
+        fs::write(subdir.join("photo.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+
+        let count = |kind| {
+            let options = FlattenOptions {
+                only: Some(kind),
+                ..Default::default()
+            };
+            collect_file_summary(root, &options).unwrap().file_count
+        };
+        assert_eq!(count(ContentKind::Text), 2);
+        assert_eq!(count(ContentKind::Binary), 1);
+
+        // An unreadable file is skipped and reported instead of ending the scan
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            // Permissions don't stop root
+            // SAFETY: geteuid has no preconditions
+            if unsafe { libc::geteuid() } == 0 {
+                return;
+            }
+            let secret = subdir.join("secret.txt");
+            fs::write(&secret, "secret").unwrap();
+            fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+            let options = FlattenOptions {
+                only: Some(ContentKind::Text),
+                ..Default::default()
+            };
+            let summary = collect_file_summary(root, &options).unwrap();
+            fs::set_permissions(&secret, fs::Permissions::from_mode(0o644)).unwrap();
+            assert_eq!(summary.file_count, 2);
+            assert!(summary.skipped.contains(&(secret, SkipReason::Unreadable)));
+        }
     }
 
+    // Tests for owner and permission filters
+    #[cfg(unix)]
     #[test]
-    fn test_flatten_multiple_conflicts() {
+    fn test_collect_summary_owned_by() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
+        create_test_structure(root).unwrap();
 
-        // Create a file in root
-        fs::write(root.join("test.txt"), "root").unwrap();
+        // SAFETY: geteuid has no preconditions
+        let euid = unsafe { libc::geteuid() };
 
-        // Create multiple subdirectories with the same filename
-        let subdir1 = root.join("subdir1");
-        fs::create_dir(&subdir1).unwrap();
-        fs::write(subdir1.join("test.txt"), "content1").unwrap();
+        let mine = FlattenOptions {
+            owned_by: Some(euid),
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &mine).unwrap().file_count, 4);
 
-        let subdir2 = root.join("subdir2");
-        fs::create_dir(&subdir2).unwrap();
-        fs::write(subdir2.join("test.txt"), "content2").unwrap();
+        let someone_else = FlattenOptions {
+            owned_by: Some(euid.wrapping_add(12345)),
+            ..Default::default()
+        };
+        assert_eq!(
+            collect_file_summary(root, &someone_else)
+                .unwrap()
+                .file_count,
+            0
+        );
+    }
 
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, false).unwrap();
+    #[cfg(unix)]
+    #[test]
+    fn test_check_writable() {
+        use std::os::unix::fs::PermissionsExt;
 
-        assert_eq!(moved_count, 2);
-        assert!(root.join("test.txt").exists());
-        assert!(root.join("test_1.txt").exists());
-        assert!(root.join("test_2.txt").exists());
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        assert!(check_writable(root).is_ok());
+        assert_eq!(fs::read_dir(root).unwrap().count(), 0);
+
+        // Permissions don't stop root
+        // SAFETY: geteuid has no preconditions
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        fs::set_permissions(root, fs::Permissions::from_mode(0o555)).unwrap();
+        let result = check_writable(root);
+        fs::set_permissions(root, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(result.unwrap_err().contains("permission"));
     }
 
     #[test]
-    fn test_flatten_with_max_depth() {
+    fn test_collect_summary_writable_only() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
         create_test_structure(root).unwrap();
 
-        let moved_count =
-            flatten_directory_by_traversal(root, Some(2), &None, &None, false).unwrap();
-
-        // Should only move files at depths 1 and 2
-        assert_eq!(moved_count, 2);
-        assert!(root.join("file1.txt").exists());
-        assert!(root.join("file2.txt").exists());
-        assert!(!root.join("file3.txt").exists());
-        assert!(!root.join("file4.txt").exists());
+        let options = FlattenOptions {
+            writable_only: true,
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &options).unwrap().file_count, 4);
     }
 
     #[test]
-    fn test_flatten_with_include_filter() {
+    fn test_flatten_prune_empty_files() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
-
-        let include = Some(vec!["src".to_string()]);
-        let moved_count =
-            flatten_directory_by_traversal(root, None, &include, &None, false).unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/empty.txt"), "").unwrap();
+        fs::write(root.join("a/notes.txt"), "notes").unwrap();
+
+        let options = FlattenOptions {
+            prune_empty_files: true,
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!((summary.file_count, summary.empty_files), (2, 1));
+
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!((report.moved_count, report.pruned_empty), (1, 1));
+        assert!(root.join("notes.txt").exists());
+        assert!(!root.join("empty.txt").exists());
+        assert!(!root.join("a/empty.txt").exists());
+    }
 
-        // Should only move files from "src" directory
-        assert_eq!(moved_count, 1);
-        assert!(root.join("main.rs").exists());
-        assert!(!root.join("readme.txt").exists());
-        assert!(!root.join("test1.rs").exists());
+    #[test]
+    fn test_flatten_skips_partial_downloads() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let downloads = root.join("downloads");
+        fs::create_dir_all(downloads.join("song.mp3.download")).unwrap();
+        fs::write(downloads.join("movie.mkv"), "done").unwrap();
+        fs::write(downloads.join("movie2.mkv.part"), "half").unwrap();
+        fs::write(downloads.join("song.mp3.download/song.mp3"), "half").unwrap();
+
+        let report = flatten_directory_by_traversal(root, &FlattenOptions::default()).unwrap();
+        assert_eq!(report.moved_count, 1);
+        assert!(root.join("movie.mkv").exists());
+        assert!(downloads.join("movie2.mkv.part").exists());
+        assert!(downloads.join("song.mp3.download/song.mp3").exists());
+
+        let options = FlattenOptions {
+            include_partial: true,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("movie2.mkv.part").exists());
+        assert!(root.join("song.mp3").exists());
     }
 
+    // Tests for --path-glob
     #[test]
-    fn test_flatten_with_exclude_filter() {
+    fn test_flatten_path_glob_excludes_nested_dir() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
 
-        let exclude = Some(vec!["src".to_string()]);
-        let moved_count =
-            flatten_directory_by_traversal(root, None, &None, &exclude, false).unwrap();
+        let movie = root.join("movie");
+        let extras = movie.join("extras");
+        fs::create_dir_all(&extras).unwrap();
+        fs::write(movie.join("movie.mkv"), "movie").unwrap();
+        fs::write(extras.join("trailer.mkv"), "trailer").unwrap();
+
+        let options = FlattenOptions {
+            path_globs: vec![PathGlob::parse("!**/extras/**").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(collect_file_summary(root, &options).unwrap().file_count, 1);
+
+        let moved_count = flatten_directory_by_traversal(root, &options)
+            .unwrap()
+            .moved_count;
+        assert_eq!(moved_count, 1);
+        assert!(root.join("movie.mkv").exists());
+        assert!(extras.join("trailer.mkv").exists());
+    }
 
-        // Should move all files except from "src" directory
-        assert_eq!(moved_count, 3);
-        assert!(!root.join("main.rs").exists());
-        assert!(root.join("readme.txt").exists());
-        assert!(root.join("test1.rs").exists());
-        assert!(root.join("guide.txt").exists());
+    // Tests for --only-dirs
+    fn create_only_dirs_structure(root: &Path) {
+        fs::create_dir_all(root.join("show/Season 1/extras")).unwrap();
+        fs::write(root.join("show/notes.txt"), "").unwrap();
+        fs::write(root.join("show/Season 1/e01.mkv"), "").unwrap();
+        fs::write(root.join("show/Season 1/extras/blooper.mkv"), "").unwrap();
     }
 
     #[test]
-    fn test_flatten_empty_directory() {
+    fn test_flatten_only_dirs() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
+        create_only_dirs_structure(root);
+
+        let options = FlattenOptions {
+            only_dirs: Some(DirGlob::parse("Season *").unwrap()),
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("e01.mkv").exists());
+        assert!(root.join("blooper.mkv").exists());
+        assert!(root.join("show/notes.txt").exists());
+    }
 
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, false).unwrap();
-        assert_eq!(moved_count, 0);
+    #[test]
+    fn test_flatten_only_dirs_to_parent() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        create_only_dirs_structure(root);
+
+        let options = FlattenOptions {
+            only_dirs: Some(DirGlob::parse("Season *").unwrap()),
+            only_dirs_to_parent: true,
+            ..Default::default()
+        };
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 2);
+        assert!(root.join("show/e01.mkv").exists());
+        assert!(root.join("show/blooper.mkv").exists());
+        assert!(!root.join("e01.mkv").exists());
     }
 
-    // Tests for quiet mode
+    // Tests for --exclude-dir
     #[test]
-    fn test_flatten_quiet_mode_basic() {
+    fn test_flatten_exclude_dir_at_any_depth() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
 
-        // Create subdirectory with files
-        let subdir = root.join("subdir");
-        fs::create_dir(&subdir).unwrap();
-        fs::write(subdir.join("test1.txt"), "content1").unwrap();
-        fs::write(subdir.join("test2.txt"), "content2").unwrap();
+        let project = root.join("project");
+        let cache = project.join("build").join("Cache");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(project.join("main.rs"), "main").unwrap();
+        fs::write(cache.join("blob.bin"), "cached").unwrap();
+
+        let top_level_cache = root.join("cache");
+        fs::create_dir(&top_level_cache).unwrap();
+        fs::write(top_level_cache.join("other.bin"), "cached").unwrap();
+
+        let options = FlattenOptions {
+            exclude_dirs: Some(vec!["cache".to_string()]),
+            ..Default::default()
+        };
+        let summary = collect_file_summary(root, &options).unwrap();
+        assert_eq!(summary.file_count, 1);
 
-        // Test with quiet mode enabled
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, true).unwrap();
+        let moved_count = flatten_directory_by_traversal(root, &options)
+            .unwrap()
+            .moved_count;
+        assert_eq!(moved_count, 1);
+        assert!(root.join("main.rs").exists());
+        assert!(cache.join("blob.bin").exists());
+        assert!(top_level_cache.join("other.bin").exists());
+    }
 
-        // Verify files were moved correctly despite quiet mode
-        assert_eq!(moved_count, 2);
-        assert!(root.join("test1.txt").exists());
-        assert!(root.join("test2.txt").exists());
-        assert_eq!(
-            fs::read_to_string(root.join("test1.txt")).unwrap(),
-            "content1"
-        );
-        assert_eq!(
-            fs::read_to_string(root.join("test2.txt")).unwrap(),
-            "content2"
-        );
+    #[test]
+    fn test_should_include_with_include_and_exclude() {
+        let include = Some(vec!["season_".to_string()]);
+        let exclude = Some(vec!["season_00".to_string()]);
+        let matching = PatternMatch::default();
+
+        assert!(should_include_top_level_dir(
+            "season_01",
+            &include,
+            &exclude,
+            matching
+        ));
+        assert!(!should_include_top_level_dir(
+            "season_00",
+            &include,
+            &exclude,
+            matching
+        ));
+        assert!(!should_include_top_level_dir(
+            "extras", &include, &exclude, matching
+        ));
     }
 
     #[test]
-    fn test_flatten_quiet_mode_with_conflicts() {
+    fn test_select_top_level_dirs() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
+        for dir in ["season_00", "season_01", "extras"] {
+            fs::create_dir(root.join(dir)).unwrap();
+        }
+        fs::write(root.join("season_notes.txt"), "root file").unwrap();
 
-        // Create a file in root
-        fs::write(root.join("test.txt"), "root content").unwrap();
-
-        // Create subdirectory with conflicting filename
-        let subdir = root.join("subdir");
-        fs::create_dir(&subdir).unwrap();
-        fs::write(subdir.join("test.txt"), "subdir content").unwrap();
-
-        // Test with quiet mode enabled
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, true).unwrap();
+        let options = FlattenOptions {
+            include: Some(vec!["season_".to_string()]),
+            exclude: Some(vec!["season_00".to_string()]),
+            ..Default::default()
+        };
+        let dirs = select_top_level_dirs(root, &options).unwrap();
 
-        // Verify conflict resolution works in quiet mode
-        assert_eq!(moved_count, 1);
         assert_eq!(
-            fs::read_to_string(root.join("test.txt")).unwrap(),
-            "root content"
-        );
-        assert!(root.join("test_1.txt").exists());
-        assert_eq!(
-            fs::read_to_string(root.join("test_1.txt")).unwrap(),
-            "subdir content"
+            dirs,
+            vec![
+                (OsString::from("extras"), false),
+                (OsString::from("season_00"), false),
+                (OsString::from("season_01"), true),
+            ]
         );
     }
 
     #[test]
-    fn test_flatten_quiet_mode_with_depth() {
+    fn test_max_dir_files() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_test_structure(root).unwrap();
+        fs::create_dir_all(root.join("wrapper")).unwrap();
+        fs::create_dir_all(root.join("album/disc1")).unwrap();
+        fs::write(root.join("wrapper/setup.exe"), "").unwrap();
+        fs::write(root.join("album/cover.jpg"), "").unwrap();
+        fs::write(root.join("album/disc1/01.flac"), "").unwrap();
+
+        let options = FlattenOptions {
+            max_dir_files: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            select_top_level_dirs(root, &options).unwrap(),
+            vec![
+                (OsString::from("album"), false),
+                (OsString::from("wrapper"), true),
+            ]
+        );
+        let report = flatten_directory_by_traversal(root, &options).unwrap();
+        assert_eq!(report.moved_count, 1);
+        assert!(root.join("setup.exe").exists());
+        assert!(root.join("album/disc1/01.flac").exists());
+    }
 
-        // Test with quiet mode and max depth
-        let moved_count =
-            flatten_directory_by_traversal(root, Some(2), &None, &None, true).unwrap();
+    #[test]
+    fn test_cli_test_pattern_subcommand() {
+        let cli = Cli::try_parse_from([
+            "rflatten",
+            "test-pattern",
+            "/some/dir",
+            "--include",
+            "doc",
+            "--files",
+        ])
+        .unwrap();
+        let Some(Command::TestPattern {
+            directory,
+            files,
+            filters,
+        }) = cli.command
+        else {
+            panic!("expected the test-pattern subcommand");
+        };
+        assert_eq!(directory, PathBuf::from("/some/dir"));
+        assert!(files);
+        assert_eq!(filters.include, Some(vec!["doc".to_string()]));
+
+        let cli = Cli::try_parse_from(["rflatten", "/some/dir", "-i", "doc"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.directory, Some(PathBuf::from("/some/dir")));
+    }
 
-        // Verify depth limiting works in quiet mode
-        assert_eq!(moved_count, 2);
-        assert!(root.join("file1.txt").exists());
-        assert!(root.join("file2.txt").exists());
-        assert!(!root.join("file3.txt").exists());
-        assert!(!root.join("file4.txt").exists());
+    #[test]
+    fn test_depth_from() {
+        let depth = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["rflatten", "/some/dir"].iter().chain(args)).unwrap();
+            FlattenOptions::from(&cli.filters).max_depth
+        };
+        assert_eq!(depth(&["--depth", "2"]), Some(2));
+        assert_eq!(depth(&["--depth", "2", "--depth-from", "root"]), Some(2));
+        assert_eq!(depth(&["--depth", "2", "--depth-from", "topdir"]), Some(3));
+        assert_eq!(depth(&[]), None);
+        assert!(Cli::try_parse_from(["rflatten", "/some/dir", "--depth-from", "topdir"]).is_err());
     }
 
     #[test]
-    fn test_flatten_quiet_mode_with_include_filter() {
+    fn test_flatten_conflict_start_and_pad() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
+        fs::write(root.join("a.txt"), "root").unwrap();
+        for dir in ["x", "y"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("a.txt"), dir).unwrap();
+        }
 
-        let include = Some(vec!["src".to_string()]);
-        // Test with quiet mode and include filter
-        let moved_count =
-            flatten_directory_by_traversal(root, None, &include, &None, true).unwrap();
+        let cli = Cli::try_parse_from([
+            "rflatten",
+            "--conflict-start",
+            "0",
+            "--conflict-pad",
+            "3",
+            root.to_str().unwrap(),
+        ])
+        .unwrap();
+        flatten_directory_by_traversal(root, &FlattenOptions::from(&cli)).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a_000.txt")).unwrap(), "x");
+        assert_eq!(fs::read_to_string(root.join("a_001.txt")).unwrap(), "y");
+    }
 
-        // Verify filtering works in quiet mode
-        assert_eq!(moved_count, 1);
-        assert!(root.join("main.rs").exists());
-        assert!(!root.join("readme.txt").exists());
-        assert!(!root.join("test1.rs").exists());
+    #[test]
+    fn test_special_files_move_conflicts_with_reading_contents() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["rflatten", "/some/dir", "--special-files", "move"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert!(parse(&[]).is_ok());
+        // Opening a FIFO to read it would block the walk
+        assert!(parse(&["--only", "text"]).is_err());
+        assert!(parse(&["--type", "image", "--sniff"]).is_err());
     }
 
     #[test]
-    fn test_flatten_quiet_mode_with_exclude_filter() {
+    fn test_dangerous_root() {
         let temp_dir = TempDir::new().unwrap();
-        let root = temp_dir.path();
-        create_multi_dir_structure(root).unwrap();
-
-        let exclude = Some(vec!["src".to_string()]);
-        // Test with quiet mode and exclude filter
-        let moved_count =
-            flatten_directory_by_traversal(root, None, &None, &exclude, true).unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        let protected = root.join("protected");
+        fs::create_dir(&protected).unwrap();
 
-        // Verify excluding works in quiet mode
-        assert_eq!(moved_count, 3);
-        assert!(!root.join("main.rs").exists());
-        assert!(root.join("readme.txt").exists());
-        assert!(root.join("test1.rs").exists());
-        assert!(root.join("guide.txt").exists());
+        let filesystem_root = root.ancestors().last().unwrap();
+        let protected = [(protected, "protected")];
+        assert!(dangerous_root(filesystem_root, &[]).is_some());
+        assert_eq!(
+            dangerous_root(&protected[0].0, &protected),
+            Some("protected")
+        );
+        assert!(dangerous_root(&root, &protected).is_none());
+        assert!(dangerous_root(&root.join("other"), &protected).is_none());
+
+        // Paths as given with --no-canonicalize are resolved for the check
+        // A directory right under the filesystem root, like /tmp
+        let top = root
+            .ancestors()
+            .find(|dir| dir.parent() == Some(filesystem_root))
+            .unwrap();
+        assert!(dangerous_root(&top.join(".."), &[]).is_some());
+        assert_eq!(
+            dangerous_root(&protected[0].0.join("../protected"), &protected),
+            Some("protected")
+        );
+        #[cfg(unix)]
+        {
+            let link = root.join("home");
+            std::os::unix::fs::symlink(&protected[0].0, &link).unwrap();
+            assert_eq!(dangerous_root(&link, &protected), Some("protected"));
+        }
     }
 
     #[test]
-    fn test_flatten_quiet_vs_normal_same_result() {
-        // Verify that quiet mode produces the same file operations as normal mode
-        let temp_dir1 = TempDir::new().unwrap();
-        let root1 = temp_dir1.path();
-
-        let temp_dir2 = TempDir::new().unwrap();
-        let root2 = temp_dir2.path();
+    fn test_resolve_new_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
 
-        // Create identical structures
-        let subdir1 = root1.join("subdir");
-        fs::create_dir(&subdir1).unwrap();
-        fs::write(subdir1.join("file1.txt"), "content1").unwrap();
-        fs::write(subdir1.join("file2.txt"), "content2").unwrap();
+        let (dest, existing) = resolve_new_directory(&root.join("a/b")).unwrap();
+        assert_eq!(dest, root.join("a/b"));
+        assert_eq!(existing, root);
+        // Nothing is created until the caller decides to
+        assert!(!root.join("a").exists());
 
-        let subdir2 = root2.join("subdir");
-        fs::create_dir(&subdir2).unwrap();
-        fs::write(subdir2.join("file1.txt"), "content1").unwrap();
-        fs::write(subdir2.join("file2.txt"), "content2").unwrap();
+        assert_eq!(
+            resolve_new_directory(&root).unwrap(),
+            (root.clone(), root.clone())
+        );
+    }
 
-        // Run with normal mode
-        let count1 = flatten_directory_by_traversal(root1, None, &None, &None, false).unwrap();
+    #[test]
+    #[cfg(unix)]
+    fn test_resolve_directory_without_canonicalize() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("target/sub")).unwrap();
+        fs::write(root.join("target/sub/file.txt"), "content").unwrap();
+        let link = root.join("link");
+        std::os::unix::fs::symlink(root.join("target"), &link).unwrap();
+
+        assert_eq!(resolve_directory(&link, true).unwrap(), root.join("target"));
+        let resolved = resolve_directory(&link, false).unwrap();
+        assert_eq!(resolved, link);
+
+        // Flattening through the symlink moves files into the directory it points to
+        let report = flatten_directory_by_traversal(&resolved, &FlattenOptions::default()).unwrap();
+        assert_eq!(report.moved_count, 1);
+        assert!(root.join("target/file.txt").exists());
+    }
 
-        // Run with quiet mode
-        let count2 = flatten_directory_by_traversal(root2, None, &None, &None, true).unwrap();
+    #[test]
+    fn test_manifest_replay_on_mirror() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.path().join("original");
+        let mirror = temp_dir.path().join("mirror");
+        for root in [&original, &mirror] {
+            fs::create_dir_all(root.join("a/b")).unwrap();
+            fs::write(root.join("test.txt"), "root").unwrap();
+            fs::write(root.join("a/test.txt"), "a").unwrap();
+            fs::write(root.join("a/b/photo.jpg"), "b").unwrap();
+        }
 
-        // Verify same number of files moved
-        assert_eq!(count1, count2);
-        assert_eq!(count1, 2);
+        let manifest_path = temp_dir.path().join("manifest.json");
+        let options = FlattenOptions {
+            manifest: Some(manifest_path.clone()),
+            ..Default::default()
+        };
+        flatten_directory_by_traversal(&original, &options).unwrap();
 
-        // Verify same files exist in both directories
-        assert!(root1.join("file1.txt").exists());
-        assert!(root1.join("file2.txt").exists());
-        assert!(root2.join("file1.txt").exists());
-        assert!(root2.join("file2.txt").exists());
+        let manifest = Manifest::read(&manifest_path).unwrap();
+        assert_eq!(manifest.moves.len(), 2);
+        assert_eq!(replay_manifest(&mirror, &manifest, true).unwrap(), 2);
 
-        // Verify same content
-        assert_eq!(
-            fs::read_to_string(root1.join("file1.txt")).unwrap(),
-            fs::read_to_string(root2.join("file1.txt")).unwrap()
-        );
-        assert_eq!(
-            fs::read_to_string(root1.join("file2.txt")).unwrap(),
-            fs::read_to_string(root2.join("file2.txt")).unwrap()
-        );
+        assert_eq!(fs::read_to_string(mirror.join("test_1.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(mirror.join("photo.jpg")).unwrap(), "b");
+        assert!(!mirror.join("a").exists());
     }
 
     #[test]
-    fn test_flatten_quiet_mode_outputs_errors() {
-        // This test verifies that errors are still output even in quiet mode
-        // Quiet mode should suppress informational output but NOT error messages
+    fn test_replay_requires_every_source() {
         let temp_dir = TempDir::new().unwrap();
         let root = temp_dir.path();
+        fs::create_dir(root.join("a")).unwrap();
+        fs::write(root.join("a/present.txt"), "present").unwrap();
 
-        // Create a subdirectory with files
-        let subdir = root.join("subdir");
-        fs::create_dir(&subdir).unwrap();
-        fs::write(subdir.join("blocked.txt"), "will fail to move").unwrap();
-        fs::write(subdir.join("success.txt"), "will move successfully").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.record(Path::new("a/present.txt"), Path::new("present.txt"));
+        manifest.record(Path::new("a/missing.txt"), Path::new("missing.txt"));
 
-        // Create a DIRECTORY (not a file) in root with the same name as one of the files
-        // This will cause fs::rename to fail for blocked.txt because you can't rename
-        // a file to a path that already exists as a directory
-        let blocking_dir = root.join("blocked.txt");
-        fs::create_dir(&blocking_dir).unwrap();
+        assert!(replay_manifest(root, &manifest, true).is_err());
+        // Nothing is moved when the tree doesn't match
+        assert!(root.join("a/present.txt").exists());
+    }
 
-        // Run with quiet mode enabled
-        // The function should continue despite the error and return Ok
-        let moved_count = flatten_directory_by_traversal(root, None, &None, &None, true).unwrap();
+    // Tests for locked file detection
+    #[test]
+    fn test_is_locked_error_ignores_other_errors() {
+        let error = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(!is_locked_error(&error));
+        assert!(!is_locked_error(&io::Error::from(io::ErrorKind::NotFound)));
+    }
 
-        // Verify only the successful file was moved (count should be 1, not 2)
-        assert_eq!(moved_count, 1);
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_is_locked_error_sharing_violation() {
+        assert!(is_locked_error(&io::Error::from_raw_os_error(32)));
+        assert!(is_locked_error(&io::Error::from_raw_os_error(33)));
+        assert!(!is_locked_error(&io::Error::from_raw_os_error(5)));
+    }
 
-        // Verify success.txt was moved successfully
-        assert!(root.join("success.txt").exists());
-        assert_eq!(
-            fs::read_to_string(root.join("success.txt")).unwrap(),
-            "will move successfully"
-        );
+    #[test]
+    fn test_flatten_report_no_locked_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
 
-        // Verify blocked.txt was NOT moved (still in subdirectory)
-        assert!(subdir.join("blocked.txt").exists());
+        let subdir = root.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        fs::write(subdir.join("test.txt"), "content").unwrap();
 
-        // Verify the blocking directory still exists
-        assert!(blocking_dir.exists());
-        assert!(blocking_dir.is_dir());
+        let report = flatten_directory_by_traversal(root, &FlattenOptions::default()).unwrap();
 
-        // Note: This test verifies the error BEHAVIOR (file not moved, operation continues)
-        // The actual error message "Error moving..." is written to stderr via eprintln!
-        // In a real run with quiet mode, you would see:
-        //   stderr: "Error moving /path/to/subdir/blocked.txt: ..."
-        //   stdout: (empty - no "Moved:" messages due to quiet mode)
-        // To verify stderr output, run: cargo test test_flatten_quiet_mode_outputs_errors -- --nocapture
+        assert_eq!(report.moved_count, 1);
+        assert!(report.locked.is_empty());
     }
 }
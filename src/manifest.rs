@@ -0,0 +1,162 @@
+//! Manifests of the moves made by a run, so they can be replayed on another tree
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// A file moved from `source` to `dest`, both relative to the root and separated by `/`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Move {
+    pub source: String,
+    pub dest: String,
+}
+
+/// The moves made by a run, in the order they happened
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub moves: Vec<Move>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            moves: Vec::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Record a move; both paths are relative to the root
+    pub fn record(&mut self, source: &Path, dest: &Path) {
+        self.moves.push(Move {
+            source: to_manifest_path(source),
+            dest: to_manifest_path(dest),
+        });
+    }
+
+    /// The moves that undo this manifest's, in reverse order
+    pub fn inverted(&self) -> Self {
+        Manifest {
+            version: self.version,
+            moves: self
+                .moves
+                .iter()
+                .rev()
+                .map(|m| Move {
+                    source: m.dest.clone(),
+                    dest: m.source.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, json + "\n")
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let manifest: Manifest = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported manifest version {}", manifest.version),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Resolve every move against `root`. Fails if a path is absolute or leaves the root.
+    pub fn resolve(&self, root: &Path) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        self.moves
+            .iter()
+            .map(|m| {
+                Ok((
+                    root.join(from_manifest_path(&m.source)?),
+                    root.join(from_manifest_path(&m.dest)?),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Manifest paths use `/` on every platform. Names that aren't valid UTF-8 are
+/// stored lossily.
+fn to_manifest_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn from_manifest_path(s: &str) -> io::Result<PathBuf> {
+    // Check each segment, since collecting into a PathBuf drops empty ones
+    let valid = s.split('/').all(|segment| {
+        let mut components = Path::new(segment).components();
+        matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+    });
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("manifest path '{}' is not inside the root", s),
+        ));
+    }
+    Ok(s.split('/').collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_and_read() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("manifest.json");
+
+        let mut manifest = Manifest::default();
+        manifest.record(&Path::new("a").join("b").join("x.txt"), Path::new("x.txt"));
+        manifest.write(&path).unwrap();
+
+        let read = Manifest::read(&path).unwrap();
+        assert_eq!(read, manifest);
+        assert_eq!(read.moves[0].source, "a/b/x.txt");
+    }
+
+    #[test]
+    fn test_resolve_rejects_paths_outside_root() {
+        let root = Path::new("/mirror");
+        let manifest = |source: &str| Manifest {
+            version: MANIFEST_VERSION,
+            moves: vec![Move {
+                source: source.to_string(),
+                dest: "x.txt".to_string(),
+            }],
+        };
+
+        assert_eq!(
+            manifest("a/x.txt").resolve(root).unwrap(),
+            vec![(root.join("a").join("x.txt"), root.join("x.txt"))]
+        );
+        assert!(manifest("../x.txt").resolve(root).is_err());
+        assert!(manifest("/etc/x.txt").resolve(root).is_err());
+        assert!(manifest("").resolve(root).is_err());
+    }
+
+    #[test]
+    fn test_read_rejects_other_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("manifest.json");
+        fs::write(&path, r#"{"version": 99, "moves": []}"#).unwrap();
+        assert!(Manifest::read(&path).is_err());
+    }
+}
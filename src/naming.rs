@@ -0,0 +1,1028 @@
+//! Destination names and conflict detection in the root directory
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Unicode normalization form for destination names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnicodeForm {
+    /// Composed form, used by Windows and most Linux software
+    Nfc,
+    /// Decomposed form, used by macOS HFS+
+    Nfd,
+}
+
+/// Normalize a name to the given form. Names that aren't valid UTF-8 are returned unchanged.
+pub fn normalize_name(name: &OsStr, form: UnicodeForm) -> OsString {
+    match name.to_str() {
+        Some(s) => match form {
+            UnicodeForm::Nfc => s.nfc().collect::<String>().into(),
+            UnicodeForm::Nfd => s.nfd().collect::<String>().into(),
+        },
+        None => name.to_os_string(),
+    }
+}
+
+/// Cleanup applied to destination names with --transform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NameTransform {
+    /// `My Photo.JPG` -> `my photo.jpg`
+    Lowercase,
+    /// `My Photo (1).JPG` -> `my-photo-1.jpg`
+    Slugify,
+    /// `My Photo.JPG` -> `My_Photo.JPG`
+    SpacesToUnderscores,
+}
+
+/// Apply `transform` to a name. Names that aren't valid UTF-8 are returned unchanged.
+pub fn transform_name(name: &OsStr, transform: NameTransform) -> OsString {
+    let Some(s) = name.to_str() else {
+        return name.to_os_string();
+    };
+    match transform {
+        NameTransform::Lowercase => s.to_lowercase().into(),
+        NameTransform::Slugify => slugify(s).into(),
+        NameTransform::SpacesToUnderscores => s.replace(' ', "_").into(),
+    }
+}
+
+/// Lowercase the stem and extension and join runs of other characters with `-`.
+/// A leading dot is kept, so hidden files stay hidden.
+fn slugify(name: &str) -> String {
+    let (hidden, name) = match name.strip_prefix('.') {
+        Some(rest) => (".", rest),
+        None => ("", name),
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+
+    let slug = |part: &str| {
+        let mut slug = String::new();
+        for c in part.chars().flat_map(char::to_lowercase) {
+            if c.is_alphanumeric() {
+                slug.push(c);
+            } else if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+        slug.trim_end_matches('-').to_string()
+    };
+
+    let mut result = hidden.to_string();
+    match slug(stem) {
+        stem if stem.is_empty() => result.push_str("file"),
+        stem => result.push_str(&stem),
+    }
+    if let Some(extension) = extension.map(slug).filter(|e| !e.is_empty()) {
+        result.push('.');
+        result.push_str(&extension);
+    }
+    result
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+    Stem,
+    Ext,
+}
+
+/// Template for destination names with --rename-by-date, e.g.
+/// `{yyyy}-{mm}-{dd}_{stem}.{ext}`. Dates are the file's modification date in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl NameTemplate {
+    /// Parse a name template (used as a clap value parser)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s.contains(['/', '\\']) {
+            return Err("the name template can't contain path separators".to_string());
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in name template '{}'", s))?;
+            parts.push(match &rest[start + 1..start + end] {
+                "yyyy" => TemplatePart::Year,
+                "mm" => TemplatePart::Month,
+                "dd" => TemplatePart::Day,
+                "stem" => TemplatePart::Stem,
+                "ext" => TemplatePart::Ext,
+                placeholder => {
+                    return Err(format!(
+                        "unknown placeholder '{{{}}}', expected {{yyyy}}, {{mm}}, {{dd}}, \
+                         {{stem}} or {{ext}}",
+                        placeholder
+                    ));
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unmatched '}}' in name template '{}'", s));
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+
+        // Without the original name every file from the same day would conflict
+        if !parts.contains(&TemplatePart::Stem) {
+            return Err("the name template must contain {stem}".to_string());
+        }
+        Ok(NameTemplate { parts })
+    }
+
+    /// The name for `file_name`, last modified at `modified`. For names without an
+    /// extension, `{ext}` and a `.` right before it are left out.
+    pub fn render(&self, file_name: &OsStr, modified: SystemTime) -> OsString {
+        let path = Path::new(file_name);
+        let stem = path.file_stem().unwrap_or(file_name);
+        let extension = path.extension();
+        let (year, month, day) = crate::dates::utc_date(modified);
+
+        let mut name = OsString::new();
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                TemplatePart::Literal(text) => {
+                    let drop_dot = extension.is_none()
+                        && self.parts.get(i + 1) == Some(&TemplatePart::Ext)
+                        && text.ends_with('.');
+                    name.push(if drop_dot {
+                        &text[..text.len() - 1]
+                    } else {
+                        text
+                    });
+                }
+                TemplatePart::Year => name.push(format!("{:04}", year)),
+                TemplatePart::Month => name.push(format!("{:02}", month)),
+                TemplatePart::Day => name.push(format!("{:02}", day)),
+                TemplatePart::Stem => name.push(stem),
+                TemplatePart::Ext => name.push(extension.unwrap_or_default()),
+            }
+        }
+        name
+    }
+}
+
+/// Device names Windows reserves regardless of extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Make a name usable on Windows by appending `suffix` to reserved device names
+/// (`CON.txt` -> `CON_.txt`) and replacing trailing dots and spaces, which Windows
+/// silently strips (`notes.` -> `notes_`). Returns `None` if the name is already safe.
+pub fn sanitize_reserved_name(name: &OsStr, suffix: &str) -> Option<OsString> {
+    let mut name = name.to_str()?.to_string();
+    let mut changed = false;
+
+    let trimmed_len = name.trim_end_matches(['.', ' ']).len();
+    if trimmed_len != name.len() {
+        name.truncate(trimmed_len);
+        name.push_str(suffix);
+        changed = true;
+    }
+
+    // Reserved names are matched on the part before the first dot
+    let base_len = name.find('.').unwrap_or(name.len());
+    let base = name[..base_len].trim_end_matches(' ');
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(base)) {
+        name.insert_str(base_len, suffix);
+        changed = true;
+    }
+
+    changed.then(|| name.into())
+}
+
+/// Longest file name most filesystems accept: 255 bytes (NAME_MAX) on Unix,
+/// 255 UTF-16 code units on Windows
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Length of a character in the units the platform's name limit is counted in
+fn char_len(c: char) -> usize {
+    if cfg!(target_os = "windows") {
+        c.len_utf16()
+    } else {
+        c.len_utf8()
+    }
+}
+
+fn str_len(s: &str) -> usize {
+    s.chars().map(char_len).sum()
+}
+
+/// Length of a name as counted against `MAX_NAME_LEN`
+pub fn name_len(name: &OsStr) -> usize {
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        name.encode_wide().count()
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        name.len()
+    }
+}
+
+const FNV_OFFSET_BASIS: u32 = 0x811c9dc5;
+
+/// 32-bit FNV-1a hash, used for short, stable name suffixes
+fn fnv1a(bytes: &[u8]) -> u32 {
+    fnv1a_update(FNV_OFFSET_BASIS, bytes)
+}
+
+/// Continue an FNV-1a hash over more bytes
+fn fnv1a_update(hash: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x01000193)
+    })
+}
+
+/// FNV-1a hash of a file's contents
+fn content_hash(path: &Path) -> io::Result<u32> {
+    use std::io::Read;
+
+    let mut file = fs::File::open(path)?;
+    let mut buffer = [0; 64 * 1024];
+    let mut hash = FNV_OFFSET_BASIS;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hash);
+        }
+        hash = fnv1a_update(hash, &buffer[..read]);
+    }
+}
+
+/// Shorten a name that exceeds `max_len`, keeping its extension and replacing
+/// the cut-off part of the stem with a short hash of the full name so that
+/// different long names stay distinct (`very_long…name.txt` -> `very_lo~1a2b3c4d.txt`).
+/// Returns `None` if the name already fits.
+pub fn fit_name_length(name: &OsStr, max_len: usize) -> Option<OsString> {
+    if name_len(name) <= max_len {
+        return None;
+    }
+
+    let name = name.to_string_lossy();
+    let hash = format!("~{:08x}", fnv1a(name.as_bytes()));
+
+    // Keep the extension unless it is unreasonably long itself
+    let path = Path::new(name.as_ref());
+    let (stem, extension) = match (
+        path.file_stem().and_then(OsStr::to_str),
+        path.extension().and_then(OsStr::to_str),
+    ) {
+        (Some(stem), Some(ext)) if str_len(ext) + 1 + hash.len() <= max_len / 2 => {
+            (stem, format!(".{}", ext))
+        }
+        _ => (name.as_ref(), String::new()),
+    };
+
+    let budget = max_len - hash.len() - str_len(&extension);
+    let mut truncated = String::new();
+    let mut len = 0;
+    for c in stem.chars() {
+        if len + char_len(c) > budget {
+            break;
+        }
+        truncated.push(c);
+        len += char_len(c);
+    }
+
+    Some(format!("{}{}{}", truncated, hash, extension).into())
+}
+
+/// Characters NTFS, exFAT and FAT don't allow in file names
+const INVALID_CHARS: [char; 9] = [':', '*', '?', '"', '<', '>', '|', '\\', '/'];
+
+/// Replace characters that are invalid on Windows filesystems (and control
+/// characters) with `_`. Names that aren't valid UTF-8 can't be represented
+/// on those filesystems either, so invalid sequences are replaced as well.
+/// Returns `None` if the name is already valid.
+pub fn sanitize_chars(name: &OsStr) -> Option<OsString> {
+    let lossy = name.to_string_lossy();
+    let sanitized: String = lossy
+        .chars()
+        .map(|c| {
+            if INVALID_CHARS.contains(&c) || c.is_control() || c == char::REPLACEMENT_CHARACTER {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    (sanitized.as_str() != name).then(|| sanitized.into())
+}
+
+/// Insert `suffix` between the stem and extension of `file_name`
+/// (e.g. `report.pdf` + `_1` -> `report_1.pdf`). Works on raw OS strings so names
+/// that aren't valid UTF-8 keep their original bytes.
+pub fn conflict_name(file_name: &OsStr, suffix: &str) -> OsString {
+    let path = Path::new(file_name);
+    let mut new_name = path
+        .file_stem()
+        .unwrap_or(OsStr::new("file"))
+        .to_os_string();
+    new_name.push(suffix);
+
+    if let Some(extension) = path.extension() {
+        new_name.push(".");
+        new_name.push(extension);
+    }
+
+    new_name
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SuffixPart {
+    Literal(String),
+    /// The conflict counter, zero-padded to `width` digits
+    Counter {
+        width: usize,
+    },
+    /// The file's modification time
+    Timestamp,
+    /// A short hash of the file's contents
+    Hash,
+}
+
+/// Format of the suffix added to conflicting names, e.g. `_{counter}` (the default),
+/// `_{counter:03}`, `_{timestamp}` or `_{hash}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictSuffix {
+    parts: Vec<SuffixPart>,
+    /// Counter of the first conflicting copy
+    start: usize,
+}
+
+impl Default for ConflictSuffix {
+    fn default() -> Self {
+        ConflictSuffix {
+            parts: vec![
+                SuffixPart::Literal("_".to_string()),
+                SuffixPart::Counter { width: 0 },
+            ],
+            start: 1,
+        }
+    }
+}
+
+/// Per-file values for the `{timestamp}` and `{hash}` placeholders
+#[derive(Default)]
+pub struct SuffixValues {
+    timestamp: Option<String>,
+    hash: Option<u32>,
+}
+
+impl ConflictSuffix {
+    /// Parse a suffix template (used as a clap value parser)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s.contains(['/', '\\']) {
+            return Err("the conflict suffix can't contain path separators".to_string());
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(SuffixPart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in conflict suffix '{}'", s))?;
+            let placeholder = &rest[start + 1..start + end];
+            parts.push(match placeholder.split_once(':') {
+                None if placeholder == "counter" => SuffixPart::Counter { width: 0 },
+                Some(("counter", width)) => SuffixPart::Counter {
+                    width: width
+                        .parse()
+                        .map_err(|_| format!("invalid counter width '{}'", width))?,
+                },
+                None if placeholder == "timestamp" => SuffixPart::Timestamp,
+                None if placeholder == "hash" => SuffixPart::Hash,
+                _ => {
+                    return Err(format!(
+                        "unknown placeholder '{{{}}}', expected {{counter}}, {{counter:03}}, \
+                         {{timestamp}} or {{hash}}",
+                        placeholder
+                    ));
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unmatched '}}' in conflict suffix '{}'", s));
+        }
+        if !rest.is_empty() {
+            parts.push(SuffixPart::Literal(rest.to_string()));
+        }
+
+        if parts.is_empty() {
+            return Err("the conflict suffix can't be empty".to_string());
+        }
+        Ok(ConflictSuffix { parts, start: 1 })
+    }
+
+    /// Start counting conflicting copies at `start`, and zero-pad counters to at least
+    /// `pad` digits (--conflict-start and --conflict-pad)
+    pub fn with_counter(mut self, start: usize, pad: usize) -> Self {
+        self.start = start;
+        for part in &mut self.parts {
+            if let SuffixPart::Counter { width } = part {
+                *width = (*width).max(pad);
+            }
+        }
+        self
+    }
+
+    /// Counter of the first conflicting copy
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Counter to try first for a conflicting copy, given the highest already in use
+    /// for its name: the next one, but never below the start
+    pub fn next_counter(&self, highest: Option<usize>) -> usize {
+        highest.map_or(self.start, |highest| (highest + 1).max(self.start))
+    }
+
+    /// Read the timestamp and content hash of `path`, if the format uses them
+    pub fn values(&self, path: &Path) -> io::Result<SuffixValues> {
+        let mut values = SuffixValues::default();
+        if self.parts.contains(&SuffixPart::Timestamp) {
+            let modified = fs::metadata(path)?.modified()?;
+            values.timestamp = Some(crate::dates::format_timestamp(modified));
+        }
+        if self.parts.contains(&SuffixPart::Hash) {
+            values.hash = Some(content_hash(path)?);
+        }
+        Ok(values)
+    }
+
+    /// Split a name this format produced into the name it was made from and its
+    /// counter, e.g. `report_2.pdf` into `report.pdf` and 2. Only formats made of text
+    /// and {counter} can be read back.
+    pub fn parse_name(&self, file_name: &OsStr) -> Option<(OsString, usize)> {
+        let path = Path::new(file_name);
+        let mut stem = path.file_stem()?.to_str()?;
+        let mut counter = None;
+        for part in self.parts.iter().rev() {
+            match part {
+                SuffixPart::Literal(text) => stem = stem.strip_suffix(text.as_str())?,
+                SuffixPart::Counter { width } => {
+                    let base = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+                    let digits = &stem[base.len()..];
+                    let n: usize = digits.parse().ok()?;
+                    // Only digits the counter renders to, so IMG_0001 isn't read as 1
+                    if format!("{:0width$}", n, width = width) != digits {
+                        return None;
+                    }
+                    counter = Some(n);
+                    stem = base;
+                }
+                SuffixPart::Timestamp | SuffixPart::Hash => return None,
+            }
+        }
+        if stem.is_empty() {
+            return None;
+        }
+
+        let mut name = OsString::from(stem);
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        Some((name, counter?))
+    }
+
+    /// Render the suffix for conflicting copy number `counter`. Formats without
+    /// `{counter}` get `_{counter}` appended after the first attempt, so every attempt
+    /// produces a new name.
+    pub fn render(&self, counter: usize, values: &SuffixValues) -> String {
+        let mut suffix = String::new();
+        let mut has_counter = false;
+        for part in &self.parts {
+            match part {
+                SuffixPart::Literal(text) => suffix.push_str(text),
+                SuffixPart::Counter { width } => {
+                    suffix.push_str(&format!("{:0width$}", counter, width = width));
+                    has_counter = true;
+                }
+                SuffixPart::Timestamp => {
+                    suffix.push_str(values.timestamp.as_deref().unwrap_or_default())
+                }
+                SuffixPart::Hash => {
+                    suffix.push_str(&format!("{:08x}", values.hash.unwrap_or_default()))
+                }
+            }
+        }
+
+        if !has_counter && counter > self.start {
+            suffix.push_str(&format!("_{}", counter));
+        }
+        suffix
+    }
+}
+
+/// How an existing root file is kept when it is overwritten
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backup {
+    /// Append a suffix (e.g. `.bak`), falling back to a numbered backup if that
+    /// name is taken
+    Suffix(String),
+    /// Numbered backups like GNU mv: `name.~1~`, `name.~2~`...
+    Numbered,
+}
+
+impl Backup {
+    /// Parse a --backup value: `numbered` or a suffix (used as a clap value parser)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s == "numbered" {
+            Ok(Backup::Numbered)
+        } else if s.is_empty() || s.contains(['/', '\\']) {
+            Err(format!(
+                "invalid backup suffix '{}', expected e.g. .bak or numbered",
+                s
+            ))
+        } else {
+            Ok(Backup::Suffix(s.to_string()))
+        }
+    }
+
+    /// Pick a free name next to `path` for its backup
+    pub fn backup_path(&self, path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default();
+        let with_suffix = |suffix: &str| {
+            let mut backup = name.to_os_string();
+            backup.push(suffix);
+            path.with_file_name(backup)
+        };
+
+        if let Backup::Suffix(suffix) = self {
+            let backup = with_suffix(suffix);
+            if !backup.exists() {
+                return backup;
+            }
+        }
+
+        let mut counter = 1;
+        loop {
+            let backup = with_suffix(&format!(".~{}~", counter));
+            if !backup.exists() {
+                return backup;
+            }
+            counter += 1;
+        }
+    }
+}
+
+/// Check whether the filesystem containing `dir` treats names case-insensitively,
+/// by creating a probe file and looking it up under a different case
+pub fn detect_case_insensitive(dir: &Path) -> io::Result<bool> {
+    let probe_name = format!(".rflatten-case-probe-{}", std::process::id());
+    let probe = dir.join(&probe_name);
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    let insensitive = dir.join(probe_name.to_uppercase()).exists();
+    fs::remove_file(&probe)?;
+
+    Ok(insensitive)
+}
+
+/// Names already present in the root. Used alongside `Path::exists` to catch
+/// conflicts the filesystem itself wouldn't report, such as "café" written
+/// in NFC by one source and NFD by another, or `README.txt` and `readme.txt`
+/// on a case-insensitive destination.
+#[derive(Default)]
+pub struct RootNames {
+    keys: HashSet<OsString>,
+    fold_case: bool,
+}
+
+impl RootNames {
+    /// Index the entries currently in `root`
+    pub fn load(root: &Path, fold_case: bool) -> io::Result<Self> {
+        let mut names = RootNames {
+            keys: HashSet::new(),
+            fold_case,
+        };
+        for entry in fs::read_dir(root)? {
+            names.insert(&entry?.file_name());
+        }
+        Ok(names)
+    }
+
+    /// Key two names compare equal under: their NFC form, lowercased when
+    /// the destination is case-insensitive
+    fn key(&self, name: &OsStr) -> OsString {
+        let key = normalize_name(name, UnicodeForm::Nfc);
+        match key.to_str() {
+            Some(s) if self.fold_case => s.to_lowercase().into(),
+            _ => key,
+        }
+    }
+
+    pub fn contains(&self, name: &OsStr) -> bool {
+        self.keys.contains(&self.key(name))
+    }
+
+    pub fn insert(&mut self, name: &OsStr) {
+        self.keys.insert(self.key(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // "café" with a precomposed é and with e + combining acute accent
+    const CAFE_NFC: &str = "caf\u{e9}.txt";
+    const CAFE_NFD: &str = "cafe\u{301}.txt";
+
+    #[test]
+    fn test_conflict_name() {
+        assert_eq!(conflict_name(OsStr::new("test.txt"), "_1"), "test_1.txt");
+        assert_eq!(conflict_name(OsStr::new("README"), "_2"), "README_2");
+        assert_eq!(
+            conflict_name(OsStr::new("archive.tar.gz"), "_3"),
+            "archive.tar_3.gz"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_conflict_name_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(
+            conflict_name(name, "_1").as_bytes(),
+            b"caf\xe9_1.txt".as_slice()
+        );
+    }
+
+    #[test]
+    fn test_conflict_suffix_default() {
+        let suffix = ConflictSuffix::default();
+        assert_eq!(ConflictSuffix::parse("_{counter}"), Ok(suffix.clone()));
+        assert_eq!(suffix.render(1, &SuffixValues::default()), "_1");
+        assert_eq!(suffix.render(12, &SuffixValues::default()), "_12");
+    }
+
+    #[test]
+    fn test_conflict_suffix_padded_counter() {
+        let suffix = ConflictSuffix::parse(" ({counter:03})").unwrap();
+        assert_eq!(suffix.render(1, &SuffixValues::default()), " (001)");
+        assert_eq!(suffix.render(1234, &SuffixValues::default()), " (1234)");
+    }
+
+    #[test]
+    fn test_conflict_suffix_start_and_pad() {
+        let suffix = ConflictSuffix::default().with_counter(0, 3);
+        assert_eq!(suffix.start(), 0);
+        assert_eq!(suffix.render(0, &SuffixValues::default()), "_000");
+        assert_eq!(suffix.next_counter(None), 0);
+        assert_eq!(suffix.next_counter(Some(0)), 1);
+        assert_eq!(
+            suffix.parse_name(OsStr::new("a_000.txt")),
+            Some((OsString::from("a.txt"), 0))
+        );
+
+        // An explicit width wider than the padding is kept
+        let suffix = ConflictSuffix::parse("-{counter:4}")
+            .unwrap()
+            .with_counter(5, 2);
+        assert_eq!(suffix.render(5, &SuffixValues::default()), "-0005");
+        assert_eq!(suffix.next_counter(Some(2)), 5);
+
+        let suffix = ConflictSuffix::parse("_{hash}").unwrap().with_counter(0, 3);
+        assert_eq!(suffix.render(0, &SuffixValues::default()), "_00000000");
+        assert_eq!(suffix.render(1, &SuffixValues::default()), "_00000000_1");
+    }
+
+    #[test]
+    fn test_conflict_suffix_parse_name() {
+        let suffix = ConflictSuffix::default();
+        assert_eq!(
+            suffix.parse_name(OsStr::new("report_2.pdf")),
+            Some((OsString::from("report.pdf"), 2))
+        );
+        assert_eq!(
+            suffix.parse_name(OsStr::new("archive.tar_12.gz")),
+            Some((OsString::from("archive.tar.gz"), 12))
+        );
+        assert_eq!(suffix.parse_name(OsStr::new("IMG_0001.jpg")), None);
+        assert_eq!(suffix.parse_name(OsStr::new("report.pdf")), None);
+        assert_eq!(suffix.parse_name(OsStr::new("_3.txt")), None);
+
+        let suffix = ConflictSuffix::parse(" ({counter:03})").unwrap();
+        assert_eq!(
+            suffix.parse_name(OsStr::new("song (007).mp3")),
+            Some((OsString::from("song.mp3"), 7))
+        );
+        assert_eq!(suffix.parse_name(OsStr::new("song (7).mp3")), None);
+        assert_eq!(
+            ConflictSuffix::parse("_{hash}")
+                .unwrap()
+                .parse_name(OsStr::new("a_1.txt")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_conflict_suffix_timestamp_and_hash() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.jpg");
+        fs::write(&path, "abc").unwrap();
+
+        let suffix = ConflictSuffix::parse("_{hash}").unwrap();
+        let values = suffix.values(&path).unwrap();
+        assert_eq!(suffix.render(1, &values), format!("_{:08x}", fnv1a(b"abc")));
+        // Without {counter}, later attempts still produce new names
+        assert_eq!(
+            suffix.render(2, &values),
+            format!("_{:08x}_2", fnv1a(b"abc"))
+        );
+
+        let suffix = ConflictSuffix::parse("-{timestamp}").unwrap();
+        let rendered = suffix.render(1, &suffix.values(&path).unwrap());
+        assert_eq!(rendered.len(), "-YYYYMMDD-HHMMSS".len());
+    }
+
+    #[test]
+    fn test_conflict_suffix_invalid() {
+        assert!(ConflictSuffix::parse("").is_err());
+        assert!(ConflictSuffix::parse("_{count}").is_err());
+        assert!(ConflictSuffix::parse("_{counter:x}").is_err());
+        assert!(ConflictSuffix::parse("_{counter").is_err());
+        assert!(ConflictSuffix::parse("_counter}").is_err());
+        assert!(ConflictSuffix::parse("/{counter}").is_err());
+    }
+
+    #[test]
+    fn test_backup_parse() {
+        assert_eq!(Backup::parse("numbered"), Ok(Backup::Numbered));
+        assert_eq!(
+            Backup::parse(".orig"),
+            Ok(Backup::Suffix(".orig".to_string()))
+        );
+        assert!(Backup::parse("").is_err());
+        assert!(Backup::parse("/bak").is_err());
+    }
+
+    #[test]
+    fn test_backup_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("notes.txt");
+        let suffix = Backup::Suffix(".bak".to_string());
+
+        assert_eq!(
+            suffix.backup_path(&path),
+            temp_dir.path().join("notes.txt.bak")
+        );
+        assert_eq!(
+            Backup::Numbered.backup_path(&path),
+            temp_dir.path().join("notes.txt.~1~")
+        );
+
+        // Existing backups are never replaced
+        fs::write(temp_dir.path().join("notes.txt.bak"), "old").unwrap();
+        fs::write(temp_dir.path().join("notes.txt.~1~"), "older").unwrap();
+        assert_eq!(
+            suffix.backup_path(&path),
+            temp_dir.path().join("notes.txt.~2~")
+        );
+    }
+
+    #[test]
+    fn test_fit_name_length_short_names_unchanged() {
+        assert!(fit_name_length(OsStr::new("notes.txt"), MAX_NAME_LEN).is_none());
+        let exact = "a".repeat(MAX_NAME_LEN);
+        assert!(fit_name_length(OsStr::new(&exact), MAX_NAME_LEN).is_none());
+    }
+
+    #[test]
+    fn test_fit_name_length_keeps_extension() {
+        let long = format!("{}.txt", "a".repeat(300));
+        let fitted = fit_name_length(OsStr::new(&long), MAX_NAME_LEN).unwrap();
+        let fitted = fitted.to_str().unwrap();
+
+        assert_eq!(fitted.len(), MAX_NAME_LEN);
+        assert!(fitted.ends_with(".txt"));
+        assert!(fitted.contains('~'));
+    }
+
+    #[test]
+    fn test_fit_name_length_distinct_names_stay_distinct() {
+        let first = format!("{}_1.txt", "a".repeat(300));
+        let second = format!("{}_2.txt", "a".repeat(300));
+        assert_ne!(
+            fit_name_length(OsStr::new(&first), MAX_NAME_LEN),
+            fit_name_length(OsStr::new(&second), MAX_NAME_LEN)
+        );
+    }
+
+    #[test]
+    fn test_fit_name_length_multibyte() {
+        // Each "é" is two bytes, so the cut must land on a character boundary
+        let long = format!("{}.jpg", "\u{e9}".repeat(200));
+        let fitted = fit_name_length(OsStr::new(&long), MAX_NAME_LEN).unwrap();
+        assert!(name_len(&fitted) <= MAX_NAME_LEN);
+        assert!(fitted.to_str().unwrap().ends_with(".jpg"));
+    }
+
+    #[test]
+    fn test_fit_name_length_long_extension() {
+        // An absurdly long "extension" is truncated along with the rest
+        let long = format!("a.{}", "b".repeat(300));
+        let fitted = fit_name_length(OsStr::new(&long), MAX_NAME_LEN).unwrap();
+        assert_eq!(name_len(&fitted), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn test_sanitize_chars() {
+        let sanitize = |name: &str| sanitize_chars(OsStr::new(name));
+
+        assert_eq!(sanitize("10:30 meeting.txt").unwrap(), "10_30 meeting.txt");
+        assert_eq!(sanitize("what?.md").unwrap(), "what_.md");
+        assert_eq!(sanitize("a<b>c|d*e\"f.txt").unwrap(), "a_b_c_d_e_f.txt");
+        assert_eq!(sanitize("tab\there").unwrap(), "tab_here");
+        assert!(sanitize("normal name (1).txt").is_none());
+        assert!(sanitize("caf\u{e9}.txt").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sanitize_chars_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(sanitize_chars(name).unwrap(), "caf_.txt");
+    }
+
+    #[test]
+    fn test_sanitize_reserved_name() {
+        let sanitize = |name: &str| sanitize_reserved_name(OsStr::new(name), "_");
+
+        assert_eq!(sanitize("CON").unwrap(), "CON_");
+        assert_eq!(sanitize("con.txt").unwrap(), "con_.txt");
+        assert_eq!(sanitize("LPT1.tar.gz").unwrap(), "LPT1_.tar.gz");
+        assert_eq!(sanitize("nul .txt").unwrap(), "nul _.txt");
+        assert_eq!(sanitize("notes.").unwrap(), "notes_");
+        assert_eq!(sanitize("draft . ").unwrap(), "draft_");
+        assert_eq!(sanitize("aux.").unwrap(), "aux_");
+
+        assert!(sanitize("console.txt").is_none());
+        assert!(sanitize("COM10").is_none());
+        assert!(sanitize("notes.txt").is_none());
+    }
+
+    #[test]
+    fn test_sanitize_reserved_name_custom_suffix() {
+        assert_eq!(
+            sanitize_reserved_name(OsStr::new("PRN.log"), "-file").unwrap(),
+            "PRN-file.log"
+        );
+    }
+
+    #[test]
+    fn test_name_template() {
+        // 2024-03-05
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_709_640_000);
+        let template = NameTemplate::parse("{yyyy}-{mm}-{dd}_{stem}.{ext}").unwrap();
+        assert_eq!(
+            template.render(OsStr::new("IMG_0001.jpg"), modified),
+            "2024-03-05_IMG_0001.jpg"
+        );
+        assert_eq!(
+            template.render(OsStr::new("README"), modified),
+            "2024-03-05_README"
+        );
+        assert_eq!(
+            template.render(OsStr::new(".bashrc"), modified),
+            "2024-03-05_.bashrc"
+        );
+
+        assert!(NameTemplate::parse("{yyyy}/{stem}").is_err());
+        assert!(NameTemplate::parse("{yyyy}-{mm}").is_err());
+        assert!(NameTemplate::parse("{stem}_{hour}").is_err());
+        assert!(NameTemplate::parse("{stem").is_err());
+    }
+
+    #[test]
+    fn test_transform_name() {
+        let transform = |name: &str, transform| transform_name(OsStr::new(name), transform);
+        assert_eq!(
+            transform("My Photo.JPG", NameTransform::Lowercase),
+            "my photo.jpg"
+        );
+        assert_eq!(
+            transform("My Photo (1).JPG", NameTransform::Slugify),
+            "my-photo-1.jpg"
+        );
+        assert_eq!(
+            transform("Ünïcode  Notes!.tar.GZ", NameTransform::Slugify),
+            "ünïcode-notes-tar.gz"
+        );
+        assert_eq!(transform(".bashrc", NameTransform::Slugify), ".bashrc");
+        assert_eq!(transform("???.txt", NameTransform::Slugify), "file.txt");
+        assert_eq!(
+            transform("My Photo.JPG", NameTransform::SpacesToUnderscores),
+            "My_Photo.JPG"
+        );
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(
+            normalize_name(OsStr::new(CAFE_NFD), UnicodeForm::Nfc),
+            CAFE_NFC
+        );
+        assert_eq!(
+            normalize_name(OsStr::new(CAFE_NFC), UnicodeForm::Nfd),
+            CAFE_NFD
+        );
+        assert_eq!(
+            normalize_name(OsStr::new("plain.txt"), UnicodeForm::Nfd),
+            "plain.txt"
+        );
+    }
+
+    #[test]
+    fn test_root_names_normalization_equivalent() {
+        let mut names = RootNames::default();
+        names.insert(OsStr::new(CAFE_NFC));
+
+        assert!(names.contains(OsStr::new(CAFE_NFC)));
+        assert!(names.contains(OsStr::new(CAFE_NFD)));
+        assert!(!names.contains(OsStr::new("cafe.txt")));
+    }
+
+    #[test]
+    fn test_root_names_load() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("existing.txt"), "").unwrap();
+        fs::create_dir(temp_dir.path().join("subdir")).unwrap();
+
+        let names = RootNames::load(temp_dir.path(), false).unwrap();
+        assert!(names.contains(OsStr::new("existing.txt")));
+        assert!(names.contains(OsStr::new("subdir")));
+        assert!(!names.contains(OsStr::new("missing.txt")));
+    }
+
+    #[test]
+    fn test_root_names_case_sensitive() {
+        let mut names = RootNames::default();
+        names.insert(OsStr::new("README.txt"));
+
+        assert!(names.contains(OsStr::new("README.txt")));
+        assert!(!names.contains(OsStr::new("readme.txt")));
+    }
+
+    #[test]
+    fn test_root_names_fold_case() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("README.txt"), "").unwrap();
+
+        let names = RootNames::load(temp_dir.path(), true).unwrap();
+        assert!(names.contains(OsStr::new("readme.txt")));
+        assert!(names.contains(OsStr::new("ReadMe.TXT")));
+        assert!(!names.contains(OsStr::new("readme.md")));
+    }
+
+    #[test]
+    fn test_detect_case_insensitive_cleans_up() {
+        let temp_dir = TempDir::new().unwrap();
+
+        // The answer depends on the filesystem the tests run on, but the
+        // probe file must never be left behind
+        detect_case_insensitive(temp_dir.path()).unwrap();
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+}
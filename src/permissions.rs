@@ -0,0 +1,151 @@
+//! File ownership and permission checks
+
+use std::fs;
+use std::path::Path;
+
+/// Resolve a user name (or numeric uid) to a uid
+#[cfg(unix)]
+pub fn resolve_user(name: &str) -> Result<u32, String> {
+    use std::ffi::CString;
+
+    if let Ok(uid) = name.parse::<u32>() {
+        return Ok(uid);
+    }
+
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name '{}'", name))?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: all pointers are valid for the duration of the call and
+        // `buf.len()` is the real size of the buffer
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+
+        if rc == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if rc != 0 || result.is_null() {
+            return Err(format!("unknown user '{}'", name));
+        }
+        return Ok(pwd.pw_uid);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn resolve_user(_name: &str) -> Result<u32, String> {
+    Err("--owned-by is only supported on Unix".to_string())
+}
+
+/// Owner of a file, where the platform has numeric owners
+pub fn owner(metadata: &fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.uid())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Check whether this process can move `path` out of its directory: that needs
+/// write and search permission on the directory, and in sticky directories
+/// (like /tmp) ownership of either the file or the directory
+#[cfg(unix)]
+pub fn can_move(path: &Path, metadata: &fs::Metadata) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    const STICKY_BIT: u32 = 0o1000;
+
+    let Some(parent) = path.parent() else {
+        return false;
+    };
+    let Ok(c_parent) = CString::new(parent.as_os_str().as_bytes()) else {
+        return false;
+    };
+
+    // SAFETY: `c_parent` is a valid NUL-terminated path
+    let accessible = unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            c_parent.as_ptr(),
+            libc::W_OK | libc::X_OK,
+            libc::AT_EACCESS,
+        )
+    } == 0;
+    if !accessible {
+        return false;
+    }
+
+    let Ok(parent_metadata) = fs::metadata(parent) else {
+        return false;
+    };
+    if parent_metadata.mode() & STICKY_BIT != 0 {
+        // SAFETY: geteuid has no preconditions
+        let euid = unsafe { libc::geteuid() };
+        return euid == 0 || metadata.uid() == euid || parent_metadata.uid() == euid;
+    }
+
+    true
+}
+
+/// Check whether this process can move `path`. Without Unix permission bits
+/// only the read-only attribute can be checked.
+#[cfg(not(unix))]
+pub fn can_move(path: &Path, metadata: &fs::Metadata) -> bool {
+    let parent_writable = path
+        .parent()
+        .and_then(|parent| fs::metadata(parent).ok())
+        .is_some_and(|parent| !parent.permissions().readonly());
+    parent_writable && !metadata.permissions().readonly()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_user() {
+        assert_eq!(resolve_user("0").unwrap(), 0);
+        assert_eq!(resolve_user("root").unwrap(), 0);
+        assert!(resolve_user("no-such-user-rflatten").is_err());
+    }
+
+    #[test]
+    fn test_can_move_in_writable_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file.txt");
+        fs::write(&file, "").unwrap();
+
+        let metadata = fs::metadata(&file).unwrap();
+        assert!(can_move(&file, &metadata));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_owner_matches_current_user() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file.txt");
+        fs::write(&file, "").unwrap();
+
+        // SAFETY: geteuid has no preconditions
+        let euid = unsafe { libc::geteuid() };
+        assert_eq!(owner(&fs::metadata(&file).unwrap()), Some(euid));
+    }
+}
@@ -0,0 +1,91 @@
+//! Output for scripts with --porcelain: one line per file, `STATUS\tSOURCE\tDEST`.
+//! This format is kept stable across versions; the human-readable messages are not.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+/// What happened to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Moved (or extracted from an archive) to DEST
+    Moved,
+    /// A duplicate, replaced with a hard link at DEST to the copy that was kept
+    Linked,
+    /// A duplicate of DEST, or declined in a conflict, and deleted
+    Removed,
+    /// A duplicate of DEST, or declined in a conflict, and left in place
+    Skipped,
+    /// An archive deleted after extraction
+    Deleted,
+    /// Locked by another process and not moved
+    Locked,
+    /// Could not be moved; the error is written to stderr
+    Error,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Moved => "moved",
+            Status::Linked => "linked",
+            Status::Removed => "removed",
+            Status::Skipped => "skipped",
+            Status::Deleted => "deleted",
+            Status::Locked => "locked",
+            Status::Error => "error",
+        }
+    }
+}
+
+/// Print a line for `source`. DEST is empty when there isn't one.
+pub fn print(status: Status, source: &Path, dest: Option<&Path>) {
+    let line = line(status, source, dest);
+    // A closed pipe shouldn't stop the run halfway
+    let _ = writeln!(io::stdout().lock(), "{}", line);
+}
+
+fn line(status: Status, source: &Path, dest: Option<&Path>) -> String {
+    format!(
+        "{}\t{}\t{}",
+        status.as_str(),
+        escape(&crate::display_path(source)),
+        dest.map(|dest| escape(&crate::display_path(dest)))
+            .unwrap_or_default()
+    )
+}
+
+/// Escape backslashes, tabs and newlines so every entry stays on one line
+pub fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        assert_eq!(
+            line(
+                Status::Moved,
+                Path::new("a/b\tc.txt"),
+                Some(Path::new("b\tc.txt"))
+            ),
+            "moved\ta/b\\tc.txt\tb\\tc.txt"
+        );
+        assert_eq!(
+            line(Status::Error, Path::new("a/new\nline"), None),
+            "error\ta/new\\nline\t"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Lowering the process's I/O priority for --nice-io
+
+use std::io;
+
+/// Ask the OS to serve this process's disk I/O only when nothing else needs it
+#[cfg(target_os = "linux")]
+pub fn lower_io_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: ioprio_set only takes integer arguments; 0 means the calling process
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Ask the OS to serve this process's disk I/O only when nothing else needs it
+#[cfg(target_os = "macos")]
+pub fn lower_io_priority() -> io::Result<()> {
+    const IOPOL_TYPE_DISK: libc::c_int = 0;
+    const IOPOL_SCOPE_PROCESS: libc::c_int = 0;
+    const IOPOL_THROTTLE: libc::c_int = 3;
+
+    unsafe extern "C" {
+        fn setiopolicy_np(
+            iotype: libc::c_int,
+            scope: libc::c_int,
+            policy: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    // SAFETY: setiopolicy_np only takes integer arguments
+    if unsafe { setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_PROCESS, IOPOL_THROTTLE) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Ask the OS to serve this process's disk I/O only when nothing else needs it
+#[cfg(target_os = "windows")]
+pub fn lower_io_priority() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, PROCESS_MODE_BACKGROUND_BEGIN, SetPriorityClass,
+    };
+
+    // SAFETY: GetCurrentProcess returns a pseudo handle that is always valid
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub fn lower_io_priority() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "I/O priorities are not supported on this platform",
+    ))
+}
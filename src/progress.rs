@@ -0,0 +1,180 @@
+//! Progress of a flatten as it runs: counters `rflatten serve` polls, and the
+//! machine-readable event stream written with --progress-fd
+
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// How far a flatten has got, and a flag that stops it before the next file
+#[derive(Default)]
+pub struct Progress {
+    pub moved: AtomicUsize,
+    /// Files the run is expected to move, once planned
+    pub total: AtomicUsize,
+    pub moved_bytes: AtomicU64,
+    /// Bytes the run is expected to move, once planned
+    pub total_bytes: AtomicU64,
+    pub cancel: AtomicBool,
+    /// Where events are written as JSON lines, if anywhere
+    events: Option<Mutex<File>>,
+}
+
+impl Progress {
+    /// Progress that also writes events to the open file descriptor `fd`
+    #[cfg(unix)]
+    pub fn to_fd(fd: i32) -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        // Taking ownership of a descriptor that isn't open would close someone else's later
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file descriptor {} is not open", fd),
+            ));
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Progress {
+            events: Some(Mutex::new(file)),
+            ..Default::default()
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn to_fd(_fd: i32) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--progress-fd is only supported on Unix",
+        ))
+    }
+
+    /// The scan found `files` files totalling `bytes`
+    pub fn scanned(&self, files: usize, bytes: u64) {
+        self.emit(json!({"event": "scanned", "files": files, "bytes": bytes}));
+    }
+
+    /// The run was confirmed and will move up to `total` files totalling `bytes`
+    pub fn planned(&self, total: usize, bytes: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.total_bytes.store(bytes, Ordering::Relaxed);
+        self.emit(json!({"event": "planned", "total": total, "bytes": bytes}));
+    }
+
+    /// `source` was moved to `dest`, and was `bytes` long
+    pub fn moved(&self, source: &Path, dest: &Path, bytes: u64) {
+        let moved = self.moved.fetch_add(1, Ordering::Relaxed) + 1;
+        let moved_bytes = self.moved_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let total = self.total.load(Ordering::Relaxed);
+        let mut event = json!({
+            "event": "moved",
+            "source": crate::display_path(source),
+            "dest": crate::display_path(dest),
+            "bytes": bytes,
+            "moved": moved,
+            "moved_bytes": moved_bytes,
+        });
+        // Runs without a scan don't know their total
+        if total > 0 {
+            event["total"] = json!(total);
+            event["total_bytes"] = json!(self.total_bytes.load(Ordering::Relaxed));
+        }
+        if let Some(percent) = self.percent() {
+            event["percent"] = json!(percent);
+        }
+        self.emit(event);
+    }
+
+    /// How much of the run is done, to a tenth of a percent. Measured in bytes, so
+    /// one huge file doesn't hold it at 99% while it's copied; runs of only empty
+    /// files fall back to counting them.
+    pub fn percent(&self) -> Option<f64> {
+        let total_bytes = self.total_bytes.load(Ordering::Relaxed);
+        let moved_bytes = self.moved_bytes.load(Ordering::Relaxed).min(total_bytes);
+        let total = self.total.load(Ordering::Relaxed);
+        let moved = self.moved.load(Ordering::Relaxed).min(total);
+        let permille = (moved_bytes as u128 * 1000)
+            .checked_div(total_bytes as u128)
+            .or_else(|| (moved as u128 * 1000).checked_div(total as u128))?;
+        Some(permille as f64 / 10.0)
+    }
+
+    pub fn errored(&self, path: &Path, error: &dyn std::fmt::Display) {
+        self.emit(json!({
+            "event": "errored",
+            "path": crate::display_path(path),
+            "message": error.to_string(),
+        }));
+    }
+
+    /// The run is over; `statistics` says how it went
+    pub fn finished(&self, statistics: Value) {
+        let mut event = json!({"event": "finished"});
+        if let (Some(event), Value::Object(statistics)) = (event.as_object_mut(), statistics) {
+            event.extend(statistics);
+        }
+        self.emit(event);
+    }
+
+    /// Write one event. A wrapper that stops reading doesn't stop the run.
+    fn emit(&self, event: Value) {
+        if let Some(events) = &self.events {
+            let _ = writeln!(events.lock().unwrap(), "{}", event);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::fd::IntoRawFd;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("events");
+        let fd = File::create(&path).unwrap().into_raw_fd();
+
+        let progress = Progress::to_fd(fd).unwrap();
+        progress.scanned(2, 10);
+        progress.planned(2, 10);
+        progress.moved(Path::new("a/x.txt"), Path::new("x.txt"), 8);
+        progress.errored(Path::new("a/y.txt"), &"permission denied");
+        progress.finished(json!({"moved": 1, "errors": 1}));
+        drop(progress);
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        let events: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[2]["event"], "moved");
+        assert_eq!(events[2]["moved_bytes"], 8);
+        assert_eq!(events[2]["percent"], 80.0);
+        assert_eq!(events[3]["message"], "permission denied");
+        assert_eq!(events[4]["event"], "finished");
+        assert_eq!(events[4]["errors"], 1);
+    }
+
+    #[test]
+    fn test_percent_falls_back_to_files() {
+        let progress = Progress::default();
+        assert_eq!(progress.percent(), None);
+        progress.planned(4, 0);
+        progress.moved(Path::new("a/x.txt"), Path::new("x.txt"), 0);
+        assert_eq!(progress.percent(), Some(25.0));
+    }
+
+    #[test]
+    fn test_closed_fd_is_rejected() {
+        assert!(Progress::to_fd(9999).is_err());
+    }
+}
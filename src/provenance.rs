@@ -0,0 +1,164 @@
+//! Recording where each flattened file came from
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extended attribute holding a file's original path relative to the root
+pub const XATTR_NAME: &str = "user.rflatten.origin";
+
+/// Extension added to a file's name for its provenance sidecar
+pub const SIDECAR_EXTENSION: &str = "origin";
+
+/// Where a flattened file's original path is recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Provenance {
+    /// In the `user.rflatten.origin` extended attribute (Linux and macOS)
+    Xattr,
+    /// In a `<name>.origin` file next to it
+    Sidecar,
+}
+
+/// Path of the sidecar file for `path`
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(SIDECAR_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Record `origin` for the file at `path`. Sidecars never replace an existing file.
+pub fn record(path: &Path, origin: &Path, provenance: Provenance) -> io::Result<()> {
+    let value = origin_bytes(origin);
+    match provenance {
+        Provenance::Xattr => set_xattr(path, XATTR_NAME, &value),
+        Provenance::Sidecar => {
+            let mut contents = value;
+            contents.push(b'\n');
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(sidecar_path(path))?;
+            io::Write::write_all(&mut file, &contents)
+        }
+    }
+}
+
+fn origin_bytes(origin: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        origin.as_os_str().as_bytes().to_vec()
+    }
+
+    #[cfg(not(unix))]
+    {
+        origin.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))?;
+
+    // SAFETY: all pointers are valid for the lengths passed
+    #[cfg(target_os = "linux")]
+    let result = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let result = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+            0,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only supported on Linux and macOS",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sidecar() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.jpg");
+        fs::write(&path, "pixels").unwrap();
+
+        record(&path, Path::new("2023/trip/photo.jpg"), Provenance::Sidecar).unwrap();
+        let sidecar = temp_dir.path().join("photo.jpg.origin");
+        assert_eq!(sidecar_path(&path), sidecar);
+        assert_eq!(
+            fs::read_to_string(&sidecar).unwrap(),
+            "2023/trip/photo.jpg\n"
+        );
+
+        // An existing sidecar is never replaced
+        assert!(record(&path, Path::new("other/photo.jpg"), Provenance::Sidecar).is_err());
+        assert_eq!(
+            fs::read_to_string(&sidecar).unwrap(),
+            "2023/trip/photo.jpg\n"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattr() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.jpg");
+        fs::write(&path, "pixels").unwrap();
+
+        match record(&path, Path::new("trip/photo.jpg"), Provenance::Xattr) {
+            Ok(()) => {}
+            // Not every filesystem the tests run on supports user attributes
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return,
+            Err(e) => panic!("setxattr failed: {}", e),
+        }
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let c_name = CString::new(XATTR_NAME).unwrap();
+        let mut buffer = [0u8; 64];
+        let len = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        };
+        assert_eq!(&buffer[..len as usize], b"trip/photo.jpg");
+    }
+}
@@ -0,0 +1,274 @@
+//! Routing files into directories under the root: by extension for --route, and
+//! into dated buckets for --dest-subdir
+
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+
+use crate::filetype::Preset;
+
+/// Files with one of `extensions` go to `dir` under the root (`None` for the root
+/// itself). No extensions means every file no other route matches (`*`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    extensions: Vec<String>,
+    dir: Option<PathBuf>,
+}
+
+impl Route {
+    /// Parse a rule like `jpg,png -> Pictures`, `@videos -> Videos` or `* -> root`
+    /// (used as a clap value parser). `@name` stands for a built-in extension set.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (extensions, dir) = s
+            .split_once("->")
+            .ok_or_else(|| format!("expected EXTENSIONS -> DIR in route '{}'", s.trim()))?;
+
+        let mut expanded = Vec::new();
+        for ext in extensions.split(',') {
+            let ext = ext.trim();
+            match ext.strip_prefix('@') {
+                Some(name) => {
+                    let preset = Preset::from_str(name, true).map_err(|_| {
+                        format!("unknown extension set '{}' in route '{}'", ext, s.trim())
+                    })?;
+                    expanded.extend(
+                        preset
+                            .class()
+                            .extensions()
+                            .iter()
+                            .map(|ext| ext.to_string()),
+                    );
+                }
+                None => expanded.push(ext.trim_start_matches('.').to_lowercase()),
+            }
+        }
+        let extensions = expanded;
+        if extensions.iter().any(String::is_empty) {
+            return Err(format!("empty extension in route '{}'", s.trim()));
+        }
+        let extensions = if extensions == ["*"] {
+            Vec::new()
+        } else {
+            extensions
+        };
+
+        let dir = match dir.trim() {
+            "root" | "." => None,
+            dir => Some(crate::parse_conflict_dir(dir)?),
+        };
+        Ok(Route { extensions, dir })
+    }
+
+    /// The directory the first route for `file_name`'s extension leads to, falling
+    /// back to a `*` route. `None` means the root.
+    pub fn dir_for<'a>(routes: &'a [Route], file_name: &OsStr) -> Option<&'a Path> {
+        let extension = Path::new(file_name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let route = routes
+            .iter()
+            .find(|route| {
+                extension
+                    .as_ref()
+                    .is_some_and(|ext| route.extensions.contains(ext))
+            })
+            .or_else(|| routes.iter().find(|route| route.extensions.is_empty()))?;
+        route.dir.as_deref()
+    }
+
+    /// Whether `name`, a directory directly in the root, holds routed files
+    pub fn is_route_dir(routes: &[Route], name: &OsStr) -> bool {
+        routes.iter().any(|route| {
+            route
+                .dir
+                .as_ref()
+                .is_some_and(|dir| dir.components().next() == Some(Component::Normal(name)))
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SubdirPart {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+}
+
+/// Template for the directory files land in with --dest-subdir, e.g. `{yyyy}/{mm}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubdirTemplate {
+    segments: Vec<Vec<SubdirPart>>,
+}
+
+impl SubdirTemplate {
+    /// Parse a subdirectory template (used as a clap value parser)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        for segment in s.split('/') {
+            if segment.is_empty() || segment == "." || segment == ".." || segment.contains('\\') {
+                return Err(format!(
+                    "'{}' must be a relative path inside the root directory",
+                    s
+                ));
+            }
+
+            let mut parts = Vec::new();
+            let mut rest = segment;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    parts.push(SubdirPart::Literal(rest[..start].to_string()));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| format!("unclosed '{{' in subdirectory template '{}'", s))?;
+                parts.push(match &rest[start + 1..start + end] {
+                    "yyyy" => SubdirPart::Year,
+                    "mm" => SubdirPart::Month,
+                    "dd" => SubdirPart::Day,
+                    placeholder => {
+                        return Err(format!(
+                            "unknown placeholder '{{{}}}', expected {{yyyy}}, {{mm}} or {{dd}}",
+                            placeholder
+                        ));
+                    }
+                });
+                rest = &rest[start + end + 1..];
+            }
+            if rest.contains('}') {
+                return Err(format!("unmatched '}}' in subdirectory template '{}'", s));
+            }
+            if !rest.is_empty() {
+                parts.push(SubdirPart::Literal(rest.to_string()));
+            }
+            segments.push(parts);
+        }
+        Ok(SubdirTemplate { segments })
+    }
+
+    /// The subdirectory for a file dated `date` (in UTC)
+    pub fn render(&self, date: SystemTime) -> PathBuf {
+        let (year, month, day) = crate::dates::utc_date(date);
+        self.segments
+            .iter()
+            .map(|parts| {
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        SubdirPart::Literal(text) => text.clone(),
+                        SubdirPart::Year => format!("{:04}", year),
+                        SubdirPart::Month => format!("{:02}", month),
+                        SubdirPart::Day => format!("{:02}", day),
+                    })
+                    .collect::<String>()
+            })
+            .collect()
+    }
+
+    /// Whether `name`, a directory directly in the root, could be a bucket this
+    /// template creates
+    pub fn is_bucket(&self, name: &OsStr) -> bool {
+        let Some(mut rest) = name.to_str() else {
+            return false;
+        };
+        for part in &self.segments[0] {
+            let digits = match part {
+                SubdirPart::Literal(text) => match rest.strip_prefix(text.as_str()) {
+                    Some(after) => {
+                        rest = after;
+                        continue;
+                    }
+                    None => return false,
+                },
+                SubdirPart::Year => 4,
+                SubdirPart::Month | SubdirPart::Day => 2,
+            };
+            if rest.len() < digits || !rest.as_bytes()[..digits].iter().all(u8::is_ascii_digit) {
+                return false;
+            }
+            rest = &rest[digits..];
+        }
+        rest.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Route::parse("jpg, .PNG -> Pictures").unwrap(),
+            Route {
+                extensions: vec!["jpg".to_string(), "png".to_string()],
+                dir: Some(PathBuf::from("Pictures")),
+            }
+        );
+        assert_eq!(
+            Route::parse(" * -> root").unwrap(),
+            Route {
+                extensions: Vec::new(),
+                dir: None,
+            }
+        );
+        assert!(Route::parse("jpg Pictures").is_err());
+        assert!(Route::parse("jpg,,png -> Pictures").is_err());
+        assert!(Route::parse("jpg -> ../Pictures").is_err());
+
+        let route = Route::parse("@audio, mod -> Music").unwrap();
+        assert!(route.extensions.iter().any(|ext| ext == "flac"));
+        assert_eq!(route.extensions.last().unwrap(), "mod");
+        assert!(Route::parse("@pictures -> Pictures").is_err());
+    }
+
+    #[test]
+    fn test_dir_for() {
+        let routes = [
+            Route::parse("jpg,png -> Pictures").unwrap(),
+            Route::parse("mp3,flac -> Media/Music").unwrap(),
+            Route::parse("* -> Other").unwrap(),
+        ];
+        assert_eq!(
+            Route::dir_for(&routes, OsStr::new("a.JPG")),
+            Some(Path::new("Pictures"))
+        );
+        assert_eq!(
+            Route::dir_for(&routes, OsStr::new("song.flac")),
+            Some(Path::new("Media/Music"))
+        );
+        assert_eq!(
+            Route::dir_for(&routes, OsStr::new("README")),
+            Some(Path::new("Other"))
+        );
+        assert_eq!(Route::dir_for(&routes[..2], OsStr::new("a.txt")), None);
+
+        assert!(Route::is_route_dir(&routes, OsStr::new("Media")));
+        assert!(!Route::is_route_dir(&routes, OsStr::new("Music")));
+    }
+
+    #[test]
+    fn test_subdir_template() {
+        // 2024-03-05 UTC
+        let date = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_709_640_000);
+
+        let template = SubdirTemplate::parse("{yyyy}/{mm}").unwrap();
+        assert_eq!(template.render(date), PathBuf::from("2024/03"));
+        assert!(template.is_bucket(OsStr::new("2024")));
+        assert!(!template.is_bucket(OsStr::new("202")));
+        assert!(!template.is_bucket(OsStr::new("photos")));
+
+        let template = SubdirTemplate::parse("by-date/{yyyy}-{mm}-{dd}").unwrap();
+        assert_eq!(template.render(date), PathBuf::from("by-date/2024-03-05"));
+        assert!(template.is_bucket(OsStr::new("by-date")));
+        assert!(!template.is_bucket(OsStr::new("2024")));
+
+        assert!(SubdirTemplate::parse("{yyyy}//{mm}").is_err());
+        assert!(SubdirTemplate::parse("../{yyyy}").is_err());
+        assert!(SubdirTemplate::parse("/{yyyy}").is_err());
+        assert!(SubdirTemplate::parse("{stem}").is_err());
+        assert!(SubdirTemplate::parse("{yyyy").is_err());
+    }
+}
@@ -0,0 +1,512 @@
+//! `rflatten serve`: plan, apply, undo and status over a Unix socket, for front ends
+//! that drive long-running flattens. Requests and responses are JSON-RPC 2.0, one
+//! per line.
+
+use crate::manifest::Manifest;
+use crate::progress::Progress;
+use crate::{
+    Cli, ConflictPolicy, FlattenOptions, check_run, check_writable, collect_file_summary,
+    dangerous_root, display_path, flatten_from_snapshot, protected_paths, remove_top_level_dirs,
+    replay_manifest, save_incremental_state,
+};
+use clap::Parser;
+use serde_json::{Value, json};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Failures of the operation itself, like an unreadable directory
+const OPERATION_FAILED: i64 = -32000;
+
+/// Listen on `socket` until the process is stopped. Each connection is served on
+/// its own thread; applies run in the background and are polled with `status`.
+pub fn serve(socket: &Path) -> io::Result<()> {
+    // A socket left behind by a server that was killed refuses connections
+    if socket.exists() {
+        match UnixStream::connect(socket) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(socket)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("'{}' already exists", display_path(socket)),
+                ));
+            }
+        }
+    }
+
+    let listener = UnixListener::bind(socket)?;
+    eprintln!("Listening on '{}'", display_path(socket));
+
+    let server = Arc::new(Server::default());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting a connection: {}", e);
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            if let Err(e) = server.serve_connection(stream) {
+                eprintln!("Error serving a connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    /// Every apply started, by id minus one
+    jobs: Mutex<Vec<Arc<Job>>>,
+}
+
+/// An apply running in the background, or finished
+struct Job {
+    root: PathBuf,
+    progress: Arc<Progress>,
+    /// Where the apply's moves are recorded, for undo
+    manifest: PathBuf,
+    state: Mutex<JobState>,
+    /// End-of-run statistics, once the apply has finished
+    statistics: Mutex<Option<Value>>,
+}
+
+#[derive(Clone)]
+enum JobState {
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+    Undone,
+}
+
+impl JobState {
+    fn name(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Cancelled => "cancelled",
+            JobState::Failed(_) => "failed",
+            JobState::Undone => "undone",
+        }
+    }
+}
+
+/// A JSON-RPC error object
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<io::Error> for RpcError {
+    fn from(e: io::Error) -> Self {
+        RpcError::new(OPERATION_FAILED, e.to_string())
+    }
+}
+
+impl Server {
+    fn serve_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(&line) {
+                writeln!(writer, "{}", response)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer one request line. Notifications (requests without an id) get no answer.
+    fn handle(&self, line: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, e.to_string())),
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            let id = id.unwrap_or(Value::Null);
+            return Some(error_response(id, INVALID_REQUEST, "missing method"));
+        };
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = self.call(method, &params);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => error_response(id, e.code, e.message),
+        })
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "plan" => plan(params),
+            "apply" => self.apply(params),
+            "status" => Ok(status(&*self.job(params)?)),
+            "cancel" => {
+                let job = self.job(params)?;
+                job.progress.cancel.store(true, Ordering::Relaxed);
+                Ok(status(&job))
+            }
+            "undo" => self.undo(params),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method '{}'", method),
+            )),
+        }
+    }
+
+    /// Scan the directory and start moving what was found in the background
+    fn apply(&self, params: &Value) -> Result<Value, RpcError> {
+        let (root, cli) = parse_params(params)?;
+        let mut options = flatten_options(&cli);
+        check_writable(&root).map_err(|message| RpcError::new(INVALID_PARAMS, message))?;
+        let summary = collect_file_summary(&root, &options)?;
+        // Nobody can confirm over the socket, so working copies need --force
+        check_run(&cli, &root, &summary, false)
+            .map_err(|message| RpcError::new(INVALID_PARAMS, message))?;
+
+        let progress = Arc::new(Progress::default());
+        progress.planned(summary.file_count, summary.total_bytes);
+        options.progress = Some(Arc::clone(&progress));
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.len() + 1;
+        let manifest = options.manifest.get_or_insert_with(|| {
+            std::env::temp_dir().join(format!("rflatten-serve-{}-{}.json", std::process::id(), id))
+        });
+        let job = Arc::new(Job {
+            root: root.clone(),
+            progress,
+            manifest: manifest.clone(),
+            state: Mutex::new(JobState::Running),
+            statistics: Mutex::new(None),
+        });
+        jobs.push(Arc::clone(&job));
+        drop(jobs);
+
+        std::thread::spawn(move || {
+            let started = Instant::now();
+            let state = match flatten_from_snapshot(&root, &options, &summary.snapshot) {
+                Ok(report) => {
+                    *job.statistics.lock().unwrap() = Some(report.statistics(started.elapsed()));
+                    if let Err(e) = remove_top_level_dirs(&root, &summary.top_level_dirs) {
+                        eprintln!(
+                            "Error removing directories in {}: {}",
+                            display_path(&root),
+                            e
+                        );
+                    }
+                    if options.incremental && !report.cancelled {
+                        save_incremental_state(&root, &options, &report.settled_dirs);
+                    }
+                    if report.cancelled {
+                        JobState::Cancelled
+                    } else {
+                        JobState::Done
+                    }
+                }
+                Err(e) => JobState::Failed(e.to_string()),
+            };
+            *job.state.lock().unwrap() = state;
+        });
+
+        Ok(json!({"job": id}))
+    }
+
+    /// Move the files of a finished apply back to where they were
+    fn undo(&self, params: &Value) -> Result<Value, RpcError> {
+        let job = self.job(params)?;
+        let mut state = job.state.lock().unwrap();
+        if matches!(*state, JobState::Running | JobState::Undone) {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("the job is {}", state.name()),
+            ));
+        }
+
+        let manifest = Manifest::read(&job.manifest)?.inverted();
+        let restored = replay_manifest(&job.root, &manifest, true)?;
+        *state = JobState::Undone;
+        Ok(json!({"restored": restored}))
+    }
+
+    fn job(&self, params: &Value) -> Result<Arc<Job>, RpcError> {
+        let id = params
+            .get("job")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing job"))?;
+        let jobs = self.jobs.lock().unwrap();
+        id.checked_sub(1)
+            .and_then(|index| jobs.get(index as usize))
+            .cloned()
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("no job {}", id)))
+    }
+}
+
+/// Scan the directory and report what an apply would move
+fn plan(params: &Value) -> Result<Value, RpcError> {
+    let (root, cli) = parse_params(params)?;
+    let summary = collect_file_summary(&root, &flatten_options(&cli))?;
+
+    let mut top_level_dirs: Vec<_> = summary
+        .top_level_dirs
+        .iter()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .collect();
+    top_level_dirs.sort();
+    Ok(json!({
+        "directory": display_path(&root),
+        "files": summary.file_count,
+        "bytes": summary.total_bytes,
+        "cross_device_files": summary.cross_device_files,
+        "duplicate_files": summary.duplicate_files,
+        "top_level_dirs": top_level_dirs,
+        "git_working_copies": summary
+            .git_working_copies
+            .iter()
+            .map(|path| display_path(path))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+fn status(job: &Job) -> Value {
+    let state = job.state.lock().unwrap().clone();
+    let mut status = json!({
+        "state": state.name(),
+        "moved": job.progress.moved.load(Ordering::Relaxed),
+        "total": job.progress.total.load(Ordering::Relaxed),
+        "moved_bytes": job.progress.moved_bytes.load(Ordering::Relaxed),
+        "total_bytes": job.progress.total_bytes.load(Ordering::Relaxed),
+        "percent": job.progress.percent(),
+    });
+    if let JobState::Failed(message) = state {
+        status["error"] = Value::String(message);
+    }
+    if let Some(statistics) = job.statistics.lock().unwrap().clone() {
+        status["statistics"] = statistics;
+    }
+    status
+}
+
+/// Read `directory` and the optional `args` (command-line options, as the CLI takes
+/// them) from a request. Roots the CLI would refuse are refused here too.
+fn parse_params(params: &Value) -> Result<(PathBuf, Cli), RpcError> {
+    let directory = params
+        .get("directory")
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing directory"))?;
+    let args = match params.get("args") {
+        None => Vec::new(),
+        Some(args) => args
+            .as_array()
+            .and_then(|args| args.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "args must be an array of strings"))?,
+    };
+
+    let cli = Cli::try_parse_from(
+        std::iter::once("rflatten")
+            .chain(args)
+            .chain(["--", directory]),
+    )
+    .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string().trim().to_string()))?;
+
+    // Options a served run doesn't implement are refused rather than dropped
+    let unsupported = [
+        ("--dest-archive", cli.dest_archive.is_some()),
+        ("--no", cli.assume_no),
+        ("--diff", cli.diff),
+        ("--porcelain", cli.porcelain),
+        ("--summary-format", cli.summary_format.is_some()),
+        ("--no-summary", cli.no_summary),
+        ("--min-files", cli.min_files.is_some()),
+        ("--post-run-cmd", cli.post_run_cmd.is_some()),
+        ("--nice-io", cli.nice_io),
+        ("--progress-fd", cli.progress_fd.is_some()),
+    ];
+    if let Some((option, _)) = unsupported.iter().find(|(_, given)| *given) {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("{} isn't supported by rflatten serve", option),
+        ));
+    }
+
+    let root = Path::new(directory)
+        .canonicalize()
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("'{}': {}", directory, e)))?;
+    if !root.is_dir() {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("'{}' is not a directory", directory),
+        ));
+    }
+    if cli
+        .conflict_policies()
+        .any(|policy| policy == ConflictPolicy::Ask)
+    {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            "--on-conflict ask needs a terminal",
+        ));
+    }
+    if !cli.force
+        && let Some(reason) = dangerous_root(&root, &protected_paths())
+    {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("refusing to flatten '{}': {}", display_path(&root), reason),
+        ));
+    }
+    Ok((root, cli))
+}
+
+/// Options for a run with nobody at a terminal to print to or prompt
+fn flatten_options(cli: &Cli) -> FlattenOptions {
+    FlattenOptions {
+        quiet: true,
+        confirm_every: None,
+        ..FlattenOptions::from(cli)
+    }
+}
+
+fn error_response(id: Value, code: i64, message: impl Into<String>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message.into()},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn request(server: &Server, method: &str, params: Value) -> Value {
+        let line = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        server.handle(&line.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_plan_apply_and_undo() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/file.txt"), "contents").unwrap();
+        let directory = json!(root.to_str().unwrap());
+        let server = Server::default();
+
+        let plan = request(&server, "plan", json!({"directory": directory}));
+        assert_eq!(plan["result"]["files"], 1);
+        assert_eq!(plan["result"]["top_level_dirs"], json!(["a"]));
+
+        let apply = request(&server, "apply", json!({"directory": directory}));
+        assert_eq!(apply["result"]["job"], 1);
+        let status = loop {
+            let status = request(&server, "status", json!({"job": 1}));
+            if status["result"]["state"] != "running" {
+                break status;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status["result"]["state"], "done");
+        assert_eq!(status["result"]["moved"], 1);
+        assert!(root.join("file.txt").exists());
+
+        let undo = request(&server, "undo", json!({"job": 1}));
+        assert_eq!(undo["result"]["restored"], 1);
+        assert!(root.join("a/b/file.txt").exists());
+        assert!(!root.join("file.txt").exists());
+    }
+
+    #[test]
+    fn test_errors() {
+        let server = Server::default();
+        assert_eq!(
+            server.handle("not json").unwrap()["error"]["code"],
+            PARSE_ERROR
+        );
+        assert_eq!(
+            request(&server, "frobnicate", Value::Null)["error"]["code"],
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            request(&server, "status", json!({"job": 7}))["error"]["code"],
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            request(
+                &server,
+                "plan",
+                json!({"directory": "/x", "args": ["--bogus"]})
+            )["error"]["code"],
+            INVALID_PARAMS
+        );
+        // Options the server doesn't implement aren't silently ignored
+        for args in [json!(["--dest-archive", "out.zip"]), json!(["--no"])] {
+            let response = request(&server, "apply", json!({"directory": "/tmp", "args": args}));
+            assert_eq!(response["error"]["code"], INVALID_PARAMS);
+            let message = response["error"]["message"].as_str().unwrap();
+            assert!(message.contains("isn't supported"), "{}", message);
+        }
+
+        // Nobody is there to answer, whichever files a policy is for
+        let temp_dir = TempDir::new().unwrap();
+        let directory = json!(temp_dir.path().to_str().unwrap());
+        assert_eq!(
+            request(
+                &server,
+                "apply",
+                json!({"directory": directory, "args": ["--on-conflict-for", "*.jpg=ask"]})
+            )["error"]["code"],
+            INVALID_PARAMS
+        );
+
+        // The checks the command line makes before moving anything apply too
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("project/.git")).unwrap();
+        fs::write(temp_dir.path().join("project/main.rs"), "fn main() {}").unwrap();
+        let directory = json!(temp_dir.path().to_str().unwrap());
+        assert_eq!(
+            request(&server, "apply", json!({"directory": directory}))["error"]["code"],
+            INVALID_PARAMS
+        );
+        assert!(temp_dir.path().join("project/main.rs").exists());
+        assert_eq!(
+            request(
+                &server,
+                "apply",
+                json!({"directory": directory, "args": ["--force", "--max-files", "0"]})
+            )["error"]["code"],
+            INVALID_PARAMS
+        );
+
+        // Notifications get no response
+        assert!(
+            server
+                .handle(r#"{"jsonrpc": "2.0", "method": "status", "params": {"job": 1}}"#)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Free space queries for the filesystem files are flattened into (including the
+//! user's disk quota), and parsing of byte sizes given on the command line
+
+use std::io;
+use std::path::Path;
+
+/// Space available to the current user on a filesystem
+pub struct FreeSpace {
+    pub bytes: u64,
+    /// Free inodes, if the filesystem reports a fixed inode count
+    pub inodes: Option<u64>,
+    /// The user's disk quota leaves less room than the filesystem does
+    pub quota: bool,
+}
+
+/// Parse a byte size such as `500`, `64K`, `1.5G` or `2GiB`.
+/// Suffixes are powers of 1024; a trailing `B` or `iB` is optional.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}', expected e.g. 500M or 2G", s))?;
+
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "unknown size unit in '{}', expected K, M, G or T",
+                s
+            ));
+        }
+    };
+
+    Ok((value * multiplier as f64) as u64)
+}
+
+/// Query the free space on the filesystem containing `path`
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // statvfs field widths differ between platforms
+pub fn free_space(path: &Path) -> io::Result<FreeSpace> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+
+    // SAFETY: statvfs only writes into the zeroed struct we pass it
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Filesystems that allocate inodes dynamically (e.g. btrfs) report zero
+    let inodes = if stat.f_files == 0 {
+        None
+    } else {
+        Some(stat.f_favail as u64)
+    };
+
+    let mut free = FreeSpace {
+        bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        inodes,
+        quota: false,
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        let (bytes, inodes) = quota_left(path);
+        if let Some(bytes) = bytes.filter(|&bytes| bytes < free.bytes) {
+            free.bytes = bytes;
+            free.quota = true;
+        }
+        if let Some(inodes) = inodes.filter(|&left| free.inodes.is_none_or(|free| left < free)) {
+            free.inodes = Some(inodes);
+            free.quota = true;
+        }
+    }
+
+    Ok(free)
+}
+
+/// Room left under the current user's hard quota limits on the filesystem containing
+/// `path`, in bytes and inodes. `None` where there's no limit or quotas are off.
+#[cfg(target_os = "linux")]
+fn quota_left(path: &Path) -> (Option<u64>, Option<u64>) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::fs::MetadataExt;
+
+    const USRQUOTA: libc::c_int = 0;
+
+    let device = std::fs::metadata(path).ok().and_then(|metadata| {
+        let dev = metadata.dev();
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+        let id = format!("{}:{}", libc::major(dev), libc::minor(dev));
+        mountinfo.lines().find_map(|line| mount_source(line, &id))
+    });
+    let Some(device) = device.and_then(|device| CString::new(device.into_vec()).ok()) else {
+        return (None, None);
+    };
+
+    // SAFETY: quotactl only writes into the zeroed struct we pass it
+    let mut quota: libc::dqblk = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::quotactl(
+            libc::QCMD(libc::Q_GETQUOTA, USRQUOTA),
+            device.as_ptr(),
+            libc::getuid() as libc::c_int,
+            &mut quota as *mut libc::dqblk as *mut libc::c_char,
+        )
+    };
+    // Most filesystems don't have quotas enabled
+    if result != 0 {
+        return (None, None);
+    }
+
+    let bytes = (quota.dqb_valid & libc::QIF_BLIMITS != 0 && quota.dqb_bhardlimit > 0)
+        .then(|| (quota.dqb_bhardlimit * 1024).saturating_sub(quota.dqb_curspace));
+    let inodes = (quota.dqb_valid & libc::QIF_ILIMITS != 0 && quota.dqb_ihardlimit > 0)
+        .then(|| quota.dqb_ihardlimit.saturating_sub(quota.dqb_curinodes));
+    (bytes, inodes)
+}
+
+/// The device mounted in a /proc/self/mountinfo line, if the mount's `major:minor`
+/// is `id` and it comes from a device node
+#[cfg(target_os = "linux")]
+fn mount_source(line: &str, id: &str) -> Option<std::ffi::OsString> {
+    let mut fields = line.split(' ');
+    if fields.nth(2)? != id {
+        return None;
+    }
+    // Optional fields end with a lone "-", followed by the filesystem type and source
+    let source = fields.skip_while(|&field| field != "-").nth(2)?;
+    source.starts_with('/').then(|| source.into())
+}
+
+/// Query the free space on the volume containing `path`
+#[cfg(target_os = "windows")]
+pub fn free_space(path: &Path) -> io::Result<FreeSpace> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+
+    let mut available: u64 = 0;
+    // SAFETY: `wide` is NUL-terminated and the output pointers are valid or null
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The space available to the caller already accounts for their quota
+    Ok(FreeSpace {
+        bytes: available,
+        inodes: None,
+        quota: false,
+    })
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+pub fn free_space(_path: &Path) -> io::Result<FreeSpace> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space queries are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500B"), Ok(500));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_size("1 TB"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn test_parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("-5").is_err());
+    }
+
+    #[test]
+    fn test_free_space_of_temp_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let free = free_space(temp_dir.path()).unwrap();
+        assert!(free.bytes > 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_mount_source() {
+        let line = "36 35 8:1 / /home rw,noatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro";
+        assert_eq!(mount_source(line, "8:1"), Some("/dev/sda1".into()));
+        assert_eq!(mount_source(line, "8:2"), None);
+
+        let line = "40 35 0:34 / /tmp rw,nosuid - tmpfs tmpfs rw";
+        assert_eq!(mount_source(line, "0:34"), None);
+    }
+
+    #[test]
+    fn test_free_space_missing_path() {
+        let temp_dir = TempDir::new().unwrap();
+        assert!(free_space(&temp_dir.path().join("missing")).is_err());
+    }
+}
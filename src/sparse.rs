@@ -0,0 +1,108 @@
+//! Copies across filesystems that keep the holes in sparse files (VM images,
+//! databases) instead of writing them out as zeros
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Whether a file has holes: fewer blocks allocated than its length needs
+#[cfg(unix)]
+pub fn is_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Copy `src` to `dest`, copying only the data regions of `src` so its holes stay
+/// holes, and keeping its permissions like `fs::copy` does. Falls back to `fs::copy`
+/// where the filesystem can't report holes. Returns the length of the file.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+))]
+pub fn copy(src: &Path, dest: &Path) -> io::Result<u64> {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::fd::AsRawFd;
+
+    let mut reader = fs::File::open(src)?;
+    let metadata = reader.metadata()?;
+    let len = metadata.len();
+    let fd = reader.as_raw_fd();
+
+    let mut writer = fs::File::create(dest)?;
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: lseek on an open descriptor has no other preconditions
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                // Only a hole is left
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) if offset == 0 => {
+                    drop(writer);
+                    return fs::copy(src, dest);
+                }
+                _ => return Err(error),
+            }
+        }
+        // SAFETY: as above
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        reader.seek(SeekFrom::Start(data as u64))?;
+        writer.seek(SeekFrom::Start(data as u64))?;
+        io::copy(&mut (&mut reader).take((hole - data) as u64), &mut writer)?;
+        offset = hole as u64;
+    }
+
+    // A hole at the end has no data region to write
+    writer.set_len(len)?;
+    writer.set_permissions(metadata.permissions())?;
+    Ok(len)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+)))]
+pub fn copy(src: &Path, dest: &Path) -> io::Result<u64> {
+    fs::copy(src, dest)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_keeps_holes() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("disk.img");
+        let dest = temp_dir.path().join("copy.img");
+
+        let mut file = fs::File::create(&src).unwrap();
+        file.set_len(16 << 20).unwrap();
+        file.seek(SeekFrom::Start(8 << 20)).unwrap();
+        file.write_all(b"data in the middle").unwrap();
+        drop(file);
+
+        assert_eq!(copy(&src, &dest).unwrap(), 16 << 20);
+        assert_eq!(fs::read(&dest).unwrap(), fs::read(&src).unwrap());
+        // Filesystems without holes store both in full
+        if is_sparse(&fs::metadata(&src).unwrap()) {
+            assert!(is_sparse(&fs::metadata(&dest).unwrap()));
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Machine-readable pre-flatten summaries for --summary-format
+
+use serde_json::Value;
+
+/// Format the summary is printed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SummaryFormat {
+    Json,
+    Yaml,
+}
+
+impl SummaryFormat {
+    pub fn render(self, summary: &Value) -> String {
+        match self {
+            SummaryFormat::Json => {
+                serde_json::to_string_pretty(summary).expect("JSON values always serialize")
+            }
+            SummaryFormat::Yaml => {
+                let mut yaml = String::new();
+                write_yaml(&mut yaml, summary, 0);
+                yaml.trim_end().to_string()
+            }
+        }
+    }
+}
+
+/// Write `value` as block-style YAML. Strings are double-quoted JSON strings, which
+/// are valid YAML and keep paths with colons or leading dashes intact.
+fn write_yaml(out: &mut String, value: &Value, indent: usize) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Object(map) => {
+            // Keys are the summary's own field names, so they never need quoting
+            for (key, value) in map {
+                match scalar(value) {
+                    Some(scalar) => out.push_str(&format!("{}{}: {}\n", pad, key, scalar)),
+                    None => {
+                        out.push_str(&format!("{}{}:\n", pad, key));
+                        write_yaml(out, value, indent + 2);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                match scalar(item) {
+                    Some(scalar) => out.push_str(&format!("{}- {}\n", pad, scalar)),
+                    None => {
+                        // The item's first line goes on the same line as its dash
+                        let mut nested = String::new();
+                        write_yaml(&mut nested, item, indent + 2);
+                        out.push_str(&pad);
+                        out.push_str("- ");
+                        out.push_str(&nested[indent + 2..]);
+                    }
+                }
+            }
+        }
+        value => out.push_str(&format!("{}{}\n", pad, scalar(value).unwrap_or_default())),
+    }
+}
+
+/// How `value` is written on one line, or `None` if it needs a block of its own
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::Object(map) if map.is_empty() => Some("{}".to_string()),
+        Value::Array(items) if items.is_empty() => Some("[]".to_string()),
+        Value::Object(_) | Value::Array(_) => None,
+        value => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_yaml() {
+        let summary = json!({
+            "files": 3,
+            "directories": [
+                {"name": "a: b", "files": 2},
+                {"name": "c", "files": 1},
+            ],
+            "skipped": [],
+            "git_working_copies": ["/x/y"],
+        });
+        assert_eq!(
+            SummaryFormat::Yaml.render(&summary),
+            "directories:\n  \
+             - files: 2\n    name: \"a: b\"\n  \
+             - files: 1\n    name: \"c\"\n\
+             files: 3\n\
+             git_working_copies:\n  - \"/x/y\"\n\
+             skipped: []"
+        );
+    }
+}
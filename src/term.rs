@@ -0,0 +1,102 @@
+//! Terminal detection: where prompts are asked, and whether output gets colors
+//! (following NO_COLOR and CLICOLOR_FORCE)
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+
+/// Whether prompts can be asked on stdin and stdout, i.e. both are terminals
+fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal()
+}
+
+/// Whether someone can see and answer a prompt, on stdin and stdout or on the
+/// controlling terminal
+pub fn can_prompt() -> bool {
+    is_interactive() || open_terminal().is_some()
+}
+
+/// Show `text` and read a line in answer. When stdin or stdout is redirected, the
+/// controlling terminal is used instead, like `sudo` does. Returns `None` if there's
+/// no terminal to ask on, and an empty answer at end of input.
+pub fn prompt(text: &str) -> io::Result<Option<String>> {
+    let mut answer = String::new();
+    if is_interactive() {
+        print!("{}", text);
+        io::stdout().flush()?;
+        io::stdin().read_line(&mut answer)?;
+        return Ok(Some(answer));
+    }
+
+    let Some((input, mut output)) = open_terminal() else {
+        return Ok(None);
+    };
+    write!(output, "{}", text)?;
+    output.flush()?;
+    BufReader::new(input).read_line(&mut answer)?;
+    Ok(Some(answer))
+}
+
+/// The controlling terminal, for reading and writing
+#[cfg(unix)]
+fn open_terminal() -> Option<(File, File)> {
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    Some((tty.try_clone().ok()?, tty))
+}
+
+#[cfg(windows)]
+fn open_terminal() -> Option<(File, File)> {
+    let input = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("CONIN$")
+        .ok()?;
+    let output = OpenOptions::new().write(true).open("CONOUT$").ok()?;
+    Some((input, output))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn open_terminal() -> Option<(File, File)> {
+    None
+}
+
+/// Whether to color stderr. CLICOLOR_FORCE (set and not `0`) turns colors on, then
+/// NO_COLOR (set and not empty) turns them off; otherwise only terminals get them.
+fn use_color() -> bool {
+    let var = |name| env::var_os(name).filter(|value| !value.is_empty());
+    decide_color(
+        var("CLICOLOR_FORCE").is_some_and(|value| value != "0"),
+        var("NO_COLOR").is_some(),
+        io::stderr().is_terminal(),
+    )
+}
+
+fn decide_color(force: bool, no_color: bool, terminal: bool) -> bool {
+    force || (!no_color && terminal)
+}
+
+/// `text` in bold yellow for stderr, if it gets colors
+pub fn warning(text: &str) -> String {
+    if use_color() {
+        format!("\x1b[1;33m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide_color() {
+        assert!(decide_color(false, false, true));
+        assert!(!decide_color(false, false, false));
+        assert!(!decide_color(false, true, true));
+        assert!(decide_color(true, true, false));
+    }
+}